@@ -0,0 +1,177 @@
+//! Throughput of a stream mode session receiving the trace in the text and
+//! binary encoding over a local TCP connection, from the socket through the
+//! sinks into the store, run with `cargo bench --bench trace_receive [-- <n>]`
+//! for n transactions
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+use pls::binary_trace::{self, MAGIC};
+use pls::options::StoreOptions;
+use pls::source::TraceSource;
+use pls::store::TransactionStore;
+use pls::trace_format::TraceFormat;
+use pls::transaction::{Transaction, TransactionCmd};
+use pls::virtual_prototype::{VPCtrlMsg, VPMode, VP};
+
+const MODULES: [(&str, &str, &str); 4] = [
+    ("dram", "80000000", "8fffffff"),
+    ("uart0", "10013000", "10013fff"),
+    ("clint", "2000000", "200ffff"),
+    ("plic", "c000000", "cffffff"),
+];
+
+/// Throughput of one way to receive the trace
+struct BenchResult {
+    name: &'static str,
+    bytes: usize,
+    transactions: usize,
+    elapsed: Duration,
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        write!(
+            f,
+            "{:<12} {:>8.1} MB {:>10} transactions {:>8.3} s {:>8.2} M transactions/s",
+            self.name,
+            self.bytes as f64 / 1e6,
+            self.transactions,
+            secs,
+            self.transactions as f64 / secs / 1e6
+        )
+    }
+}
+
+/// Transactions in the style of the RISC-V VP++, cycling through the modules
+fn transactions(count: usize) -> impl Iterator<Item = Transaction> {
+    (0..count).map(|i| {
+        let target = i % MODULES.len();
+        let start = u64::from_str_radix(MODULES[target].1, 16).unwrap();
+        Transaction {
+            sim_time: i as u64 * 10,
+            action: if i % 3 == 0 {
+                TransactionCmd::Write
+            } else {
+                TransactionCmd::Read
+            },
+            initiator: format!("core{}", i % 2),
+            target: target as u8,
+            address: format!("{:x}", start + (i as u64 * 4) % 0x1000),
+            data_length: 4,
+            data: format!("{:x}", i * 7),
+            attributes: BTreeMap::new(),
+        }
+    })
+}
+
+fn text_trace(count: usize) -> Vec<u8> {
+    let mut trace = String::new();
+    for (name, start, end) in MODULES {
+        trace.push_str(&format!("I;{name};{start};{end}\n"));
+    }
+    for transaction in transactions(count) {
+        trace.push_str(&format!("{transaction}\n"));
+    }
+    trace.into_bytes()
+}
+
+fn binary_trace(count: usize) -> Vec<u8> {
+    let mut trace = MAGIC.to_vec();
+    for (name, start, end) in MODULES {
+        binary_trace::encode_module(name, start, end, &mut trace).unwrap();
+    }
+    for transaction in transactions(count) {
+        binary_trace::encode_transaction(&transaction, &mut trace).unwrap();
+    }
+    trace
+}
+
+/// Sends the trace over a local TCP connection to the receiver of a stream
+/// mode session, which passes it through the sinks into the store. Returns
+/// the number of stored transactions once the receiver finished.
+async fn receive(trace: Vec<u8>) -> Result<(usize, Duration), String> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|e| e.to_string())?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let binary = trace.starts_with(MAGIC);
+    let sender = tokio::spawn(async move {
+        if let Ok((mut stream, _)) = listener.accept().await {
+            // like a VP with binary support, the offer is read first
+            let mut offer = [0; MAGIC.len()];
+            if binary && stream.read_exact(&mut offer).await.is_err() {
+                return;
+            }
+            let _ = stream.write_all(&trace).await;
+        }
+    });
+
+    let mut format = TraceFormat::default();
+    format.binary = binary;
+    let (channel, mut vp_recv) = broadcast::channel::<VPCtrlMsg>(32);
+    let store_opt = StoreOptions::default();
+    let store = TransactionStore::new(store_opt.memory_cap, store_opt.spill_dir);
+
+    let start = Instant::now();
+    let source = TraceSource::local_port(port);
+    let vp = VP::attach(
+        VPMode::Stream,
+        Arc::new(channel),
+        source,
+        format,
+        store,
+        Vec::new(),
+    )
+    .await
+    .map_err(|_| String::from("could not connect to the trace"))?;
+    loop {
+        match vp_recv.recv().await {
+            Ok(VPCtrlMsg::Finished) | Err(broadcast::error::RecvError::Closed) => break,
+            // the notifications of single transactions are not needed
+            _ => {}
+        }
+    }
+    let elapsed = start.elapsed();
+    let count = vp.steps.lock().await.len();
+    let _ = sender.await;
+    Ok((count, elapsed))
+}
+
+/// Measures how fast the trace of the given number of transactions is
+/// received in the text encoding, the only one before, and the binary one
+async fn run(count: usize) -> Result<Vec<BenchResult>, String> {
+    let cases = [("text", text_trace(count)), ("binary", binary_trace(count))];
+
+    let mut results = Vec::new();
+    for (name, trace) in cases {
+        let bytes = trace.len();
+        let (transactions, elapsed) = receive(trace).await?;
+        results.push(BenchResult {
+            name,
+            bytes,
+            transactions,
+            elapsed,
+        });
+    }
+    Ok(results)
+}
+
+#[tokio::main]
+async fn main() {
+    // cargo passes --bench, the only other argument is the number of transactions
+    let count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(1_000_000);
+    match run(count).await {
+        Ok(results) => results.iter().for_each(|result| println!("{result}")),
+        Err(e) => eprintln!("[BENCH] {e}"),
+    }
+}
@@ -0,0 +1,297 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc;
+
+use crate::gdb_proxy::ProxyCmd;
+use crate::query::{Context, Query};
+use crate::ranges;
+use crate::sink::{TraceState, TransactionSink};
+use crate::transaction::Transaction;
+use crate::virtual_prototype::{VPCtrlMsg, VPLayout};
+
+/// Number of violations of a rule which are reported to clients, later ones are only counted
+const MAX_REPORTS: usize = 100;
+
+/// Schema of the rules file
+#[derive(Deserialize, Debug)]
+struct RuleDef {
+    name: String,
+    /// query selecting the transactions the rule applies to, all if missing
+    #[serde(rename = "match", default)]
+    when: Option<String>,
+    /// query every selected transaction has to fulfill
+    assert: String,
+    /// interrupt the VP through the gdb proxy on a violation
+    #[serde(default)]
+    halt: bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    when: Option<Query>,
+    assert: Query,
+    halt: bool,
+}
+
+/// Bus protocol rules, e.g. `match: target == "clint" && action == W`, `assert: len == 8`
+#[derive(Default, Debug)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Loads a YAML list of rules with name, optional match, assert and halt
+    pub fn load(path: &Path) -> Result<RuleSet, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("could not read rules {} ({e})", path.display()))?;
+        let defs: Vec<RuleDef> = serde_yaml::from_str(&content)
+            .map_err(|e| format!("could not parse rules {} ({e})", path.display()))?;
+
+        let mut rules = Vec::new();
+        for def in defs {
+            let parse = |q: &str| Query::parse(q).map_err(|e| format!("rule {}: {e}", def.name));
+            rules.push(Rule {
+                when: def.when.as_deref().map(parse).transpose()?,
+                assert: parse(&def.assert)?,
+                name: def.name,
+                halt: def.halt,
+            });
+        }
+        Ok(RuleSet { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub rule: String,
+    /// index of the violating transaction in the session
+    pub index: usize,
+    pub sim_time: u64,
+    pub transaction: String,
+    /// number of violations of the rule so far
+    pub count: usize,
+    pub halt: bool,
+}
+
+/// Evaluates the rules against the live transaction stream
+pub struct Checker {
+    rules: Arc<RuleSet>,
+    counts: Vec<usize>,
+    /// inclusive address range of each module of the layout
+    ranges: Vec<(u64, u64)>,
+    channel: Arc<Sender<VPCtrlMsg>>,
+    halt: mpsc::Sender<ProxyCmd>,
+}
+
+impl Checker {
+    pub fn new(
+        rules: Arc<RuleSet>,
+        channel: Arc<Sender<VPCtrlMsg>>,
+        halt: mpsc::Sender<ProxyCmd>,
+    ) -> Checker {
+        Checker {
+            counts: vec![0; rules.rules.len()],
+            rules,
+            ranges: Vec::new(),
+            channel,
+            halt,
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionSink for Checker {
+    async fn on_layout(&mut self, layout: &VPLayout) {
+        // invalid ranges never contain an address
+        self.ranges = (layout.start_addrs.iter().zip(layout.end_addrs.iter()))
+            .map(|(start, end)| ranges::parse_range(start, end).unwrap_or((1, 0)))
+            .collect();
+    }
+
+    /// Transactions are checked before they are applied to the shadow memory,
+    /// so `mem()` refers to the state the transaction saw
+    async fn on_transaction(&mut self, transaction: &Transaction, state: &TraceState<'_>) {
+        let index = state.index;
+        let shadow = state.shadow.lock().await;
+        let context = Context {
+            modules: &state.layout.modules,
+            ranges: &self.ranges,
+            shadow: Some(&shadow),
+        };
+        for (rule, count) in self.rules.rules.iter().zip(self.counts.iter_mut()) {
+            let selected = rule
+                .when
+                .as_ref()
+                .is_none_or(|q| q.matches_in(transaction, index, &context));
+            if !selected || rule.assert.matches_in(transaction, index, &context) {
+                continue;
+            }
+
+            *count += 1;
+            // every violation of a halt rule interrupts the VP, even if it is not reported
+            if rule.halt {
+                let _ = self.halt.try_send(ProxyCmd::Interrupt);
+            }
+            if *count > MAX_REPORTS {
+                continue;
+            }
+            println!("[ASSERT] {} violated at {index}: {transaction}", rule.name);
+            let _ = self.channel.send(VPCtrlMsg::Violation(Violation {
+                rule: rule.name.clone(),
+                index,
+                sim_time: transaction.sim_time,
+                transaction: transaction.to_string(),
+                count: *count,
+                halt: rule.halt,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::lock::Mutex;
+    use tokio::sync::broadcast;
+
+    use crate::shadow::ShadowMemory;
+
+    const RULES: &str = r#"
+- name: accesses inside module ranges
+  assert: mapped
+- name: mtimecmp is written with 8 bytes
+  match: target == "clint" && action == W
+  assert: len == 8
+  halt: true
+- name: uart is ready
+  match: target == "uart0"
+  assert: mem(0x10013008, 1) == 1
+"#;
+
+    fn rule_set(rules: &str) -> Result<RuleSet, String> {
+        let path = std::env::temp_dir().join(format!("pls-rules-{}.yaml", std::process::id()));
+        fs::write(&path, rules).unwrap();
+        let rule_set = RuleSet::load(&path);
+        let _ = fs::remove_file(path);
+        rule_set
+    }
+
+    fn layout() -> VPLayout {
+        VPLayout {
+            modules: vec![String::from("clint"), String::from("uart0")],
+            start_addrs: vec![String::from("2000000"), String::from("10013000")],
+            end_addrs: vec![String::from("200ffff"), String::from("10013fff")],
+        }
+    }
+
+    async fn check(
+        checker: &mut Checker,
+        layout: &VPLayout,
+        shadow: &Mutex<ShadowMemory>,
+        index: usize,
+        line: &str,
+    ) {
+        let transaction: Transaction = line.parse().unwrap();
+        let state = TraceState {
+            index,
+            layout,
+            shadow,
+        };
+        checker.on_transaction(&transaction, &state).await;
+    }
+
+    #[test]
+    fn loads_rules() {
+        let rules = rule_set(RULES).unwrap();
+        assert_eq!(rules.rules.len(), 3);
+        assert!(rules.rules[0].when.is_none());
+        assert!(rules.rules[1].halt);
+        let invalid = rule_set("- name: broken\n  assert: len ==\n").unwrap_err();
+        assert!(invalid.starts_with("rule broken:"), "{invalid}");
+        assert!(rule_set("- name: no assert\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_violations_and_halts() {
+        let (channel, mut violations) = broadcast::channel(4 * MAX_REPORTS);
+        let (halt, mut interrupts) = mpsc::channel(4 * MAX_REPORTS);
+        let rules = Arc::new(rule_set(RULES).unwrap());
+        let mut checker = Checker::new(rules, Arc::new(channel), halt);
+        let layout = layout();
+        checker.on_layout(&layout).await;
+
+        let shadow = Mutex::new(ShadowMemory::default());
+        check(
+            &mut checker,
+            &layout,
+            &shadow,
+            0,
+            "W;core0;0;2004000;10;8;1",
+        )
+        .await;
+        check(
+            &mut checker,
+            &layout,
+            &shadow,
+            1,
+            "R;core0;0;2004000;20;4;1",
+        )
+        .await;
+        assert!(violations.try_recv().is_err());
+
+        check(
+            &mut checker,
+            &layout,
+            &shadow,
+            2,
+            "W;core0;1;20000000;30;4;1",
+        )
+        .await;
+        let violation = violations.try_recv().unwrap();
+        let VPCtrlMsg::Violation(violation) = violation else {
+            panic!("expected violation");
+        };
+        assert_eq!(violation.rule, "accesses inside module ranges");
+        assert_eq!(
+            (violation.index, violation.count, violation.halt),
+            (2, 1, false)
+        );
+        // the status register was never observed
+        let VPCtrlMsg::Violation(violation) = violations.try_recv().unwrap() else {
+            panic!("expected violation");
+        };
+        assert_eq!(violation.rule, "uart is ready");
+        assert!(interrupts.try_recv().is_err());
+
+        for index in 0..MAX_REPORTS + 5 {
+            check(
+                &mut checker,
+                &layout,
+                &shadow,
+                index,
+                "W;core0;0;2004000;10;4;1",
+            )
+            .await;
+        }
+        let mut reported = 0;
+        while let Ok(VPCtrlMsg::Violation(violation)) = violations.try_recv() {
+            assert!(violation.halt);
+            reported += 1;
+        }
+        assert_eq!(reported, MAX_REPORTS);
+        let mut halted = 0;
+        while let Ok(ProxyCmd::Interrupt) = interrupts.try_recv() {
+            halted += 1;
+        }
+        assert_eq!(halted, MAX_REPORTS + 5);
+    }
+}
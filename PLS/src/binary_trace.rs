@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::hex;
+use crate::trace_format::TraceLine;
+use crate::transaction::{Transaction, TransactionCmd};
+
+/// Start of a binary trace. If enabled in the trace format PLS sends it when
+/// connecting to a socket to offer the encoding, a VP supporting it answers
+/// with it instead of text lines. VPs may also send it unasked.
+pub const MAGIC: &[u8] = b"PLSB\x02";
+
+/// `tag, name length (u8), name, start (u64), end (u64)`
+const MODULE: u8 = 1;
+/// `tag, time (u64), action (u8), target (u8), address (u64), length (u8),
+/// data size (u8), initiator length (u8), data, initiator`. The data is little
+/// endian of any size, a size of 0 means the transaction has no data.
+const TRANSACTION: u8 = 2;
+const TRANSACTION_SIZE: usize = 22;
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn text(bytes: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(bytes).map_err(|e| format!("invalid name in binary trace ({e})"))
+}
+
+/// Decodes the first frame of the buffer, returns the frame and its size or
+/// None if the frame is incomplete. Numbers are little endian, addresses and
+/// data are converted to the hex strings of the text trace.
+pub fn decode(buf: &[u8]) -> Result<Option<(TraceLine, usize)>, String> {
+    let Some(&tag) = buf.first() else {
+        return Ok(None);
+    };
+    match tag {
+        MODULE => {
+            let Some(&name_len) = buf.get(1) else {
+                return Ok(None);
+            };
+            let size = 2 + name_len as usize + 16;
+            if buf.len() < size {
+                return Ok(None);
+            }
+            let name = text(&buf[2..size - 16])?;
+            let start = u64_at(buf, size - 16);
+            let end = u64_at(buf, size - 8);
+            let module =
+                TraceLine::Module(name.to_owned(), format!("{start:x}"), format!("{end:x}"));
+            Ok(Some((module, size)))
+        }
+        TRANSACTION => {
+            if buf.len() < TRANSACTION_SIZE {
+                return Ok(None);
+            }
+            let data_end = TRANSACTION_SIZE + buf[20] as usize;
+            let size = data_end + buf[21] as usize;
+            if buf.len() < size {
+                return Ok(None);
+            }
+            let action = match buf[9] {
+                0 => TransactionCmd::Read,
+                1 => TransactionCmd::Write,
+                action => return Err(format!("invalid action {action} in binary trace")),
+            };
+            let transaction = Transaction {
+                sim_time: u64_at(buf, 1),
+                action,
+                initiator: text(&buf[data_end..size])?.to_owned(),
+                target: buf[10],
+                address: format!("{:x}", u64_at(buf, 11)),
+                data_length: buf[19],
+                data: data_hex(&buf[TRANSACTION_SIZE..data_end]),
+                attributes: BTreeMap::new(),
+            };
+            Ok(Some((TraceLine::Transaction(transaction), size)))
+        }
+        tag => Err(format!("invalid frame {tag} in binary trace")),
+    }
+}
+
+/// Hex string of little endian data without leading zeros, empty for no data
+fn data_hex(bytes: &[u8]) -> String {
+    let mut hex: String = bytes.iter().rev().map(|b| format!("{b:02x}")).collect();
+    let zeros = hex.len() - hex.trim_start_matches('0').len();
+    // a value of zero keeps one digit
+    hex.drain(..zeros.min(hex.len().saturating_sub(1)));
+    hex
+}
+
+/// Little endian bytes of a hex string of any length
+fn data_bytes(hex: &str) -> io::Result<Vec<u8>> {
+    let digits = hex.trim_start_matches("0x").as_bytes();
+    let bytes: Vec<u8> = digits
+        .rchunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(|| invalid(format!("invalid data {hex}")))?;
+    if bytes.len() > u8::MAX as usize {
+        return Err(invalid(format!("data {hex} is longer than 255 bytes")));
+    }
+    Ok(bytes)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn address(value: &str) -> io::Result<u64> {
+    hex::parse(value).map_err(|e| invalid(format!("invalid address {e}")))
+}
+
+pub fn encode_module(name: &str, start: &str, end: &str, out: &mut dyn Write) -> io::Result<()> {
+    let name = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+    let (start, end) = (address(start)?, address(end)?);
+    out.write_all(&[MODULE, name.len() as u8])?;
+    out.write_all(name)?;
+    out.write_all(&start.to_le_bytes())?;
+    out.write_all(&end.to_le_bytes())
+}
+
+/// Encodes a transaction, attributes are not part of the binary trace
+pub fn encode_transaction(transaction: &Transaction, out: &mut dyn Write) -> io::Result<()> {
+    let initiator = transaction.initiator.as_bytes();
+    let initiator = &initiator[..initiator.len().min(u8::MAX as usize)];
+    let action = match transaction.action {
+        TransactionCmd::Read => 0,
+        TransactionCmd::Write => 1,
+    };
+    let address = address(&transaction.address)?;
+    let data = data_bytes(&transaction.data)?;
+    out.write_all(&[TRANSACTION])?;
+    out.write_all(&transaction.sim_time.to_le_bytes())?;
+    out.write_all(&[action, transaction.target])?;
+    out.write_all(&address.to_le_bytes())?;
+    out.write_all(&[transaction.data_length])?;
+    out.write_all(&[data.len() as u8, initiator.len() as u8])?;
+    out.write_all(&data)?;
+    out.write_all(initiator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::ToBinary;
+
+    fn round_trip(line: &str) -> Transaction {
+        let transaction: Transaction = line.parse().unwrap();
+        let mut buf = Vec::new();
+        encode_transaction(&transaction, &mut buf).unwrap();
+        // incomplete frames are not decoded
+        assert!(decode(&buf[..buf.len() - 1]).unwrap().is_none());
+        let Some((TraceLine::Transaction(decoded), size)) = decode(&buf).unwrap() else {
+            panic!("expected transaction");
+        };
+        assert_eq!(size, buf.len());
+        // any decoded transaction can be sent to the clients
+        decoded.to_binary();
+        decoded
+    }
+
+    #[test]
+    fn transactions_round_trip() {
+        for line in [
+            "R;core0;1;10013004;10;4;1",
+            "W;core0;0;80000000;20;16;112233445566778899aabbccddeeff00",
+            "W;dma;3;ffffffffffffffff;18446744073709551615;1;0",
+            "R;core0;1;10;10;4;",
+        ] {
+            assert_eq!(round_trip(line).to_string(), line);
+        }
+        // leading zeros of the data are not kept
+        assert_eq!(round_trip("R;core0;1;10;10;4;00ab").data, "ab");
+    }
+
+    #[test]
+    fn modules_round_trip() {
+        let mut buf = Vec::new();
+        encode_module("uart0", "10013000", "10013fff", &mut buf).unwrap();
+        let Some((TraceLine::Module(name, start, end), size)) = decode(&buf).unwrap() else {
+            panic!("expected module");
+        };
+        assert_eq!(
+            (name.as_str(), start.as_str(), end.as_str()),
+            ("uart0", "10013000", "10013fff")
+        );
+        assert_eq!(size, buf.len());
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        let mut buf = Vec::new();
+        let invalid: Transaction = "R;core0;1;10;10;4;xyz".parse().unwrap();
+        assert!(encode_transaction(&invalid, &mut buf).is_err());
+        let wide: Transaction = format!("R;core0;1;10;10;4;{}", "ff".repeat(256))
+            .parse()
+            .unwrap();
+        assert!(encode_transaction(&wide, &mut buf).is_err());
+        assert!(decode(&[3]).is_err());
+
+        encode_transaction(&"R;core0;1;10;10;4;1".parse().unwrap(), &mut buf).unwrap();
+        buf[9] = 2;
+        assert!(decode(&buf).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::options::StoreOptions;
+use crate::session::Session;
+use crate::source::TraceSource;
+use crate::store::TransactionStore;
+use crate::trace_format::TraceFormat;
+use crate::virtual_prototype::{VPCtrlMsg, VPMode, VP};
+
+/// VP and firmware of a headless run
+pub struct RunOptions {
+    pub vp: String,
+    pub binary: String,
+    pub args: Vec<String>,
+    /// port of the bus trace of the VP, used if no other source is given
+    pub port: u16,
+    pub source: Option<TraceSource>,
+    pub format: TraceFormat,
+    pub timeout: Duration,
+}
+
+/// Runs the VP without clients until it closes its trace connection or the
+/// timeout expires. Returns the recorded session and whether the timeout expired.
+pub async fn record(options: RunOptions) -> Result<(Session, bool), String> {
+    let mut args = options.args;
+    let source = match options.source {
+        Some(source) => source,
+        None => {
+            if !args.iter().any(|a| a == "--debug-bus-mode") {
+                args.push(String::from("--debug-bus-mode"));
+            }
+            if !args.iter().any(|a| a == "--debug-bus-port") {
+                args.push(String::from("--debug-bus-port"));
+                args.push(options.port.to_string());
+            }
+            TraceSource::local_port(options.port)
+        }
+    };
+
+    let (channel, mut vp_recv) = broadcast::channel::<VPCtrlMsg>(32);
+    let store_opt = StoreOptions::default();
+    let store = TransactionStore::new(store_opt.memory_cap, store_opt.spill_dir.clone());
+    let vp = VP::start(
+        options.vp,
+        options.binary,
+        args,
+        VPMode::Stream,
+        Arc::new(channel),
+        source,
+        options.format,
+        store,
+        Vec::new(),
+    )
+    .await
+    .map_err(|_| String::from("could not start VP"))?;
+
+    let finished = tokio::time::timeout(options.timeout, async {
+        loop {
+            match vp_recv.recv().await {
+                Ok(VPCtrlMsg::Finished) | Err(broadcast::error::RecvError::Closed) => break,
+                _ => {}
+            }
+        }
+    })
+    .await;
+    if finished.is_err() {
+        println!(
+            "[CHECK] timeout after {}s, stopping VP",
+            options.timeout.as_secs()
+        );
+    }
+
+    let empty = TransactionStore::new(store_opt.memory_cap, store_opt.spill_dir);
+    let session = Session {
+        layout: std::mem::take(&mut *vp.arch.lock().await),
+        store: std::mem::replace(&mut *vp.steps.lock().await, empty),
+    };
+    drop(vp);
+    Ok((session, finished.is_err()))
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::check::{self, RunOptions};
+use crate::diff::{self, DiffOptions, DiffReport};
+use crate::export::{self, ExportFormat, Selection, SelectionStore};
+use crate::filter::{FilterCommand, TransactionFilter};
+use crate::session::Session;
+use crate::trace_format::TraceFormat;
+
+const USAGE: &str = "usage:
+  PLS                       start the server
+  PLS export <session> --format <csv|ndjson|vcd|perfetto|arrow> [--filter <filter.json>] [--output <file>]
+            [--trace-format <format.json>]
+  PLS diff <session> <other session> [--trace-format <format.json>] [diff options]
+  PLS check <golden session> --vp <vp> --binary <elf> [--args \"<vp args>\"]
+            [--port <trace port> | --source <tcp|unix|pipe|file|tail>:<location>]
+            [--timeout <s>] [--record <session.ndjson>] [--trace-format <format.json>] [diff options]
+
+diff options:
+  --by-address --ignore-time --time-tolerance <ns> --ignore-modules <a,b> --limit <entries> --json";
+
+/// Options of all subcommands which take no value
+const SWITCHES: [&str; 3] = ["by-address", "ignore-time", "json"];
+
+/// Positional arguments, `--name value` options and switches of a subcommand
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    switches: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            switches: Vec::new(),
+        };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if SWITCHES.contains(&name) => parsed.switches.push(name.to_owned()),
+                Some(name) => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| format!("missing value of --{name}"))?;
+                    parsed.options.insert(name.to_owned(), value.clone());
+                }
+                None => parsed.positional.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn switch(&self, name: &str) -> bool {
+        self.switches.iter().any(|s| s == name)
+    }
+}
+
+/// Runs a subcommand and returns the exit code of the process
+pub async fn run(args: &[String]) -> i32 {
+    let parsed = Args::parse(&args[1..]);
+    let result = match args[0].as_str() {
+        "export" => parsed.and_then(|a| export_session(&a)).map(|_| true),
+        "diff" => parsed.and_then(|a| diff_sessions(&a)),
+        "check" => match parsed {
+            Ok(a) => check_session(&a).await,
+            Err(e) => Err(e),
+        },
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            return 0;
+        }
+        cmd => Err(format!("unknown subcommand {cmd}")),
+    };
+
+    // like diff(1) differences are reported with exit code 1
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("[CLI] {e}\n{USAGE}");
+            2
+        }
+    }
+}
+
+fn load_filter(path: &Path) -> Result<TransactionFilter, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("could not read filter {} ({e})", path.display()))?;
+    serde_json::from_str::<FilterCommand>(&content)
+        .map_err(|e| format!("could not parse FilterCommand ({e})"))
+        .and_then(TransactionFilter::try_from)
+}
+
+/// Format of raw traces, the format of the RISC-V VP++ if not given
+fn trace_format(args: &Args) -> Result<TraceFormat, String> {
+    let Some(path) = args.option("trace-format") else {
+        return Ok(TraceFormat::default());
+    };
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("could not read trace format {path} ({e})"))?;
+    serde_json::from_str(&content).map_err(|e| format!("could not parse trace format ({e})"))
+}
+
+fn export_session(args: &Args) -> Result<(), String> {
+    let [input] = args.positional.as_slice() else {
+        return Err(String::from("export takes exactly one session file"));
+    };
+    let format = args.option("format").unwrap_or("ndjson");
+    let format = ExportFormat::from_name(format).ok_or(format!("unknown format {format}"))?;
+    let filter = args
+        .option("filter")
+        .map(|f| load_filter(Path::new(f)))
+        .transpose()?;
+
+    let mut session = Session::load(Path::new(input), &trace_format(args)?)?;
+    let mut selection = Selection {
+        layout: &session.layout,
+        store: SelectionStore::Session(&mut session.store),
+        filter: filter.as_ref(),
+    };
+
+    let written = match args.option("output").map(PathBuf::from) {
+        Some(path) => File::create(&path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            export::export(format, &mut selection, &mut out)?;
+            out.flush()
+        }),
+        None => {
+            let mut out = BufWriter::new(io::stdout().lock());
+            export::export(format, &mut selection, &mut out).and_then(|_| out.flush())
+        }
+    };
+    written.map_err(|e| format!("could not export ({e})"))
+}
+
+fn parse_number<T: std::str::FromStr>(args: &Args, name: &str, default: T) -> Result<T, String> {
+    match args.option(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("could not parse {name} {value}")),
+        None => Ok(default),
+    }
+}
+
+fn diff_options(args: &Args) -> Result<DiffOptions, String> {
+    Ok(DiffOptions {
+        by_address: args.switch("by-address"),
+        ignore_time: args.switch("ignore-time"),
+        time_tolerance: parse_number(args, "time-tolerance", 0)?,
+        ignore_modules: args
+            .option("ignore-modules")
+            .map(|m| m.split(',').map(|m| m.trim().to_owned()).collect())
+            .unwrap_or_default(),
+        limit: parse_number(args, "limit", 100)?,
+    })
+}
+
+/// Prints the differences of two sessions and returns whether they are equal
+fn diff_sessions(args: &Args) -> Result<bool, String> {
+    let [session, other] = args.positional.as_slice() else {
+        return Err(String::from("diff takes exactly two session files"));
+    };
+    let options = diff_options(args)?;
+
+    let format = trace_format(args)?;
+    let mut session = Session::load(Path::new(session), &format)?;
+    let mut other = Session::load(Path::new(other), &format)?;
+    let report = diff::diff(&mut session, &mut other, &options);
+    print_report(args, &report)?;
+    Ok(report.is_empty())
+}
+
+/// Runs a VP and firmware headless and compares the recorded trace with a
+/// golden session. Returns whether the traces are equal, a run which timed
+/// out fails as its trace may be incomplete.
+async fn check_session(args: &Args) -> Result<bool, String> {
+    let [golden] = args.positional.as_slice() else {
+        return Err(String::from("check takes exactly one golden session file"));
+    };
+    let options = diff_options(args)?;
+    let run = RunOptions {
+        vp: args.option("vp").ok_or("missing --vp")?.to_owned(),
+        binary: args.option("binary").ok_or("missing --binary")?.to_owned(),
+        args: args
+            .option("args")
+            .unwrap_or_default()
+            .split_ascii_whitespace()
+            .map(String::from)
+            .collect(),
+        port: parse_number(args, "port", 5006)?,
+        source: args.option("source").map(str::parse).transpose()?,
+        format: trace_format(args)?,
+        timeout: Duration::from_secs(parse_number(args, "timeout", 60)?),
+    };
+    let timeout = run.timeout.as_secs();
+    // load the golden session first to fail before the VP is started
+    let mut golden = Session::load(Path::new(golden), &run.format)?;
+
+    let (mut session, timed_out) = check::record(run).await?;
+    if let Some(path) = args.option("record") {
+        let mut selection = Selection {
+            layout: &session.layout,
+            store: SelectionStore::Session(&mut session.store),
+            filter: None,
+        };
+        File::create(path)
+            .and_then(|file| {
+                let mut out = BufWriter::new(file);
+                export::export(ExportFormat::Ndjson, &mut selection, &mut out)?;
+                out.flush()
+            })
+            .map_err(|e| format!("could not record session to {path} ({e})"))?;
+    }
+
+    let report = diff::diff(&mut golden, &mut session, &options);
+    print_report(args, &report)?;
+    if timed_out {
+        return Err(format!(
+            "VP timed out after {timeout}s, its trace may be incomplete"
+        ));
+    }
+    Ok(report.is_empty())
+}
+
+fn print_report(args: &Args, report: &DiffReport) -> Result<(), String> {
+    if args.switch("json") {
+        let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+        println!("{json}");
+    } else {
+        print!("{report}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "I;rom;0;fff\nI;ram;1000;1fff\nW;core0;1;1004;5;4;2a\nR;core0;0;10;7;4;1\n";
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Writes the files into a new directory of the test
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pls-cli-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn parses_options_and_switches() {
+        let parsed = Args::parse(&args(&[
+            "a.ndjson",
+            "--ignore-time",
+            "--limit",
+            "5",
+            "--ignore-modules",
+            "rom, ram",
+            "b.ndjson",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.positional, ["a.ndjson", "b.ndjson"]);
+        assert!(parsed.switch("ignore-time") && !parsed.switch("json"));
+        let options = diff_options(&parsed).unwrap();
+        assert!(options.ignore_time && !options.by_address);
+        assert_eq!((options.limit, options.time_tolerance), (5, 0));
+        assert_eq!(options.ignore_modules, ["rom", "ram"]);
+
+        assert!(Args::parse(&args(&["a", "--format"])).is_err());
+        let parsed = Args::parse(&args(&["--time-tolerance", "-1"])).unwrap();
+        assert!(diff_options(&parsed).is_err());
+    }
+
+    #[tokio::test]
+    async fn exports_and_diffs_sessions() {
+        let changed = TRACE.replace(";2a", ";2b");
+        let dir = files("diff", &[("a.log", TRACE), ("b.log", &changed)]);
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        assert_eq!(
+            run(&args(&["diff", &path("a.log"), &path("a.log")])).await,
+            0
+        );
+        assert_eq!(
+            run(&args(&["diff", &path("a.log"), &path("b.log")])).await,
+            1
+        );
+        assert_eq!(run(&args(&["diff", &path("a.log")])).await, 2);
+        assert_eq!(
+            run(&args(&["diff", &path("a.log"), &path("none")])).await,
+            2
+        );
+        assert_eq!(run(&args(&["merge"])).await, 2);
+
+        let export = ["export", &path("a.log"), "--format", "csv", "--output"];
+        assert_eq!(
+            run(&args(&[&export[..], &[&path("a.csv")]].concat())).await,
+            0
+        );
+        let csv = fs::read_to_string(dir.join("a.csv")).unwrap();
+        assert_eq!(csv.lines().nth(1), Some("0,5,Write,core0,ram,1,1004,4,2a"));
+        let export = ["export", &path("a.log"), "--format", "xml"];
+        assert_eq!(run(&args(&export)).await, 2);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn checks_a_run_against_a_golden_session() {
+        let dir = files("check", &[("golden.log", TRACE), ("trace.log", TRACE)]);
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let source = format!("file:{}", path("trace.log"));
+        let check = [
+            "check",
+            &path("golden.log"),
+            "--vp",
+            "true",
+            "--binary",
+            "firmware.elf",
+            "--source",
+            &source,
+            "--record",
+            &path("run.ndjson"),
+        ];
+        assert_eq!(run(&args(&check)).await, 0);
+        // the recorded run can be used as golden session
+        assert_eq!(
+            run(&args(&["diff", &path("run.ndjson"), &path("golden.log")])).await,
+            0
+        );
+        assert_eq!(run(&args(&check[..5])).await, 2);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
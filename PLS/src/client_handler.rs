@@ -1,24 +1,59 @@
 use futures::stream::SplitSink;
-use futures::{lock::Mutex, SinkExt, StreamExt};
+use futures::{lock::Mutex, Sink, SinkExt, StreamExt};
 use serde_json::Error;
-use std::{path::PathBuf, sync::Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{ops::ControlFlow, path::PathBuf, sync::Arc};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc;
 use warp::filters::ws::Message;
 use warp::ws::WebSocket;
 
-use crate::command::{Command, GenericCommand};
-use crate::gdb_proxy::{self, GdbStatus};
-use crate::options::{self, Options};
+use crate::assertions::{Checker, RuleSet};
+use crate::command::{
+    AttachCommand, Command, CursorCommand, CursorResponse, ExportCommand, ExportResponse,
+    GenericCommand, PageCommand, PageResponse, QueryCommand, QueryMatch, QueryResponse,
+    ShadowQuery, ShadowResponse, StepCommand, StepResponse,
+};
+use crate::cursor;
+use crate::export::{self, Snapshot};
+use crate::filter::{AddressRange, FilterCommand, TransactionFilter};
+use crate::gdb_proxy::{self, GdbStatus, ProxyCmd};
+use crate::options::{self, Options, Project, ProjectTranfer};
+use crate::query::{self, Query};
+use crate::register_map::RegisterMaps;
+use crate::scripting::{ScriptHost, Scripts};
+use crate::shadow;
+use crate::sink::{FileRecorder, TransactionSink};
+use crate::source::TraceSource;
+use crate::stepper;
+use crate::store::TransactionStore;
+use crate::trace_format::TraceFormat;
 use crate::transaction::{ToBinary, Transaction};
 use crate::virtual_prototype::{VPCtrlMsg, VPLayout, VPMode, VP};
-use crate::{Project, ProjectTranfer};
+
+/// Maximum number of transactions per packet and page
+const PAGE_SIZE: usize = 10_000;
+/// Top bit of the preamble of a transaction packet of a filtered stream, each
+/// transaction of the packet is preceded by its 8 byte index
+const INDEXED_PACKET: u64 = 1 << 63;
+/// Transactions a query searches per request, the client continues at `next`
+const QUERY_SCAN_LIMIT: usize = 1_000_000;
+/// Transactions searched per lock of the store
+const QUERY_SCAN_CHUNK: usize = 16_384;
+/// Matches a query response contains at most
+const QUERY_MATCH_LIMIT: usize = 10_000;
 
 pub struct Gdb {
     pub connection_status: Arc<Mutex<GdbStatus>>,
     // channel on which status updates are sent by gdb_proxy
     pub proxy_receiver: Sender<GdbStatus>,
-    // channel on which continue commands are sent to gdb_proxy
-    pub proxy_sender: Sender<u32>,
+    // channel on which requests and session commands are sent to gdb_proxy
+    pub proxy_sender: mpsc::Sender<ProxyCmd>,
+    // held while a client steps the VP
+    pub step_lock: Arc<Mutex<()>>,
+    // set to stop the step in progress
+    pub step_cancel: Arc<AtomicBool>,
 }
 
 pub struct State {
@@ -27,10 +62,21 @@ pub struct State {
     pub gdb: Gdb,
     pub vp_channel: Arc<Sender<VPCtrlMsg>>,
     pub options: Arc<Options>,
+    pub reg_maps: Arc<RegisterMaps>,
+    pub rules: Arc<RuleSet>,
+    pub scripts: Arc<Scripts>,
 }
 
 pub struct LocalState {
     pub sent_steps: usize,
+    // set while a step with batched transactions is in progress
+    pub batching: bool,
+    // position in the recorded transactions, None while following the VP
+    pub cursor: Option<usize>,
+    // only matching transactions are sent to the client
+    pub filter: Option<TransactionFilter>,
+    // channel on which spawned step tasks report their result
+    pub step_results: mpsc::Sender<Result<StepResponse, String>>,
 }
 
 pub async fn handle(ws: WebSocket, state: Arc<State>) {
@@ -38,8 +84,15 @@ pub async fn handle(ws: WebSocket, state: Arc<State>) {
     let sndr_ptr = &mut sndr;
 
     let sent_steps = send_state(sndr_ptr, state.clone()).await;
-    let mut l_state = LocalState { sent_steps };
-    send_transactions(sndr_ptr, state.vp.clone(), &mut l_state).await;
+    let (step_results, mut step_recv) = mpsc::channel(4);
+    let mut l_state = LocalState {
+        sent_steps,
+        batching: false,
+        cursor: None,
+        filter: None,
+        step_results,
+    };
+    send_transactions(sndr_ptr, &state, &mut l_state).await;
 
     let mut vp_recv = state.vp_channel.clone().subscribe();
     let mut gdb_status_recv = state.gdb.proxy_receiver.subscribe();
@@ -60,19 +113,12 @@ pub async fn handle(ws: WebSocket, state: Arc<State>) {
                 }
             }
             // This block handles updates from the VP transaction receiver
-            update_signal = vp_recv.recv() =>{
-                if update_signal.is_ok(){
-                    match update_signal.unwrap(){
-                        VPCtrlMsg::RecvModule => send_layout(sndr_ptr, state.vp.clone()).await,
-                        VPCtrlMsg::RecvTransaction => send_transactions(sndr_ptr, state.vp.clone(),&mut l_state).await,
-                        VPCtrlMsg::Shutdown => {},
-                    }
-                }
-            }
+            update = vp_recv.recv() => handle_vp_update(sndr_ptr, &state, &mut l_state, update).await,
+            // This block reports finished steps to the PLW
+            Some(result) = step_recv.recv() => send_step_result(sndr_ptr, state.vp.clone(), &mut l_state, result).await,
             // This block relays updates from the gdb connection to the PLW
             con_update = gdb_status_recv.recv() => {
-                if con_update.is_ok(){
-                    let signal = con_update.unwrap();
+                if let Ok(signal) = con_update{
                     let mut con = state.gdb.connection_status.lock().await;
                     *con = signal;
                     send_command(sndr_ptr,Command::Status,con.to_string()).await;
@@ -82,51 +128,182 @@ pub async fn handle(ws: WebSocket, state: Arc<State>) {
     }
 }
 
+/// Passes an update of the VP to the client. If the client lagged behind and
+/// missed updates, it is resynced with the current layout, transactions and
+/// statistics. Missed warnings, violations and script events are only logged.
+async fn handle_vp_update(
+    sndr: &mut (impl Sink<Message> + Unpin),
+    state: &State,
+    l_state: &mut LocalState,
+    update: Result<VPCtrlMsg, RecvError>,
+) {
+    match update {
+        Ok(VPCtrlMsg::RecvModule) => send_layout(sndr, state.vp.clone()).await,
+        Ok(VPCtrlMsg::RecvTransaction | VPCtrlMsg::Finished) => {
+            send_transactions(sndr, state, l_state).await
+        }
+        Ok(VPCtrlMsg::Stats) => send_stats(sndr, state.vp.clone()).await,
+        Ok(VPCtrlMsg::Script(event)) => {
+            let msg = serde_json::to_string(&event).expect("[CH] could not serialize script event");
+            send_command(sndr, Command::Script, msg).await;
+        }
+        Ok(VPCtrlMsg::Warning(warning)) => {
+            let msg = serde_json::to_string(&warning).expect("[CH] could not serialize warning");
+            send_command(sndr, Command::Warning, msg).await;
+        }
+        Ok(VPCtrlMsg::Violation(violation)) => {
+            let msg =
+                serde_json::to_string(&violation).expect("[CH] could not serialize violation");
+            send_command(sndr, Command::Violation, msg).await;
+        }
+        Ok(VPCtrlMsg::Shutdown) | Err(RecvError::Closed) => {}
+        Err(RecvError::Lagged(missed)) => {
+            println!("[CH] client missed {missed} VP update(s), resyncing");
+            send_layout(sndr, state.vp.clone()).await;
+            send_transactions(sndr, state, l_state).await;
+            send_stats(sndr, state.vp.clone()).await;
+        }
+    }
+}
+
 async fn send_transactions(
-    sndr: &mut SplitSink<WebSocket, Message>,
-    vp_mutex: Arc<Mutex<Option<VP>>>,
+    sndr: &mut (impl Sink<Message> + Unpin),
+    state: &State,
     l_state: &mut LocalState,
 ) {
-    let mut vp_lock = vp_mutex.lock().await;
-    if vp_lock.is_none() {
+    // batched transactions are sent with the step response and
+    // clients inspecting the history do not receive new ones
+    if l_state.batching || l_state.cursor.is_some() {
         return;
     }
-    let vp = vp_lock.as_mut().unwrap();
-    let mut trans_lock = vp.steps.lock().await;
 
-    let len = trans_lock.len();
-    if len == 0 || len == l_state.sent_steps {
+    let vp_lock = state.vp.lock().await;
+    let Some(vp) = vp_lock.as_ref() else {
         return;
-    }
+    };
+    let mut steps = vp.steps.lock().await;
+    let len = steps.len();
+    let layout = vp.arch.lock().await;
 
-    // Step mode: send new transactions since last call
-    // Stream mode: send all transactions and clear vector
-    if let Some(transactions) = trans_lock.get(l_state.sent_steps..len) {
-        let mut buffer: Vec<u8> = Vec::new();
-        // first 8 byte of each packet is the current transaction count
-        let send_size = (Transaction::BIN_SIZE * transactions.len()) + 8;
-        buffer.reserve(send_size);
+    // send new transactions since last call, large backlogs in several packets
+    while l_state.sent_steps < len {
+        let first = l_state.sent_steps;
+        let transactions = steps.range(first..len.min(first + PAGE_SIZE));
+        let selected: Vec<(usize, &Transaction)> = transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| match &l_state.filter {
+                Some(filter) => filter.matches(t, &layout.modules),
+                None => true,
+            })
+            .collect();
 
-        let cnt = vp.tcount as u64;
-        buffer.extend_from_slice(&cnt.to_le_bytes());
-        for transaction in transactions {
-            buffer.extend_from_slice(&transaction.to_binary());
+        // first 8 byte of each packet is the index of its first transaction,
+        // filtered packets precede each transaction with its index instead
+        let mut buffer: Vec<u8> = Vec::new();
+        if l_state.filter.is_some() {
+            buffer.reserve((Transaction::BIN_SIZE + 8) * selected.len() + 8);
+            buffer.extend_from_slice(&(first as u64 | INDEXED_PACKET).to_le_bytes());
+            for (i, transaction) in selected.iter() {
+                buffer.extend_from_slice(&((first + i) as u64).to_le_bytes());
+                buffer.extend_from_slice(&transaction.to_binary());
+            }
+        } else {
+            buffer.reserve(Transaction::BIN_SIZE * selected.len() + 8);
+            buffer.extend_from_slice(&(first as u64).to_le_bytes());
+            for (_, transaction) in selected.iter() {
+                buffer.extend_from_slice(&transaction.to_binary());
+            }
         }
 
         let packet = Message::binary(buffer.clone());
         buffer.clear();
 
-        vp.tcount += transactions.len();
-        let _ = sndr.send(packet).await;
+        // decoded registers follow the binary packet of their transactions
+        let mut decoded = Vec::new();
+        if !state.reg_maps.is_empty() {
+            for (i, transaction) in selected.iter() {
+                let target = transaction.target as usize;
+                let (Some(module), Some(start)) =
+                    (layout.modules.get(target), layout.start_addrs.get(target))
+                else {
+                    continue;
+                };
+                let Ok(start) = u64::from_str_radix(start, 16) else {
+                    continue;
+                };
+                decoded.extend(state.reg_maps.decode(first + i, module, start, transaction));
+            }
+        }
 
-        if vp.mode == VPMode::Stream {
-            trans_lock.clear();
-        } else if vp.mode == VPMode::Step {
-            l_state.sent_steps = vp.tcount;
+        l_state.sent_steps += transactions.len();
+        if !selected.is_empty() {
+            let _ = sndr.send(packet).await;
+        }
+        if !decoded.is_empty() {
+            let msg = serde_json::to_string(&decoded).expect("[CH] could not serialize registers");
+            send_command(sndr, Command::Decode, msg).await;
+        }
+        if transactions.is_empty() {
+            break;
         }
     }
 }
 
+/// Reports a failure if a step task ends without a result, so the client
+/// loop stops batching
+struct StepReport(Option<mpsc::Sender<Result<StepResponse, String>>>);
+
+impl Drop for StepReport {
+    fn drop(&mut self) {
+        if let Some(results) = self.0.take() {
+            let _ = results.try_send(Err(String::from("step was aborted")));
+        }
+    }
+}
+
+async fn send_step_result(
+    sndr: &mut (impl Sink<Message> + Unpin),
+    vp_mutex: Arc<Mutex<Option<VP>>>,
+    l_state: &mut LocalState,
+    result: Result<StepResponse, String>,
+) {
+    let batched = l_state.batching;
+    l_state.batching = false;
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            println!("[CH] step failed [{e}]");
+            send_command(sndr, Command::Error, e).await;
+            return;
+        }
+    };
+
+    if batched {
+        // transactions of the response must not be streamed again, the
+        // client may have been behind the start of the step
+        l_state.sent_steps = response.start + response.trans_count;
+        if let Some(filter) = &l_state.filter {
+            let modules = match vp_mutex.lock().await.as_ref() {
+                Some(vp) => vp.arch.lock().await.modules.clone(),
+                None => Vec::new(),
+            };
+            response.trans.retain(|line| {
+                line.parse::<Transaction>()
+                    .is_ok_and(|t| filter.matches(&t, &modules))
+            });
+        }
+    }
+
+    send_command(
+        sndr,
+        Command::Step,
+        serde_json::to_string(&response).expect("[CH] could not serialize step response"),
+    )
+    .await;
+}
+
 async fn handle_msg(
     message: Message,
     sndr: &mut SplitSink<WebSocket, Message>,
@@ -144,6 +321,8 @@ async fn handle_msg(
 
     let Ok(cmd): Result<GenericCommand, Error> = serde_json::from_str(msg) else {
         println!("[CH] could not parse GenericCommand");
+        let err = String::from("could not parse GenericCommand");
+        send_command(sndr, Command::Error, err).await;
         return;
     };
 
@@ -153,26 +332,453 @@ async fn handle_msg(
             if is_running && cmd.value.is_empty() {
                 stop_vp(sndr, state).await;
                 local_state.sent_steps = 0;
+                local_state.cursor = None;
             } else {
                 start_vp(sndr, state.clone(), cmd.value).await;
             }
         }
+        Command::Attach => attach_vp(sndr, state, &cmd.value).await,
         Command::Status => send_status(sndr, state.vp.clone()).await,
         Command::Step => {
-            match cmd.value.parse::<u32>() {
-                Ok(steps) => {
-                    if steps < 1 {
-                        return;
-                    }
+            let step_cmd = match serde_json::from_str::<StepCommand>(&cmd.value) {
+                Ok(step_cmd) => step_cmd,
+                Err(e) => {
+                    let err = format!("could not parse StepCommand ({e})");
+                    send_command(sndr, Command::Error, err).await;
+                    return;
+                }
+            };
+            if step_cmd.cancel {
+                state.gdb.step_cancel.store(true, Ordering::Relaxed);
+                return;
+            }
+            if local_state.batching {
+                let err = String::from("another step is in progress");
+                send_command(sndr, Command::Error, err).await;
+                return;
+            }
+
+            if step_cmd.batch_trans {
+                // everything produced before the step is still streamed
+                send_transactions(sndr, &state, local_state).await;
+                local_state.batching = true;
+            }
+
+            // stepping takes a while, results are reported back to the client loop
+            let mut report = StepReport(Some(local_state.step_results.clone()));
+            tokio::spawn(async move {
+                let result = stepper::step(state, &step_cmd).await;
+                if let Some(results) = report.0.take() {
+                    let _ = results.send(result).await;
+                }
+            });
+        }
+        Command::Cursor => move_cursor(sndr, state, local_state, &cmd.value).await,
+        Command::Shadow => query_shadow(sndr, state, local_state, &cmd.value).await,
+        Command::Filter => set_filter(sndr, local_state, cmd.value).await,
+        Command::Query => search_history(sndr, state, &cmd.value).await,
+        Command::Page => send_page(sndr, state, &cmd.value).await,
+        Command::Stats => send_stats(sndr, state.vp.clone()).await,
+        Command::Export => export_trace(sndr, state, &cmd.value).await,
+        Command::Options
+        | Command::Error
+        | Command::Decode
+        | Command::Violation
+        | Command::Warning
+        | Command::Script => {
+            let err = format!("{:?} cannot be sent by clients", cmd.command);
+            send_command(sndr, Command::Error, err).await;
+        }
+    }
+}
 
-                    if let Err(e) = state.gdb.proxy_sender.send(steps) {
-                        println!("[CH] could not send steps to gdb proxy{e}");
+async fn move_cursor(
+    sndr: &mut SplitSink<WebSocket, Message>,
+    state: Arc<State>,
+    local_state: &mut LocalState,
+    value: &str,
+) {
+    let cursor_cmd = match serde_json::from_str::<CursorCommand>(value) {
+        Ok(cursor_cmd) => cursor_cmd,
+        Err(e) => {
+            let err = format!("could not parse CursorCommand ({e})");
+            send_command(sndr, Command::Error, err).await;
+            return;
+        }
+    };
+
+    let vp_lock = state.vp.lock().await;
+    let Some(vp) = vp_lock.as_ref() else {
+        let err = String::from("no VP is running");
+        send_command(sndr, Command::Error, err).await;
+        return;
+    };
+    let mut steps = vp.steps.lock().await;
+
+    let position = match cursor::seek(local_state.cursor, &cursor_cmd, steps.len()) {
+        Ok(position) => position,
+        Err(e) => {
+            send_command(sndr, Command::Error, e).await;
+            return;
+        }
+    };
+    local_state.cursor = position;
+
+    let mut response = CursorResponse {
+        position,
+        total: steps.len(),
+        transaction: None,
+        modules: Vec::new(),
+        registers: Vec::new(),
+    };
+    let mut packet = None;
+    let mut target = None;
+    if let Some((pos, transaction)) = position.and_then(|p| Some((p, steps.get(p)?.clone()))) {
+        let modules = vp.arch.lock().await.modules.len();
+        response.transaction = Some(transaction.to_string());
+        response.modules = cursor::module_state(&mut steps, modules, pos)
+            .iter()
+            .map(|t| t.as_ref().map(|t| t.to_string()))
+            .collect();
+        target = Some((pos, transaction.target as usize));
+
+        // the transaction at the cursor is also sent in binary form for the views
+        let mut buffer: Vec<u8> = Vec::with_capacity(Transaction::BIN_SIZE + 8);
+        buffer.extend_from_slice(&(pos as u64).to_le_bytes());
+        buffer.extend_from_slice(&transaction.to_binary());
+        packet = Some(Message::binary(buffer));
+    }
+    drop(steps);
+    // older shadow states are replayed from the store, which must be unlocked
+    if let Some((pos, target)) = target {
+        response.registers = shadow::view(&vp.shadow, &vp.steps, Some(pos))
+            .await
+            .registers(target, Some(pos));
+    }
+    drop(vp_lock);
+
+    send_command(
+        sndr,
+        Command::Cursor,
+        serde_json::to_string(&response).expect("[CH] could not serialize cursor response"),
+    )
+    .await;
+    if let Some(packet) = packet {
+        let _ = sndr.send(packet).await;
+    }
+
+    // catch up with the transactions received while inspecting the history
+    if position.is_none() {
+        send_transactions(sndr, &state, local_state).await;
+    }
+}
+
+async fn search_history(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>, value: &str) {
+    let parsed = serde_json::from_str::<QueryCommand>(value)
+        .map_err(|e| format!("could not parse QueryCommand ({e})"))
+        .and_then(|cmd| Query::parse(&cmd.query).map(|query| (cmd, query)));
+    let (cmd, query) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            send_command(sndr, Command::Error, e).await;
+            return;
+        }
+    };
+
+    // the VP is not kept locked during the search, the store only per chunk
+    let (steps, arch) = match state.vp.lock().await.as_ref() {
+        Some(vp) => (vp.steps.clone(), vp.arch.clone()),
+        None => {
+            let err = String::from("no VP is running");
+            send_command(sndr, Command::Error, err).await;
+            return;
+        }
+    };
+    let modules = arch.lock().await.modules.clone();
+    let limit = cmd.limit.min(QUERY_MATCH_LIMIT);
+
+    let mut matches = Vec::new();
+    let mut index = cmd.start;
+    let end = cmd.start.saturating_add(QUERY_SCAN_LIMIT);
+    let mut total = steps.lock().await.len();
+    while index < total.min(end) && matches.len() < limit {
+        let mut steps = steps.lock().await;
+        let chunk_end = (index + QUERY_SCAN_CHUNK).min(end);
+        steps.scan(index, |i, transaction| {
+            if i >= chunk_end || matches.len() >= limit {
+                return ControlFlow::Break(());
+            }
+            index = i + 1;
+            if query.matches(transaction, i, &modules) {
+                matches.push(QueryMatch {
+                    index: i,
+                    transaction: transaction.to_string(),
+                });
+            }
+            ControlFlow::Continue(())
+        });
+        total = steps.len();
+        if index < chunk_end && matches.len() < limit {
+            // the store ended before the chunk
+            break;
+        }
+    }
+
+    let response = QueryResponse {
+        matches,
+        next: (index < total).then_some(index),
+        total,
+    };
+
+    let msg = serde_json::to_string(&response).expect("[CH] could not serialize query response");
+    send_command(sndr, Command::Query, msg).await;
+}
+
+async fn send_page(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>, value: &str) {
+    let cmd = match serde_json::from_str::<PageCommand>(value) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            let err = format!("could not parse PageCommand ({e})");
+            send_command(sndr, Command::Error, err).await;
+            return;
+        }
+    };
+    let around = match cmd.around.as_deref().map(query::parse_time).transpose() {
+        Ok(around) => around,
+        Err(e) => {
+            send_command(sndr, Command::Error, e).await;
+            return;
+        }
+    };
+
+    let result = {
+        let vp_lock = state.vp.lock().await;
+        match vp_lock.as_ref() {
+            Some(vp) => {
+                let modules = vp.arch.lock().await.modules.clone();
+                let mut steps = vp.steps.lock().await;
+                let target = cmd
+                    .target
+                    .as_ref()
+                    .map(|m| modules.iter().position(|name| name == m).ok_or(m));
+                let range = cmd.range.as_ref().map(AddressRange::parse).transpose();
+
+                match (target, range) {
+                    (Some(Err(m)), _) => Err(format!("unknown module {m}")),
+                    (_, Err(e)) => Err(e),
+                    (target, Ok(range)) => {
+                        // positions of the matching transactions, None selects all
+                        let mut selection = target
+                            .and_then(Result::ok)
+                            .map(|t| steps.target_positions(t as u8));
+                        if let Some((start, end)) = range {
+                            let in_range = steps.address_positions(start, end);
+                            selection = Some(match selection {
+                                Some(s) => s
+                                    .into_iter()
+                                    .filter(|p| in_range.binary_search(p).is_ok())
+                                    .collect(),
+                                None => in_range,
+                            });
+                        }
+
+                        let count = cmd.count.min(PAGE_SIZE);
+                        let selected = selection.as_ref().map_or(steps.len(), Vec::len);
+                        let first = match around {
+                            Some(time) => {
+                                let pos = steps.position_at(time);
+                                let pos = selection
+                                    .as_ref()
+                                    .map_or(pos, |s| s.partition_point(|p| *p < pos));
+                                pos.saturating_sub(count / 2)
+                                    .min(selected.saturating_sub(count))
+                            }
+                            None => cmd.start.min(selected),
+                        };
+
+                        let mut transactions = Vec::new();
+                        for i in first..selected.min(first + count) {
+                            let index = selection.as_ref().map_or(i, |s| s[i]);
+                            if let Some(transaction) = steps.get(index) {
+                                transactions.push(QueryMatch {
+                                    index,
+                                    transaction: transaction.to_string(),
+                                });
+                            }
+                        }
+                        Ok(PageResponse {
+                            first,
+                            selected,
+                            total: steps.len(),
+                            transactions,
+                        })
                     }
                 }
-                Err(_err) => unimplemented!(),
-            };
+            }
+            None => Err(String::from("no VP is running")),
+        }
+    };
+
+    match result {
+        Ok(response) => {
+            let msg = serde_json::to_string(&response).expect("[CH] could not serialize page");
+            send_command(sndr, Command::Page, msg).await;
+        }
+        Err(e) => send_command(sndr, Command::Error, e).await,
+    }
+}
+
+async fn send_stats(sndr: &mut (impl Sink<Message> + Unpin), vp: Arc<Mutex<Option<VP>>>) {
+    let report = {
+        let vp_lock = vp.lock().await;
+        let Some(vp) = vp_lock.as_ref() else {
+            return;
+        };
+        let modules = vp.arch.lock().await.modules.clone();
+        let report = vp.stats.lock().await.report(&modules);
+        report
+    };
+
+    let msg = serde_json::to_string(&report).expect("[CH] could not serialize stats");
+    send_command(sndr, Command::Stats, msg).await;
+}
+
+/// Takes a snapshot of the transactions of the running VP received so far
+/// for an export, restricted by an optional filter
+pub async fn export_session(
+    state: &State,
+    filter: Option<FilterCommand>,
+) -> Result<Snapshot, String> {
+    let filter = filter.map(TransactionFilter::try_from).transpose()?;
+
+    let vp_lock = state.vp.lock().await;
+    let Some(vp) = vp_lock.as_ref() else {
+        return Err(String::from("no VP is running"));
+    };
+    let len = vp.steps.lock().await.len();
+    let layout = vp.arch.lock().await.clone();
+    Ok(Snapshot {
+        layout,
+        store: vp.steps.clone(),
+        len,
+        filter,
+    })
+}
+
+async fn export_trace(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>, value: &str) {
+    let cmd = match serde_json::from_str::<ExportCommand>(value) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            let err = format!("could not parse ExportCommand ({e})");
+            send_command(sndr, Command::Error, err).await;
+            return;
+        }
+    };
+
+    let dir = state.options.export_dir.clone();
+    let result = match export_session(&state, cmd.filter).await {
+        Ok(snapshot) => {
+            snapshot
+                .export(move |selection| export::export_to_file(cmd.format, selection, &dir))
+                .await
+        }
+        Err(e) => Err(e),
+    }
+    .map(|path| ExportResponse {
+        format: cmd.format,
+        path,
+    });
+
+    match result {
+        Ok(response) => {
+            println!("[CH] exported trace to {}", response.path.display());
+            let msg = serde_json::to_string(&response).expect("[CH] could not serialize export");
+            send_command(sndr, Command::Export, msg).await;
+        }
+        Err(e) => send_command(sndr, Command::Error, e).await,
+    }
+}
+
+async fn set_filter(
+    sndr: &mut SplitSink<WebSocket, Message>,
+    local_state: &mut LocalState,
+    value: String,
+) {
+    // an empty value removes the filter
+    if value.is_empty() {
+        local_state.filter = None;
+        send_command(sndr, Command::Filter, value).await;
+        return;
+    }
+
+    let filter = serde_json::from_str::<FilterCommand>(&value)
+        .map_err(|e| format!("could not parse FilterCommand ({e})"))
+        .and_then(TransactionFilter::try_from);
+    match filter {
+        Ok(filter) => {
+            local_state.filter = Some(filter);
+            send_command(sndr, Command::Filter, value).await;
         }
-        Command::Options => unimplemented!(),
+        Err(e) => send_command(sndr, Command::Error, e).await,
+    }
+}
+
+async fn query_shadow(
+    sndr: &mut SplitSink<WebSocket, Message>,
+    state: Arc<State>,
+    local_state: &mut LocalState,
+    value: &str,
+) {
+    let query = match serde_json::from_str::<ShadowQuery>(value) {
+        Ok(query) => query,
+        Err(e) => {
+            let err = format!("could not parse ShadowQuery ({e})");
+            send_command(sndr, Command::Error, err).await;
+            return;
+        }
+    };
+
+    let result = {
+        let vp_lock = state.vp.lock().await;
+        match vp_lock.as_ref() {
+            Some(vp) => {
+                let modules = vp.arch.lock().await.modules.clone();
+                let at = query.at.or(local_state.cursor);
+                let shadow = shadow::view(&vp.shadow, &vp.steps, at).await;
+                let module = query
+                    .module
+                    .as_ref()
+                    .map(|m| modules.iter().position(|name| name == m).ok_or(m));
+
+                match (module, query.address.as_ref()) {
+                    (Some(Err(m)), _) => Err(format!("unknown module {m}")),
+                    (module, Some(address)) => match u64::from_str_radix(address, 16) {
+                        Ok(address) => Ok(ShadowResponse {
+                            at,
+                            values: shadow
+                                .read(module.and_then(Result::ok), address, query.length, at)
+                                .into_iter()
+                                .collect(),
+                        }),
+                        Err(_) => Err(format!("could not parse address {address}")),
+                    },
+                    (Some(Ok(module)), None) => Ok(ShadowResponse {
+                        at,
+                        values: shadow.registers(module, at),
+                    }),
+                    (None, None) => Err(String::from("module or address is required")),
+                }
+            }
+            None => Err(String::from("no VP is running")),
+        }
+    };
+
+    match result {
+        Ok(response) => {
+            let msg = serde_json::to_string(&response).expect("[CH] could not serialize shadow");
+            send_command(sndr, Command::Shadow, msg).await;
+        }
+        Err(e) => send_command(sndr, Command::Error, e).await,
     }
 }
 
@@ -198,7 +804,13 @@ async fn start_vp(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>, c
         start_opt.args,
         start_opt.mode,
         state.vp_channel.clone(),
-        Some(state.options.vp_opt.vp_trace_port),
+        start_opt.source,
+        start_opt.format,
+        TransactionStore::new(
+            state.options.store_opt.memory_cap,
+            state.options.store_opt.spill_dir.clone(),
+        ),
+        session_sinks(&state),
     )
     .await
     else {
@@ -207,19 +819,115 @@ async fn start_vp(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>, c
     let mut vp_lock = state.vp.lock().await;
     let vp = vp_lock.insert(new_vp);
 
+    // point the gdb proxy to the stub of this session
+    let _ = state
+        .gdb
+        .proxy_sender
+        .send(ProxyCmd::Session(start_opt.debug_port))
+        .await;
+
     // spawn gdbgui if needed
-    if start_opt.arch.is_some() {
-        if let Ok(gdb_subprocess) =
-            gdb_proxy::start_gdbgui(&state.options.gdb_opt, start_opt.arch.unwrap(), &binary)
-        {
+    if let Some(arch) = start_opt.arch {
+        if let Ok(gdb_subprocess) = gdb_proxy::start_gdbgui(&state.options.gdb_opt, arch, &binary) {
             vp.gdbgui = Some(gdb_subprocess)
         }
     }
 
-    println!("[CH] VP with PID [{}] started", vp.subproc.id());
+    if let Some(subproc) = &vp.subproc {
+        println!("[CH] VP with PID [{}] started", subproc.id());
+    }
+    send_command(sndr, Command::Start, vp.is_running.to_string()).await;
+}
+
+async fn attach_vp(sndr: &mut (impl Sink<Message> + Unpin), state: Arc<State>, value: &str) {
+    let attach_cmd = match serde_json::from_str::<AttachCommand>(value) {
+        Ok(attach_cmd) => attach_cmd,
+        Err(e) => {
+            let err = format!("could not parse AttachCommand ({e})");
+            send_command(sndr, Command::Error, err).await;
+            return;
+        }
+    };
+
+    if get_status(state.vp.clone()).await {
+        let err = String::from("VP is running already");
+        send_command(sndr, Command::Error, err).await;
+        return;
+    }
+
+    let format = match &attach_cmd.format {
+        Some(name) => match state.options.trace_formats.get(name) {
+            Some(format) => format.clone(),
+            None => {
+                let err = format!("unknown trace format {name}");
+                send_command(sndr, Command::Error, err).await;
+                return;
+            }
+        },
+        None => TraceFormat::default(),
+    };
+
+    // stepping needs the gdb stub of the VP
+    let mode = match attach_cmd.debug_port {
+        Some(_) => VPMode::Step,
+        None => VPMode::Stream,
+    };
+    let Ok(new_vp) = VP::attach(
+        mode,
+        state.vp_channel.clone(),
+        TraceSource::Tcp(attach_cmd.trace.clone()),
+        format,
+        TransactionStore::new(
+            state.options.store_opt.memory_cap,
+            state.options.store_opt.spill_dir.clone(),
+        ),
+        session_sinks(&state),
+    )
+    .await
+    else {
+        let err = format!("could not attach to {}", attach_cmd.trace);
+        send_command(sndr, Command::Error, err).await;
+        return;
+    };
+    let mut vp_lock = state.vp.lock().await;
+    let vp = vp_lock.insert(new_vp);
+
+    let _ = state
+        .gdb
+        .proxy_sender
+        .send(ProxyCmd::Session(attach_cmd.debug_port))
+        .await;
+
+    println!("[CH] attached to VP at {}", attach_cmd.trace);
     send_command(sndr, Command::Start, vp.is_running.to_string()).await;
 }
 
+/// Optional sinks of a VP session, depending on the options of the server
+fn session_sinks(state: &State) -> Vec<Box<dyn TransactionSink>> {
+    let mut sinks: Vec<Box<dyn TransactionSink>> = Vec::new();
+    if !state.rules.is_empty() {
+        sinks.push(Box::new(Checker::new(
+            state.rules.clone(),
+            state.vp_channel.clone(),
+            state.gdb.proxy_sender.clone(),
+        )));
+    }
+    if !state.scripts.is_empty() {
+        sinks.push(Box::new(ScriptHost::new(
+            &state.scripts,
+            state.vp_channel.clone(),
+            state.gdb.proxy_sender.clone(),
+        )));
+    }
+    if let Some(dir) = &state.options.record_dir {
+        match FileRecorder::create(dir) {
+            Ok(recorder) => sinks.push(Box::new(recorder)),
+            Err(e) => println!("[CH] {e}"),
+        }
+    }
+    sinks
+}
+
 async fn stop_vp(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>) {
     let mut vp_lock = state.vp.lock().await;
     if vp_lock.is_some() && vp_lock.as_mut().unwrap().stop() {
@@ -230,10 +938,11 @@ async fn stop_vp(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>) {
         .unwrap();
         let _ = sndr.send(Message::text(msg)).await;
         let _ = vp_lock.take(); // Drop old VP struct
+        let _ = state.gdb.proxy_sender.send(ProxyCmd::Session(None)).await;
     }
 }
 
-async fn send_command(sndr: &mut SplitSink<WebSocket, Message>, command: Command, value: String) {
+async fn send_command(sndr: &mut (impl Sink<Message> + Unpin), command: Command, value: String) {
     let _ = sndr
         .send(Message::text(
             serde_json::to_string(&GenericCommand { command, value }).unwrap(),
@@ -242,7 +951,7 @@ async fn send_command(sndr: &mut SplitSink<WebSocket, Message>, command: Command
 }
 
 #[allow(clippy::len_zero)]
-async fn send_layout(sndr: &mut SplitSink<WebSocket, Message>, vp: Arc<Mutex<Option<VP>>>) {
+async fn send_layout(sndr: &mut (impl Sink<Message> + Unpin), vp: Arc<Mutex<Option<VP>>>) {
     let mut vp_locked = vp.lock().await;
     if vp_locked.is_some() {
         let v = vp_locked.as_mut().unwrap();
@@ -274,15 +983,12 @@ async fn send_state(sndr: &mut SplitSink<WebSocket, Message>, state: Arc<State>)
 
     send_layout(sndr, state.vp.clone()).await;
 
+    // new clients start with the latest page, older ones are requested by page
     let mut sent_steps: usize = 0;
     let mut vp_lock = state.vp.lock().await;
     if vp_lock.is_some() {
         let s = vp_lock.as_mut().unwrap().steps.lock().await;
-        if !s.is_empty() {
-            sent_steps = s.len() - 1;
-        } else {
-            sent_steps = 0;
-        }
+        sent_steps = s.len().saturating_sub(PAGE_SIZE);
     }
 
     let gdb_status = state.gdb.connection_status.lock().await;
@@ -315,3 +1021,276 @@ async fn send_status(sndr: &mut SplitSink<WebSocket, Message>, vp: Arc<Mutex<Opt
     };
     let _ = sndr.send(Message::text(msg)).await;
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use tokio::sync::broadcast;
+
+    use crate::shadow::ShadowMemory;
+    use crate::stats::TrafficStats;
+
+    /// VP session without a process holding the given transactions
+    pub(crate) fn vp(lines: &[&str]) -> VP {
+        let mut store = TransactionStore::new(0, std::env::temp_dir());
+        for line in lines {
+            store.push(line.parse().unwrap());
+        }
+        let layout = VPLayout {
+            modules: vec![String::from("ram")],
+            start_addrs: vec![String::from("0")],
+            end_addrs: vec![String::from("ffffffff")],
+        };
+        VP {
+            subproc: None,
+            gdbgui: None,
+            channel: Arc::new(broadcast::channel(32).0),
+            is_running: true,
+            steps: Arc::new(Mutex::new(store)),
+            arch: Arc::new(Mutex::new(layout)),
+            shadow: Arc::new(Mutex::new(ShadowMemory::default())),
+            stats: Arc::new(Mutex::new(TrafficStats::default())),
+            mode: VPMode::Stream,
+        }
+    }
+
+    /// Server state without a gdb proxy
+    pub(crate) fn state(vp: Option<VP>) -> State {
+        let options = serde_json::json!({
+            "serv_opt": { "address": "127.0.0.1", "port": 8080, "static_dir": "./dist" },
+            "vp_opt": { "vp_debug_port": 5005, "vp_trace_port": 5006 },
+            "gdb_opt": { "gdbproxy_port": 5007, "gdbgui_port": 5000, "gdb_bin": "gdb", "gdbgui": "gdbgui" },
+            "bin_dir": "./sw",
+            "vp_dir": "./bin",
+            "gui_vp_kit_dir": "",
+            "gui_vp_args": ""
+        });
+        State {
+            vp: Arc::new(Mutex::new(vp)),
+            pr: Arc::new((Vec::new(), Vec::new())),
+            gdb: Gdb {
+                connection_status: Arc::new(Mutex::new(GdbStatus::NotConnected)),
+                proxy_receiver: broadcast::channel(32).0,
+                proxy_sender: mpsc::channel(32).0,
+                step_lock: Arc::new(Mutex::new(())),
+                step_cancel: Arc::new(AtomicBool::new(false)),
+            },
+            vp_channel: Arc::new(broadcast::channel(32).0),
+            options: Arc::new(serde_json::from_value(options).unwrap()),
+            reg_maps: Arc::new(RegisterMaps::default()),
+            rules: Arc::new(RuleSet::default()),
+            scripts: Arc::new(Scripts::default()),
+        }
+    }
+
+    fn local_state(sent_steps: usize) -> LocalState {
+        LocalState {
+            sent_steps,
+            batching: false,
+            cursor: None,
+            filter: None,
+            step_results: mpsc::channel(4).0,
+        }
+    }
+
+    fn binary_packets(receiver: &mut UnboundedReceiver<Message>) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        while let Ok(Some(message)) = receiver.try_next() {
+            if message.is_binary() {
+                packets.push(message.into_bytes());
+            }
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn sends_transactions_which_do_not_fit_the_packet() {
+        let state = state(Some(vp(&[
+            "W;core0;0;80000000;20;16;112233445566778899aabbccddeeff00",
+            "R;core0;0;10;30;4",
+            "R;;0;10;40;4;1",
+            "R;core0;0;invalid;50;4;invalid",
+        ])));
+        let (mut sender, mut receiver) = unbounded();
+        let mut l_state = local_state(0);
+        send_transactions(&mut sender, &state, &mut l_state).await;
+        assert_eq!(l_state.sent_steps, 4);
+
+        let packets = binary_packets(&mut receiver);
+        assert_eq!(packets.len(), 1);
+        let packet = &packets[0];
+        assert_eq!(packet.len(), 8 + 4 * Transaction::BIN_SIZE);
+        let record = |i: usize| &packet[8 + i * Transaction::BIN_SIZE..][..Transaction::BIN_SIZE];
+        let data = |i: usize| u64::from_le_bytes(record(i)[20..28].try_into().unwrap());
+        // wide data is sent with its low 64 bits, missing values as 0
+        assert_eq!(data(0), 0x99aabbccddeeff00);
+        assert_eq!(data(1), 0);
+        assert_eq!(record(2)[9], 0);
+        assert_eq!(record(3)[11..19], [0; 8]);
+    }
+
+    fn step_response(start: usize, lines: &[&str]) -> StepResponse {
+        StepResponse {
+            trans: lines.iter().map(|line| line.to_string()).collect(),
+            start,
+            steps_done: None,
+            trans_count: lines.len(),
+        }
+    }
+
+    #[tokio::test]
+    async fn continues_streaming_after_batched_steps() {
+        let vp_mutex = Arc::new(Mutex::new(Some(vp(&[]))));
+        let (mut sender, mut receiver) = unbounded();
+        // the client is behind the start of the step
+        let mut l_state = local_state(2);
+        l_state.batching = true;
+        let response = step_response(5, &["R;core0;0;10;10;4;1", "W;core0;0;14;20;4;2"]);
+        send_step_result(&mut sender, vp_mutex.clone(), &mut l_state, Ok(response)).await;
+        assert_eq!(l_state.sent_steps, 7);
+        assert!(!l_state.batching);
+        let Ok(Some(message)) = receiver.try_next() else {
+            panic!("expected step response");
+        };
+        let command: GenericCommand = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        let response: serde_json::Value = serde_json::from_str(&command.value).unwrap();
+        assert_eq!(response["trans"].as_array().unwrap().len(), 2);
+
+        // filtered transactions are counted as sent as well
+        l_state.batching = true;
+        l_state.filter = Some(
+            TransactionFilter::try_from(FilterCommand {
+                action: Some(crate::transaction::TransactionCmd::Write),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let response = step_response(7, &["R;core0;0;10;30;4;1", "W;core0;0;14;40;4;2"]);
+        send_step_result(&mut sender, vp_mutex, &mut l_state, Ok(response)).await;
+        assert_eq!(l_state.sent_steps, 9);
+        let Ok(Some(message)) = receiver.try_next() else {
+            panic!("expected step response");
+        };
+        let command: GenericCommand = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        let response: serde_json::Value = serde_json::from_str(&command.value).unwrap();
+        assert_eq!(response["trans"][0], "W;core0;0;14;40;4;2");
+        assert_eq!(response["trans"].as_array().unwrap().len(), 1);
+    }
+
+    fn messages(receiver: &mut UnboundedReceiver<Message>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while let Ok(Some(message)) = receiver.try_next() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn command(message: &Message) -> GenericCommand {
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn resyncs_lagging_clients() {
+        let state = state(Some(vp(&["R;core0;0;10;10;4;1", "W;core0;0;14;20;4;2"])));
+        let (mut sender, mut receiver) = unbounded();
+        let mut l_state = local_state(1);
+
+        let warning = crate::ranges::LayoutWarning::InvalidRange {
+            module: String::from("ram"),
+            start: String::from("x"),
+            end: String::from("0"),
+        };
+        let update = Ok(VPCtrlMsg::Warning(warning));
+        handle_vp_update(&mut sender, &state, &mut l_state, update).await;
+        let sent = messages(&mut receiver);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(command(&sent[0]).command, Command::Warning);
+        assert!(command(&sent[0]).value.contains("InvalidRange"));
+
+        let update = Err(RecvError::Lagged(40));
+        handle_vp_update(&mut sender, &state, &mut l_state, update).await;
+        let sent = messages(&mut receiver);
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].to_str().unwrap().contains("\"modules\":[\"ram\"]"));
+        assert_eq!(sent[1].as_bytes().len(), 8 + Transaction::BIN_SIZE);
+        assert_eq!(l_state.sent_steps, 2);
+        assert_eq!(command(&sent[2]).command, Command::Stats);
+    }
+
+    #[tokio::test]
+    async fn attaches_to_running_vps() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let trace = b"I;ram;0;fff\nR;core0;0;10;1;4;1\nW;core0;0;14;2;4;2\n";
+            tokio::io::AsyncWriteExt::write_all(&mut stream, trace)
+                .await
+                .unwrap();
+        });
+
+        let mut state = state(None);
+        let (proxy, mut proxy_recv) = mpsc::channel(4);
+        state.gdb.proxy_sender = proxy;
+        let state = Arc::new(state);
+        let mut finished = state.vp_channel.subscribe();
+        let (mut sender, mut receiver) = unbounded();
+
+        let attach = format!("{{\"trace\": \"{address}\", \"debug_port\": 5005}}");
+        attach_vp(&mut sender, state.clone(), &attach).await;
+        let sent = messages(&mut receiver);
+        assert_eq!(command(&sent[0]).command, Command::Start);
+        assert_eq!(command(&sent[0]).value, "true");
+        assert!(matches!(
+            proxy_recv.try_recv(),
+            Ok(ProxyCmd::Session(Some(5005)))
+        ));
+
+        while !matches!(finished.recv().await, Ok(VPCtrlMsg::Finished)) {}
+        let mut vp_lock = state.vp.lock().await;
+        let vp = vp_lock.as_mut().unwrap();
+        assert_eq!(vp.mode, VPMode::Step);
+        assert_eq!(vp.arch.lock().await.modules, ["ram"]);
+        assert_eq!(vp.steps.lock().await.len(), 2);
+        drop(vp_lock);
+
+        // a second session is rejected while the first one runs
+        attach_vp(&mut sender, state.clone(), &attach).await;
+        let sent = messages(&mut receiver);
+        assert_eq!(command(&sent[0]).command, Command::Error);
+        assert_eq!(command(&sent[0]).value, "VP is running already");
+        // stopping only detaches
+        assert!(state.vp.lock().await.as_mut().unwrap().stop());
+    }
+
+    #[tokio::test]
+    async fn reports_failed_attaches() {
+        let state = Arc::new(state(None));
+        let (mut sender, mut receiver) = unbounded();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+
+        for (attach, error) in [
+            (
+                "{\"debug_port\": 5005}".to_owned(),
+                "could not parse AttachCommand",
+            ),
+            (
+                "{\"trace\": \"127.0.0.1:5006\", \"format\": \"ovp\"}".to_owned(),
+                "unknown trace format ovp",
+            ),
+            (
+                format!("{{\"trace\": \"{closed}\"}}"),
+                "could not attach to",
+            ),
+        ] {
+            attach_vp(&mut sender, state.clone(), &attach).await;
+            let sent = messages(&mut receiver);
+            assert_eq!(command(&sent[0]).command, Command::Error);
+            assert!(command(&sent[0]).value.starts_with(error), "{attach}");
+        }
+        assert!(state.vp.lock().await.is_none());
+    }
+}
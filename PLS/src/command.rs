@@ -1,11 +1,34 @@
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::export::ExportFormat;
+use crate::filter::{AddressRange, FilterCommand};
+use crate::shadow::ShadowValue;
+use crate::source::TraceSource;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Command {
     Start,
+    /// connect to a VP which was started outside of PLS
+    Attach,
     Status,
     Step,
     Options,
+    Error,
+    Cursor,
+    Shadow,
+    Decode,
+    Filter,
+    Query,
+    Page,
+    Stats,
+    Export,
+    /// assertion violation reported by the server
+    Violation,
+    /// layout or address range warning reported by the server
+    Warning,
+    /// annotation, metric or event of a script
+    Script,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,20 +43,196 @@ pub struct StartCommand {
     pub proj: String,
     pub args: String,
     pub gdb_arch: String,
+    /// where the trace is read from, by default the debug bus port of the VP
+    #[serde(default)]
+    pub source: Option<TraceSource>,
+}
+
+/// Session with a VP which was started by hand, e.g. under valgrind or on
+/// another machine behind an SSH tunnel
+#[derive(Deserialize, Debug)]
+pub struct AttachCommand {
+    /// `<host>:<port>` of the debug bus trace
+    pub trace: String,
+    /// local port of the gdb stub, stepping is only available with it
+    #[serde(default)]
+    pub debug_port: Option<u16>,
+    /// name of the trace format in the options, by default the RISC-V VP++ format
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// Granularity of a step performed on the virtual prototype
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum StepUnit {
+    /// execute the given number of instructions
+    #[default]
+    Instructions,
+    /// run until the given number of bus transactions was observed
+    Transactions,
+    /// run until a transaction shows that the simulation time advanced by the
+    /// given nanoseconds, the time is only known from the trace
+    Time,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct StepCommand {
-    /// number of steps to be performed on the virtual prototype
-    pub steps: u32,
+    /// number of steps to be performed on the virtual prototype, ns for time steps
+    #[serde(default)]
+    pub steps: u64,
+    /// unit in which steps are counted
+    #[serde(default)]
+    pub unit: StepUnit,
     /// indicates if the transactions should be sent as one message
+    #[serde(default)]
     pub batch_trans: bool,
+    /// stops the step in progress, instruction steps after their current
+    /// instruction and other steps by interrupting the VP
+    #[serde(default)]
+    pub cancel: bool,
 }
 
 #[derive(Serialize, Debug)]
 pub struct StepResponse {
+    /// transactions in trace line format, only filled for batched steps
     pub trans: Vec<String>,
-    pub steps_done: u32,
+    /// index of the first transaction of the step in the session
+    pub start: usize,
+    /// number of instructions executed on the virtual prototype, None for
+    /// transaction and time steps which let the VP run until they are reached
+    pub steps_done: Option<u32>,
+    /// number of transactions observed while stepping
+    pub trans_count: usize,
+}
+
+/// Moves the cursor of a client through the recorded transactions
+#[derive(Deserialize, Debug)]
+pub enum CursorCommand {
+    /// move back by the given number of transactions
+    Back(usize),
+    /// move forward by the given number of transactions
+    Forward(usize),
+    /// move to the transaction with the given index
+    Jump(usize),
+    /// follow incoming transactions again
+    Live,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CursorResponse {
+    /// index of the transaction the cursor points to, None if live
+    pub position: Option<usize>,
+    /// number of recorded transactions
+    pub total: usize,
+    /// transaction at the cursor in trace line format
+    pub transaction: Option<String>,
+    /// latest transaction of each layout module up to the cursor
+    pub modules: Vec<Option<String>>,
+    /// reconstructed registers of the target module at the cursor
+    pub registers: Vec<ShadowValue>,
+}
+
+/// Queries the shadow memory, either a single address or all registers of a module
+#[derive(Deserialize, Debug)]
+pub struct ShadowQuery {
+    /// name of the layout module
+    pub module: Option<String>,
+    /// address in hex
+    pub address: Option<String>,
+    /// number of bytes read at the address
+    #[serde(default = "default_shadow_length")]
+    pub length: u8,
+    /// transaction index of the state, defaults to the cursor of the client
+    pub at: Option<usize>,
+}
+
+fn default_shadow_length() -> u8 {
+    4
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShadowResponse {
+    pub at: Option<usize>,
+    pub values: Vec<ShadowValue>,
+}
+
+/// Searches the recorded transactions with a query expression
+#[derive(Deserialize, Debug)]
+pub struct QueryCommand {
+    pub query: String,
+    /// index of the transaction the search starts at
+    #[serde(default)]
+    pub start: usize,
+    /// maximum number of matches in the response, at most 10000. A response
+    /// searches up to a million transactions, even with fewer matches.
+    #[serde(default = "default_query_limit")]
+    pub limit: usize,
+}
+
+fn default_query_limit() -> usize {
+    1000
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryMatch {
+    pub index: usize,
+    /// transaction in trace line format
+    pub transaction: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryResponse {
+    pub matches: Vec<QueryMatch>,
+    /// index the search continues at, None if it reached the end of the store
+    pub next: Option<usize>,
+    /// number of recorded transactions
+    pub total: usize,
+}
+
+/// Requests a window of the recorded transactions, either from a position
+/// or centered around a simulation time. Target and address range restrict
+/// the window to the matching transactions.
+#[derive(Deserialize, Debug)]
+pub struct PageCommand {
+    /// position of the first transaction in the window
+    #[serde(default)]
+    pub start: usize,
+    /// simulation time with unit (e.g. 3.2ms), replaces start
+    pub around: Option<String>,
+    #[serde(default = "default_page_count")]
+    pub count: usize,
+    /// module name
+    pub target: Option<String>,
+    pub range: Option<AddressRange>,
+}
+
+fn default_page_count() -> usize {
+    1000
+}
+
+#[derive(Serialize, Debug)]
+pub struct PageResponse {
+    /// position of the first transaction in the selection
+    pub first: usize,
+    /// number of transactions matching target and range
+    pub selected: usize,
+    /// number of recorded transactions
+    pub total: usize,
+    pub transactions: Vec<QueryMatch>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportCommand {
+    pub format: ExportFormat,
+    /// only matching transactions are exported
+    pub filter: Option<FilterCommand>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportResponse {
+    pub format: ExportFormat,
+    /// path of the written file on the server
+    pub path: PathBuf,
 }
 
 #[derive(Deserialize, Debug)]
@@ -0,0 +1,82 @@
+use crate::command::CursorCommand;
+use crate::store::TransactionStore;
+use crate::transaction::Transaction;
+
+/// Moves a cursor through a history of `total` transactions. A position of
+/// None follows the live end of the history.
+pub fn seek(
+    position: Option<usize>,
+    cmd: &CursorCommand,
+    total: usize,
+) -> Result<Option<usize>, String> {
+    if total == 0 {
+        return Err(String::from("no transactions recorded"));
+    }
+    let last = total - 1;
+    let current = position.unwrap_or(last).min(last);
+
+    match *cmd {
+        CursorCommand::Back(n) => Ok(Some(current.saturating_sub(n))),
+        CursorCommand::Forward(n) => Ok(Some(current.saturating_add(n).min(last))),
+        CursorCommand::Jump(k) if k <= last => Ok(Some(k)),
+        CursorCommand::Jump(k) => Err(format!("transaction {k} is not recorded ({total} total)")),
+        CursorCommand::Live => Ok(None),
+    }
+}
+
+/// Returns the latest transaction of each module up to and including `position`
+pub fn module_state(
+    steps: &mut TransactionStore,
+    modules: usize,
+    position: usize,
+) -> Vec<Option<Transaction>> {
+    (0..modules)
+        .map(|target| {
+            let index = steps.last_of_target(target as u8, position)?;
+            steps.get(index).cloned()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeks_within_the_history() {
+        assert_eq!(seek(None, &CursorCommand::Back(2), 10), Ok(Some(7)));
+        assert_eq!(seek(Some(1), &CursorCommand::Back(5), 10), Ok(Some(0)));
+        assert_eq!(seek(Some(7), &CursorCommand::Forward(5), 10), Ok(Some(9)));
+        assert_eq!(
+            seek(Some(7), &CursorCommand::Forward(usize::MAX), 10),
+            Ok(Some(9))
+        );
+        assert_eq!(seek(None, &CursorCommand::Jump(3), 10), Ok(Some(3)));
+        assert!(seek(None, &CursorCommand::Jump(10), 10).is_err());
+        assert_eq!(seek(Some(3), &CursorCommand::Live, 10), Ok(None));
+        assert!(seek(None, &CursorCommand::Back(1), 0).is_err());
+        // a position beyond a shorter history, e.g. after a restart, is clamped
+        assert_eq!(seek(Some(20), &CursorCommand::Back(1), 10), Ok(Some(8)));
+    }
+
+    #[test]
+    fn collects_the_latest_transaction_of_each_module() {
+        let mut steps = TransactionStore::new(0, std::env::temp_dir());
+        for line in [
+            "W;core0;0;80000000;10;4;1",
+            "W;core0;2;10013000;20;4;41",
+            "W;core0;0;80000004;30;4;2",
+        ] {
+            steps.push(line.parse().unwrap());
+        }
+        let mut state = |position| -> Vec<Option<String>> {
+            module_state(&mut steps, 3, position)
+                .iter()
+                .map(|t| t.as_ref().map(|t| t.address.clone()))
+                .collect()
+        };
+        let module = |address: &str| Some(String::from(address));
+        assert_eq!(state(0), vec![module("80000000"), None, None]);
+        assert_eq!(state(2), vec![module("80000004"), None, module("10013000")]);
+    }
+}
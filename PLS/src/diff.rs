@@ -0,0 +1,401 @@
+use serde::Serialize;
+
+use crate::session::Session;
+use crate::transaction::Transaction;
+
+/// Number of transactions searched ahead to realign the streams
+const LOOKAHEAD: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct DiffOptions {
+    /// align transactions by target and address instead of all values
+    pub by_address: bool,
+    pub ignore_time: bool,
+    /// allowed difference of the simulation times in ns
+    pub time_tolerance: u64,
+    /// transactions of these modules are skipped
+    pub ignore_modules: Vec<String>,
+    /// maximum number of reported entries
+    pub limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub enum DiffEntry {
+    /// transaction of the first session without counterpart in the second
+    Missing { index: usize, transaction: String },
+    /// transaction of the second session without counterpart in the first
+    Extra { index: usize, transaction: String },
+    /// aligned transactions with different values
+    Changed {
+        index: usize,
+        other_index: usize,
+        transaction: String,
+        other: String,
+        fields: Vec<&'static str>,
+    },
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DiffReport {
+    /// positions of the first differing transactions in both sessions
+    pub first_divergence: Option<(usize, usize)>,
+    pub missing: usize,
+    pub extra: usize,
+    pub changed: usize,
+    pub entries: Vec<DiffEntry>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.first_divergence.is_none()
+    }
+
+    fn add(&mut self, limit: usize, (index, other_index): (usize, usize), entry: DiffEntry) {
+        if self.first_divergence.is_none() {
+            self.first_divergence = Some((index, other_index));
+        }
+        match entry {
+            DiffEntry::Missing { .. } => self.missing += 1,
+            DiffEntry::Extra { .. } => self.extra += 1,
+            DiffEntry::Changed { .. } => self.changed += 1,
+        }
+        if self.entries.len() < limit {
+            self.entries.push(entry);
+        }
+    }
+}
+
+impl std::fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some((index, other_index)) = self.first_divergence else {
+            return writeln!(f, "traces are equal");
+        };
+        writeln!(
+            f,
+            "first divergence at {index} / {other_index}: {} missing, {} extra, {} changed",
+            self.missing, self.extra, self.changed
+        )?;
+        for entry in self.entries.iter() {
+            match entry {
+                DiffEntry::Missing { index, transaction } => {
+                    writeln!(f, "- {index:>8}          {transaction}")?
+                }
+                DiffEntry::Extra { index, transaction } => {
+                    writeln!(f, "+          {index:>8} {transaction}")?
+                }
+                DiffEntry::Changed {
+                    index,
+                    other_index,
+                    transaction,
+                    other,
+                    fields,
+                } => {
+                    writeln!(f, "~ {index:>8}          {transaction}")?;
+                    writeln!(
+                        f,
+                        "~          {other_index:>8} {other} ({})",
+                        fields.join(", ")
+                    )?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares hex values as numbers, values which are wider than 64 bits or no
+/// hex numbers are compared without leading zeros and ignoring case
+fn same_value(a: &str, b: &str) -> bool {
+    match (u64::from_str_radix(a, 16), u64::from_str_radix(b, 16)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a
+            .trim_start_matches('0')
+            .eq_ignore_ascii_case(b.trim_start_matches('0')),
+    }
+}
+
+/// Compares two transactions with their target names
+struct Comparison<'a> {
+    options: &'a DiffOptions,
+    modules: &'a [String],
+    other_modules: &'a [String],
+}
+
+impl Comparison<'_> {
+    /// Targets are compared by module name, the sessions may use different layouts
+    fn same_target(&self, a: &Transaction, b: &Transaction) -> bool {
+        match (
+            self.modules.get(a.target as usize),
+            self.other_modules.get(b.target as usize),
+        ) {
+            (Some(a), Some(b)) => a == b,
+            _ => a.target == b.target,
+        }
+    }
+
+    /// Names of the differing values, the simulation time is never used for alignment
+    fn differences(&self, a: &Transaction, b: &Transaction) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if !self.same_target(a, b) {
+            fields.push("target");
+        }
+        if !same_value(&a.address, &b.address) {
+            fields.push("address");
+        }
+        if a.action != b.action {
+            fields.push("action");
+        }
+        if a.initiator != b.initiator {
+            fields.push("initiator");
+        }
+        if a.data_length != b.data_length {
+            fields.push("data_length");
+        }
+        if !same_value(&a.data, &b.data) {
+            fields.push("data");
+        }
+        if !self.options.ignore_time
+            && a.sim_time.abs_diff(b.sim_time) > self.options.time_tolerance
+        {
+            fields.push("sim_time");
+        }
+        fields
+    }
+
+    fn ignored(&self, modules: &[String], t: &Transaction) -> bool {
+        modules
+            .get(t.target as usize)
+            .is_some_and(|m| self.options.ignore_modules.contains(m))
+    }
+
+    /// Checks whether two transactions are counterparts in the aligned streams
+    fn aligned(&self, a: &Transaction, b: &Transaction) -> bool {
+        if self.options.by_address {
+            self.same_target(a, b) && same_value(&a.address, &b.address)
+        } else {
+            self.differences(a, b).iter().all(|f| *f == "sim_time")
+        }
+    }
+}
+
+/// Aligns the transactions of two sessions and reports their differences.
+/// Transactions are compared in sequence, after a mismatch the streams are
+/// realigned at the nearest matching transaction within a lookahead window.
+pub fn diff(session: &mut Session, other: &mut Session, options: &DiffOptions) -> DiffReport {
+    let cmp = Comparison {
+        options,
+        modules: &session.layout.modules,
+        other_modules: &other.layout.modules,
+    };
+    let (a, b) = (&mut session.store, &mut other.store);
+    let mut report = DiffReport::default();
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        if a.get(i).is_some_and(|t| cmp.ignored(cmp.modules, t)) {
+            i += 1;
+            continue;
+        }
+        if b.get(j).is_some_and(|t| cmp.ignored(cmp.other_modules, t)) {
+            j += 1;
+            continue;
+        }
+
+        let (Some(x), Some(y)) = (a.get(i).cloned(), b.get(j).cloned()) else {
+            if let Some(x) = a.get(i) {
+                let entry = DiffEntry::Missing {
+                    index: i,
+                    transaction: x.to_string(),
+                };
+                report.add(options.limit, (i, j), entry);
+                i += 1;
+            } else if let Some(y) = b.get(j) {
+                let entry = DiffEntry::Extra {
+                    index: j,
+                    transaction: y.to_string(),
+                };
+                report.add(options.limit, (i, j), entry);
+                j += 1;
+            }
+            continue;
+        };
+
+        if cmp.aligned(&x, &y) {
+            let fields = cmp.differences(&x, &y);
+            if !fields.is_empty() {
+                let entry = DiffEntry::Changed {
+                    index: i,
+                    other_index: j,
+                    transaction: x.to_string(),
+                    other: y.to_string(),
+                    fields,
+                };
+                report.add(options.limit, (i, j), entry);
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        // search the nearest transaction which realigns both streams
+        let mut skip = None;
+        for k in 1..=LOOKAHEAD {
+            if b.get(j + k).is_some_and(|t| cmp.aligned(&x, t)) {
+                skip = Some((0, k));
+                break;
+            }
+            if a.get(i + k).is_some_and(|t| cmp.aligned(t, &y)) {
+                skip = Some((k, 0));
+                break;
+            }
+        }
+
+        match skip {
+            Some((missing, extra)) => {
+                for index in i..i + missing {
+                    let Some(t) = a.get(index).filter(|t| !cmp.ignored(cmp.modules, t)) else {
+                        continue;
+                    };
+                    let transaction = t.to_string();
+                    report.add(
+                        options.limit,
+                        (index, j),
+                        DiffEntry::Missing { index, transaction },
+                    );
+                }
+                for index in j..j + extra {
+                    let Some(t) = b.get(index).filter(|t| !cmp.ignored(cmp.other_modules, t))
+                    else {
+                        continue;
+                    };
+                    let transaction = t.to_string();
+                    report.add(
+                        options.limit,
+                        (i, index),
+                        DiffEntry::Extra { index, transaction },
+                    );
+                }
+                i += missing;
+                j += extra;
+            }
+            None => {
+                let entry = DiffEntry::Changed {
+                    index: i,
+                    other_index: j,
+                    transaction: x.to_string(),
+                    other: y.to_string(),
+                    fields: cmp.differences(&x, &y),
+                };
+                report.add(options.limit, (i, j), entry);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::TransactionStore;
+    use crate::virtual_prototype::VPLayout;
+
+    fn session(addresses: &[&str]) -> Session {
+        let mut store = TransactionStore::new(0, std::env::temp_dir());
+        for (time, address) in addresses.iter().enumerate() {
+            let line = format!("W;core0;0;{address};{time};4;1");
+            store.push(line.parse().unwrap());
+        }
+        let layout = VPLayout {
+            modules: vec![String::from("ram")],
+            ..Default::default()
+        };
+        Session { layout, store }
+    }
+
+    fn options() -> DiffOptions {
+        DiffOptions {
+            limit: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn equal_sessions() {
+        let mut a = session(&["0", "4", "8"]);
+        let mut b = session(&["0", "4", "8"]);
+        assert!(diff(&mut a, &mut b, &options()).is_empty());
+    }
+
+    #[test]
+    fn realigns_after_missing_and_extra_transactions() {
+        let mut a = session(&["0", "4", "8", "c", "10"]);
+        let mut b = session(&["0", "4", "c", "20", "10"]);
+        let options = DiffOptions {
+            ignore_time: true,
+            ..options()
+        };
+        let report = diff(&mut a, &mut b, &options);
+        assert_eq!(report.first_divergence, Some((2, 2)));
+        assert_eq!((report.missing, report.extra, report.changed), (1, 1, 0));
+        assert!(matches!(
+            report.entries[0],
+            DiffEntry::Missing { index: 2, .. }
+        ));
+        assert!(matches!(
+            report.entries[1],
+            DiffEntry::Extra { index: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn reports_changed_times() {
+        let mut a = session(&["0", "4"]);
+        let mut b = session(&["0"]);
+        b.store.push("W;core0;0;4;6;4;1".parse().unwrap());
+        let report = diff(&mut a, &mut b, &options());
+        assert_eq!((report.missing, report.extra, report.changed), (0, 0, 1));
+        let DiffEntry::Changed {
+            index: 1, fields, ..
+        } = &report.entries[0]
+        else {
+            panic!("expected Changed");
+        };
+        assert_eq!(fields, &["sim_time"]);
+
+        let options = DiffOptions {
+            time_tolerance: 5,
+            ..options()
+        };
+        assert!(diff(&mut a, &mut b, &options).is_empty());
+    }
+
+    #[test]
+    fn compares_values_regardless_of_their_notation() {
+        let mut a = session(&["4"]);
+        for line in [
+            "W;core0;0;8;1;4;0A",
+            "W;core0;0;c;2;16;00112233445566778899AABBCCDDEEFF",
+        ] {
+            a.store.push(line.parse().unwrap());
+        }
+        let mut b = session(&["0004"]);
+        for line in [
+            "W;core0;0;08;1;4;a",
+            "W;core0;0;C;2;16;112233445566778899aabbccddeeff",
+        ] {
+            b.store.push(line.parse().unwrap());
+        }
+        assert!(diff(&mut a, &mut b, &options()).is_empty());
+
+        b.store.push("W;core0;0;10;3;4;1".parse().unwrap());
+        a.store.push("W;core0;0;10;3;4;10".parse().unwrap());
+        let report = diff(&mut a, &mut b, &options());
+        assert_eq!(report.changed, 1);
+        assert!(
+            matches!(&report.entries[0], DiffEntry::Changed { fields, .. } if fields == &["data"])
+        );
+    }
+}
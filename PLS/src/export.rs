@@ -0,0 +1,366 @@
+use futures::executor::block_on;
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::filter::TransactionFilter;
+use crate::hex;
+use crate::store::TransactionStore;
+use crate::transaction::{Transaction, TransactionCmd};
+use crate::virtual_prototype::VPLayout;
+
+pub mod arrow;
+pub mod csv;
+pub mod ndjson;
+pub mod perfetto;
+pub mod vcd;
+
+/// Transactions copied per lock of a live store
+const LIVE_CHUNK: usize = 16_384;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Vcd,
+    /// Chrome JSON trace events for Perfetto
+    Perfetto,
+    Csv,
+    /// one JSON object per line, starting with the layout
+    Ndjson,
+    /// Apache Arrow IPC file
+    Arrow,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Vcd => "vcd",
+            ExportFormat::Perfetto => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Arrow => "arrow",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Vcd => "text/plain",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Perfetto => "application/json",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Arrow => "application/vnd.apache.arrow.file",
+        }
+    }
+
+    /// Parses the lower case name used in URLs and on the command line
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "vcd" => Some(ExportFormat::Vcd),
+            "perfetto" => Some(ExportFormat::Perfetto),
+            "csv" => Some(ExportFormat::Csv),
+            "ndjson" => Some(ExportFormat::Ndjson),
+            "arrow" => Some(ExportFormat::Arrow),
+            _ => None,
+        }
+    }
+}
+
+/// Transaction with its target resolved to the module name, as written to
+/// CSV and NDJSON exports
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    pub index: usize,
+    pub sim_time: u64,
+    pub action: TransactionCmd,
+    pub initiator: String,
+    pub target: String,
+    pub target_index: u8,
+    pub address: String,
+    pub data_length: u8,
+    pub data: String,
+    /// fields of custom trace formats, only written to NDJSON
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Record {
+    pub fn new(index: usize, transaction: &Transaction, layout: &VPLayout) -> Record {
+        Record {
+            index,
+            sim_time: transaction.sim_time,
+            action: transaction.action,
+            initiator: transaction.initiator.clone(),
+            target: layout
+                .modules
+                .get(transaction.target as usize)
+                .cloned()
+                .unwrap_or_default(),
+            target_index: transaction.target,
+            address: transaction.address.clone(),
+            data_length: transaction.data_length,
+            data: transaction.data.clone(),
+            attributes: transaction.attributes.clone(),
+        }
+    }
+}
+
+impl From<Record> for Transaction {
+    fn from(record: Record) -> Self {
+        Transaction {
+            sim_time: record.sim_time,
+            action: record.action,
+            initiator: record.initiator,
+            target: record.target_index,
+            address: record.address,
+            data_length: record.data_length,
+            data: record.data,
+            attributes: record.attributes,
+        }
+    }
+}
+
+/// Store of the exported transactions
+pub enum SelectionStore<'a> {
+    /// store of a loaded session
+    Session(&'a mut TransactionStore),
+    /// store of a running VP with its length when the export started, it is
+    /// locked per chunk so the trace receiver is not blocked by the export
+    Live(&'a Mutex<TransactionStore>, usize),
+}
+
+/// Recorded transactions of a session which are exported
+pub struct Selection<'a> {
+    pub layout: &'a VPLayout,
+    pub store: SelectionStore<'a>,
+    pub filter: Option<&'a TransactionFilter>,
+}
+
+impl Selection<'_> {
+    /// Calls `f` with each transaction passing the filter, stops at the first
+    /// error. Live stores are locked blocking, so exports of running sessions
+    /// must run on a blocking thread.
+    pub fn for_each<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(usize, &Transaction) -> io::Result<()>,
+    {
+        let (modules, filter) = (&self.layout.modules, self.filter);
+        let mut visit = |index: usize, transaction: &Transaction| {
+            if filter.is_some_and(|f| !f.matches(transaction, modules)) {
+                return Ok(());
+            }
+            f(index, transaction)
+        };
+
+        match &mut self.store {
+            SelectionStore::Session(store) => {
+                let mut result = Ok(());
+                store.scan(0, |index, transaction| match visit(index, transaction) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(e) => {
+                        result = Err(e);
+                        ControlFlow::Break(())
+                    }
+                });
+                result
+            }
+            SelectionStore::Live(store, len) => {
+                let mut start = 0;
+                while start < *len {
+                    let end = (*len).min(start + LIVE_CHUNK);
+                    let chunk = block_on(store.lock()).range(start..end);
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    for (i, transaction) in chunk.iter().enumerate() {
+                        visit(start + i, transaction)?;
+                    }
+                    start += chunk.len();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Copy of the layout and filter of a running session for an export
+pub struct Snapshot {
+    pub layout: VPLayout,
+    pub store: Arc<Mutex<TransactionStore>>,
+    /// number of transactions which are exported
+    pub len: usize,
+    pub filter: Option<TransactionFilter>,
+}
+
+impl Snapshot {
+    /// Runs an export on a blocking thread
+    pub async fn export<T, F>(self, export: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut Selection) -> Result<T, String> + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || {
+            export(&mut Selection {
+                layout: &self.layout,
+                store: SelectionStore::Live(&self.store, self.len),
+                filter: self.filter.as_ref(),
+            })
+        })
+        .await
+        .map_err(|e| format!("export failed ({e})"))?
+    }
+}
+
+/// Writer which passes its data to a channel, e.g. to stream an export as
+/// HTTP body. Writing fails once the receiver is gone.
+pub struct ChannelWriter(pub mpsc::Sender<io::Result<Vec<u8>>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parses an address or data field of the transaction at `index` for the
+/// typed columns of an export. Empty fields are None, values which are no hex
+/// number of at most 64 bits are an error.
+pub fn hex_field(index: usize, name: &str, value: &str) -> io::Result<Option<u64>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    hex::parse(value).map(Some).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{name} of transaction {index}: {e}"),
+        )
+    })
+}
+
+/// Writes the selected transactions in the given format
+pub fn export(
+    format: ExportFormat,
+    selection: &mut Selection,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Vcd => vcd::write(selection, out),
+        ExportFormat::Perfetto => perfetto::write(selection, out),
+        ExportFormat::Csv => csv::write(selection, out),
+        ExportFormat::Ndjson => ndjson::write(selection, out),
+        ExportFormat::Arrow => arrow::write(selection, out),
+    }
+}
+
+/// Creates a file `<name>-<secs>.<extension>` in `dir` which did not exist
+/// before, a counter is appended for several files in the same second
+pub fn create_unique(dir: &Path, name: &str, extension: &str) -> io::Result<(PathBuf, File)> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    fs::create_dir_all(dir)?;
+    for n in 0.. {
+        let file_name = match n {
+            0 => format!("{name}-{secs}.{extension}"),
+            n => format!("{name}-{secs}-{n}.{extension}"),
+        };
+        let path = dir.join(file_name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("[EXPORT] no unique file name")
+}
+
+/// Exports the selected transactions to a new file in `dir` and returns its path
+pub fn export_to_file(
+    format: ExportFormat,
+    selection: &mut Selection,
+    dir: &Path,
+) -> Result<PathBuf, String> {
+    let (path, file) = create_unique(dir, "trace", format.extension())
+        .map_err(|e| format!("could not create export in {} ({e})", dir.display()))?;
+    let mut out = BufWriter::new(file);
+    let written = export(format, selection, &mut out).and_then(|_| out.flush());
+    match written {
+        Ok(()) => Ok(path),
+        Err(e) => Err(format!("could not export to {} ({e})", path.display())),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn layout() -> VPLayout {
+        VPLayout {
+            modules: vec!["rom".to_owned(), "ram".to_owned()],
+            start_addrs: vec!["0".to_owned(), "1000".to_owned()],
+            end_addrs: vec!["fff".to_owned(), "1fff".to_owned()],
+        }
+    }
+
+    pub(crate) fn store(lines: &[&str]) -> TransactionStore {
+        let mut store = TransactionStore::new(0, std::env::temp_dir());
+        for line in lines {
+            store.push(line.parse().unwrap());
+        }
+        store
+    }
+
+    /// Exports the transactions of a loaded session with the layout above
+    pub(crate) fn exported(format: ExportFormat, lines: &[&str]) -> io::Result<Vec<u8>> {
+        let (layout, mut store) = (layout(), store(lines));
+        let mut out = Vec::new();
+        export(
+            format,
+            &mut Selection {
+                layout: &layout,
+                store: SelectionStore::Session(&mut store),
+                filter: None,
+            },
+            &mut out,
+        )?;
+        Ok(out)
+    }
+
+    #[test]
+    fn exports_live_sessions_up_to_their_length() {
+        let lines = ["W;core0;1;1000;5;4;2a", "R;core1;0;10;7;4;"];
+        let live = Mutex::new(store(&[lines[0], lines[1], "R;core0;1;1004;9;4;0"]));
+        let layout = layout();
+        let mut out = Vec::new();
+        let mut selection = Selection {
+            layout: &layout,
+            store: SelectionStore::Live(&live, 2),
+            filter: None,
+        };
+        export(ExportFormat::Ndjson, &mut selection, &mut out).unwrap();
+        assert_eq!(out, exported(ExportFormat::Ndjson, &lines).unwrap());
+    }
+
+    #[test]
+    fn parses_hex_fields() {
+        assert_eq!(hex_field(0, "data", "").unwrap(), None);
+        assert_eq!(
+            hex_field(0, "data", "ffffffffffffffff").unwrap(),
+            Some(u64::MAX)
+        );
+        let error = hex_field(3, "data", "1ffffffffffffffff").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("transaction 3"));
+    }
+}
@@ -0,0 +1,210 @@
+use arrow_array::builder::{StringBuilder, UInt64Builder, UInt8Builder};
+use arrow_array::types::UInt8Type;
+use arrow_array::{ArrayRef, DictionaryArray, RecordBatch, StringArray};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use super::{hex_field, Selection};
+use crate::transaction::{Transaction, TransactionCmd};
+
+/// Number of rows per record batch
+const BATCH_SIZE: usize = 65_536;
+/// Values of the action dictionary
+const ACTIONS: [&str; 2] = ["Read", "Write"];
+
+fn dictionary(name: &str, nullable: bool) -> Field {
+    Field::new(
+        name,
+        DataType::Dictionary(Box::new(DataType::UInt8), Box::new(DataType::Utf8)),
+        nullable,
+    )
+}
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("index", DataType::UInt64, false),
+        Field::new("sim_time", DataType::UInt64, false),
+        dictionary("action", false),
+        Field::new("initiator", DataType::Utf8, false),
+        dictionary("target", true),
+        Field::new("address", DataType::UInt64, false),
+        Field::new("data_length", DataType::UInt8, false),
+        // transactions without data, e.g. some reads, have none
+        Field::new("data", DataType::UInt64, true),
+        // data wider than 64 bits as hex, data is null then
+        Field::new("wide_data", DataType::Utf8, true),
+    ])
+}
+
+/// Columns of the batch currently written
+struct Columns {
+    index: UInt64Builder,
+    sim_time: UInt64Builder,
+    action: UInt8Builder,
+    initiator: StringBuilder,
+    target: UInt8Builder,
+    address: UInt64Builder,
+    data_length: UInt8Builder,
+    data: UInt64Builder,
+    wide_data: StringBuilder,
+    len: usize,
+}
+
+impl Columns {
+    fn new() -> Columns {
+        Columns {
+            index: UInt64Builder::with_capacity(BATCH_SIZE),
+            sim_time: UInt64Builder::with_capacity(BATCH_SIZE),
+            action: UInt8Builder::with_capacity(BATCH_SIZE),
+            initiator: StringBuilder::new(),
+            target: UInt8Builder::with_capacity(BATCH_SIZE),
+            address: UInt64Builder::with_capacity(BATCH_SIZE),
+            data_length: UInt8Builder::with_capacity(BATCH_SIZE),
+            data: UInt64Builder::with_capacity(BATCH_SIZE),
+            wide_data: StringBuilder::new(),
+            len: 0,
+        }
+    }
+
+    fn append(&mut self, index: usize, t: &Transaction, modules: usize) -> io::Result<()> {
+        let address = hex_field(index, "address", &t.address)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("transaction {index} has no address"),
+            )
+        })?;
+        let (data, wide_data) = match hex_field(index, "data", &t.data) {
+            Ok(data) => (data, None),
+            Err(_) if t.data.chars().all(|c| c.is_ascii_hexdigit()) => (None, Some(&t.data)),
+            Err(e) => return Err(e),
+        };
+        self.index.append_value(index as u64);
+        self.sim_time.append_value(t.sim_time);
+        self.action.append_value(match t.action {
+            TransactionCmd::Read => 0,
+            TransactionCmd::Write => 1,
+        });
+        self.initiator.append_value(&t.initiator);
+        // targets outside of the layout have no name
+        self.target
+            .append_option(((t.target as usize) < modules).then_some(t.target));
+        self.address.append_value(address);
+        self.data_length.append_value(t.data_length);
+        self.data.append_option(data);
+        self.wide_data.append_option(wide_data);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Finishes the batch and resets the builders for the next one
+    fn finish(
+        &mut self,
+        schema: &Arc<Schema>,
+        actions: &ArrayRef,
+        modules: &ArrayRef,
+    ) -> io::Result<RecordBatch> {
+        let action = DictionaryArray::<UInt8Type>::try_new(self.action.finish(), actions.clone())
+            .map_err(io::Error::other)?;
+        let target = DictionaryArray::<UInt8Type>::try_new(self.target.finish(), modules.clone())
+            .map_err(io::Error::other)?;
+        self.len = 0;
+
+        RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(self.index.finish()),
+                Arc::new(self.sim_time.finish()),
+                Arc::new(action),
+                Arc::new(self.initiator.finish()),
+                Arc::new(target),
+                Arc::new(self.address.finish()),
+                Arc::new(self.data_length.finish()),
+                Arc::new(self.data.finish()),
+                Arc::new(self.wide_data.finish()),
+            ],
+        )
+        .map_err(io::Error::other)
+    }
+}
+
+/// Writes the transactions as Apache Arrow IPC file with typed columns,
+/// actions and targets are dictionary encoded
+pub fn write(selection: &mut Selection, out: &mut dyn Write) -> io::Result<()> {
+    let schema = Arc::new(schema());
+    let actions: ArrayRef = Arc::new(StringArray::from(ACTIONS.to_vec()));
+    let modules: ArrayRef = Arc::new(StringArray::from(selection.layout.modules.clone()));
+    let module_count = selection.layout.modules.len();
+
+    let mut writer = FileWriter::try_new(out, &schema).map_err(io::Error::other)?;
+    let mut columns = Columns::new();
+    selection.for_each(|index, transaction| {
+        columns.append(index, transaction, module_count)?;
+        if columns.len < BATCH_SIZE {
+            return Ok(());
+        }
+        let batch = columns.finish(&schema, &actions, &modules)?;
+        writer.write(&batch).map_err(io::Error::other)
+    })?;
+
+    if columns.len > 0 {
+        let batch = columns.finish(&schema, &actions, &modules)?;
+        writer.write(&batch).map_err(io::Error::other)?;
+    }
+    writer.finish().map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{tests::exported, ExportFormat};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt64Type;
+    use arrow_array::Array;
+    use arrow_ipc::reader::FileReader;
+
+    fn batches(lines: &[&str]) -> io::Result<Vec<RecordBatch>> {
+        let out = exported(ExportFormat::Arrow, lines)?;
+        let reader = FileReader::try_new(io::Cursor::new(out), None).unwrap();
+        Ok(reader.map(Result::unwrap).collect())
+    }
+
+    #[test]
+    fn writes_typed_columns() {
+        let wide = "00112233445566778899aabbccddeeff";
+        let batches = batches(&[
+            "W;core0;1;1004;5;4;2a",
+            "R;core1;7;10;7;2;",
+            &format!("R;dma;0;20;9;16;{wide}"),
+        ])
+        .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.schema().as_ref(), &schema());
+        assert_eq!(batch.num_rows(), 3);
+
+        let column = |name: &str| batch.column_by_name(name).unwrap();
+        let target = column("target").as_dictionary::<UInt8Type>();
+        let names = target.values().as_string::<i32>();
+        assert_eq!(names.value(target.keys().value(0) as usize), "ram");
+        assert!(target.is_null(1));
+        let action = column("action").as_dictionary::<UInt8Type>();
+        assert_eq!(action.keys().values().as_ref(), [1, 0, 0]);
+        let address = column("address").as_primitive::<UInt64Type>();
+        assert_eq!(address.values().as_ref(), [0x1004, 0x10, 0x20]);
+
+        let data = column("data").as_primitive::<UInt64Type>();
+        let wide_data = column("wide_data").as_string::<i32>();
+        assert_eq!(data.value(0), 0x2a);
+        assert!(data.is_null(1) && wide_data.is_null(1));
+        assert!(data.is_null(2));
+        assert_eq!(wide_data.value(2), wide);
+    }
+
+    #[test]
+    fn rejects_data_which_is_no_hex() {
+        let error = batches(&["R;core0;1;1000;0;4;xyz"]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
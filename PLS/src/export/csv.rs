@@ -0,0 +1,62 @@
+use std::io::{self, Write};
+
+use super::{Record, Selection};
+
+/// Quotes a field if it contains a separator, quote or line break
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Writes the transactions as comma separated values with a header row
+pub fn write(selection: &mut Selection, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "index,sim_time,action,initiator,target,target_index,address,data_length,data"
+    )?;
+    let layout = selection.layout;
+    selection.for_each(|index, transaction| {
+        let r = Record::new(index, transaction, layout);
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            r.index,
+            r.sim_time,
+            r.action,
+            field(&r.initiator),
+            field(&r.target),
+            r.target_index,
+            r.address,
+            r.data_length,
+            r.data
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::{tests::exported, ExportFormat};
+
+    #[test]
+    fn writes_a_row_per_transaction() {
+        let out = exported(
+            ExportFormat::Csv,
+            &[
+                "W;core0;1;1004;5;4;2a",
+                "R;cpu \"a\",b;0;10;7;2;",
+                "R;core0;9;0;8;1;0",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "index,sim_time,action,initiator,target,target_index,address,data_length,data\n\
+             0,5,Write,core0,ram,1,1004,4,2a\n\
+             1,7,Read,\"cpu \"\"a\"\",b\",rom,0,10,2,\n\
+             2,8,Read,core0,,9,0,1,0\n"
+        );
+    }
+}
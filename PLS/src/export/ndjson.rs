@@ -0,0 +1,85 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{self, Write};
+
+use super::{Record, Selection};
+use crate::transaction::Transaction;
+use crate::virtual_prototype::VPLayout;
+
+/// First line of a NDJSON export
+#[derive(Deserialize, Debug)]
+pub struct Header {
+    pub layout: VPLayout,
+}
+
+/// Writes the layout followed by one JSON object per transaction. The result
+/// can be loaded again as recorded session.
+pub fn write(selection: &mut Selection, out: &mut dyn Write) -> io::Result<()> {
+    let layout = selection.layout;
+    write_header(layout, out)?;
+    selection.for_each(|index, transaction| write_record(index, transaction, layout, out))
+}
+
+pub fn write_header(layout: &VPLayout, out: &mut dyn Write) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, &json!({ "layout": layout }))?;
+    writeln!(out)
+}
+
+pub fn write_record(
+    index: usize,
+    transaction: &Transaction,
+    layout: &VPLayout,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, &Record::new(index, transaction, layout))?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tests::{layout, store};
+    use crate::export::{export, ExportFormat, SelectionStore};
+    use crate::session::Session;
+    use crate::trace_format::TraceFormat;
+
+    #[test]
+    fn loads_exported_sessions_again() {
+        let mut store = store(&["W;core0;1;1004;5;4;2a", "R;core1;0;10;7;2;"]);
+        let mut custom: Transaction = "R;dma;1;1008;9;16;0123456789abcdef0123456789abcdef"
+            .parse()
+            .unwrap();
+        custom.attributes.insert("burst".to_owned(), "4".to_owned());
+        store.push(custom);
+
+        let layout = layout();
+        let mut out = Vec::new();
+        let mut selection = Selection {
+            layout: &layout,
+            store: SelectionStore::Session(&mut store),
+            filter: None,
+        };
+        export(ExportFormat::Ndjson, &mut selection, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("{\"layout\":{"));
+        assert!(text
+            .lines()
+            .next()
+            .unwrap()
+            .contains("\"modules\":[\"rom\",\"ram\"]"));
+        assert!(text.contains("\"index\":2,"));
+        assert!(text.contains("\"target\":\"ram\",\"target_index\":1,"));
+
+        let path = std::env::temp_dir().join(format!("pls-export-{}.ndjson", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let loaded = Session::load(&path, &TraceFormat::default());
+        let _ = std::fs::remove_file(path);
+        let mut loaded = loaded.unwrap();
+        assert_eq!(loaded.layout.modules, layout.modules);
+        assert_eq!(loaded.layout.end_addrs, layout.end_addrs);
+        assert_eq!(
+            format!("{:?}", loaded.store.range(0..3)),
+            format!("{:?}", store.range(0..3))
+        );
+    }
+}
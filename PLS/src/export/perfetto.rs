@@ -0,0 +1,213 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::Selection;
+use crate::transaction::{Transaction, TransactionCmd};
+
+/// Process of the initiator tracks
+const INITIATORS: u32 = 1;
+/// Process of the target tracks
+const TARGETS: u32 = 2;
+/// Length of a bandwidth counter window in ns
+const WINDOW: u64 = 1000;
+
+/// Writes trace events separated by commas and keeps the state of the tracks
+struct EventWriter<'a> {
+    out: &'a mut dyn Write,
+    first: bool,
+    modules: &'a [String],
+    /// track of each initiator
+    initiators: HashMap<String, u32>,
+    /// start of the current bandwidth window and the bytes of each target
+    window: u64,
+    bytes: Vec<u64>,
+}
+
+impl EventWriter<'_> {
+    fn event(&mut self, event: Value) -> io::Result<()> {
+        if !self.first {
+            writeln!(self.out, ",")?;
+        }
+        self.first = false;
+        serde_json::to_writer(&mut *self.out, &event)?;
+        Ok(())
+    }
+
+    fn flush_window(&mut self) -> io::Result<()> {
+        self.event(counter(self.modules, self.window, &self.bytes))
+    }
+
+    fn transaction(&mut self, t: &Transaction) -> io::Result<()> {
+        let Some(module) = self.modules.get(t.target as usize) else {
+            return Ok(());
+        };
+
+        let start = t.sim_time - t.sim_time % WINDOW;
+        if start > self.window {
+            self.flush_window()?;
+            self.bytes.iter_mut().for_each(|b| *b = 0);
+            // the counters drop to zero in windows without accesses
+            if start > self.window + WINDOW {
+                self.window += WINDOW;
+                self.flush_window()?;
+            }
+            self.window = start;
+        }
+        self.bytes[t.target as usize] += t.data_length as u64;
+
+        let tid = match self.initiators.get(&t.initiator) {
+            Some(tid) => *tid,
+            None => {
+                let tid = self.initiators.len() as u32;
+                self.initiators.insert(t.initiator.clone(), tid);
+                self.event(metadata("thread_name", INITIATORS, Some(tid), &t.initiator))?;
+                tid
+            }
+        };
+        self.event(access(t, module, INITIATORS, tid))?;
+        self.event(access(t, module, TARGETS, t.target as u32))
+    }
+}
+
+/// Trace event timestamps are given in us
+fn timestamp(ns: u64) -> f64 {
+    ns as f64 / 1000.0
+}
+
+fn metadata(name: &str, pid: u32, tid: Option<u32>, value: &str) -> Value {
+    match tid {
+        Some(tid) => {
+            json!({"ph": "M", "name": name, "pid": pid, "tid": tid, "args": {"name": value}})
+        }
+        None => json!({"ph": "M", "name": name, "pid": pid, "args": {"name": value}}),
+    }
+}
+
+/// Bandwidth of all targets in bytes per window, starting at `start`
+fn counter(modules: &[String], start: u64, bytes: &[u64]) -> Value {
+    let args: serde_json::Map<String, Value> = modules
+        .iter()
+        .zip(bytes)
+        .map(|(module, bytes)| (module.clone(), json!(bytes)))
+        .collect();
+    json!({"ph": "C", "name": "bytes", "pid": TARGETS, "ts": timestamp(start), "args": args})
+}
+
+fn access(t: &Transaction, module: &str, pid: u32, tid: u32) -> Value {
+    let action = match t.action {
+        TransactionCmd::Read => "R",
+        TransactionCmd::Write => "W",
+    };
+    json!({
+        "ph": "i",
+        "s": "t",
+        "name": format!("{action} 0x{}", t.address),
+        "cat": module,
+        "pid": pid,
+        "tid": tid,
+        "ts": timestamp(t.sim_time),
+        "args": {
+            "initiator": t.initiator,
+            "target": module,
+            "address": format!("0x{}", t.address),
+            "data": format!("0x{}", t.data),
+            "length": t.data_length,
+        },
+    })
+}
+
+/// Writes the transactions as Chrome JSON trace events, which can be opened
+/// in Perfetto. Each initiator and target has its own track, the bytes
+/// transferred per target are added as counters.
+pub fn write(selection: &mut Selection, out: &mut dyn Write) -> io::Result<()> {
+    let layout = selection.layout;
+    writeln!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+    let mut events = EventWriter {
+        out,
+        first: true,
+        modules: &layout.modules,
+        initiators: HashMap::new(),
+        window: 0,
+        bytes: vec![0; layout.modules.len()],
+    };
+    events.event(metadata("process_name", INITIATORS, None, "Initiators"))?;
+    events.event(metadata("process_name", TARGETS, None, "Targets"))?;
+    for (i, module) in layout.modules.iter().enumerate() {
+        events.event(metadata("thread_name", TARGETS, Some(i as u32), module))?;
+    }
+
+    let mut empty = true;
+    selection.for_each(|_, transaction| {
+        empty = false;
+        events.transaction(transaction)
+    })?;
+
+    if !empty {
+        events.flush_window()?;
+    }
+    writeln!(events.out, "\n]}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{tests::exported, ExportFormat};
+
+    fn events(lines: &[&str]) -> Vec<Value> {
+        let out = exported(ExportFormat::Perfetto, lines).unwrap();
+        let trace: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(trace["displayTimeUnit"], "ns");
+        trace["traceEvents"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn writes_tracks_of_initiators_and_targets() {
+        let events = events(&["W;core0;1;1004;500;4;2a", "R;core1;0;10;3500;2;ffff"]);
+        let names: Vec<&Value> = events
+            .iter()
+            .filter(|e| e["ph"] == "M")
+            .map(|e| &e["args"]["name"])
+            .collect();
+        assert_eq!(
+            names,
+            ["Initiators", "Targets", "rom", "ram", "core0", "core1"]
+        );
+
+        let accesses: Vec<&Value> = events.iter().filter(|e| e["ph"] == "i").collect();
+        assert_eq!(accesses.len(), 4);
+        assert_eq!(accesses[0]["name"], "W 0x1004");
+        assert_eq!(
+            (&accesses[0]["pid"], &accesses[0]["tid"]),
+            (&json!(1), &json!(0))
+        );
+        assert_eq!(
+            (&accesses[1]["pid"], &accesses[1]["tid"]),
+            (&json!(2), &json!(1))
+        );
+        assert_eq!(accesses[1]["ts"], 0.5);
+        assert_eq!(accesses[3]["args"]["data"], "0xffff");
+        assert_eq!(accesses[3]["cat"], "rom");
+    }
+
+    #[test]
+    fn drops_counters_to_zero_between_accesses() {
+        let trace = events(&["W;core0;1;1004;500;4;2a", "R;core1;0;10;3500;2;ffff"]);
+        let counters: Vec<(f64, &Value)> = trace
+            .iter()
+            .filter(|e| e["ph"] == "C")
+            .map(|e| (e["ts"].as_f64().unwrap(), &e["args"]))
+            .collect();
+        assert_eq!(
+            counters,
+            [
+                (0.0, &json!({"rom": 0, "ram": 4})),
+                (1.0, &json!({"rom": 0, "ram": 0})),
+                (3.0, &json!({"rom": 2, "ram": 0})),
+            ]
+        );
+
+        let empty = events(&[]);
+        assert!(empty.iter().all(|e| e["ph"] == "M"));
+    }
+}
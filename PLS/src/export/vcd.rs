@@ -0,0 +1,187 @@
+use std::io::{self, Write};
+
+use super::Selection;
+use crate::transaction::{Transaction, TransactionCmd};
+use crate::virtual_prototype::VPLayout;
+
+/// Width of the data wire if no transaction carries wider data
+const DATA_BITS: usize = 64;
+
+/// Identifiers of the signals of one module scope
+struct Scope {
+    access: String,
+    address: String,
+    data: String,
+    write: String,
+    initiator: String,
+}
+
+/// Encodes a signal number as VCD identifier of printable characters
+fn identifier(mut n: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return id;
+        }
+        n -= 1;
+    }
+}
+
+/// Module names are used as scope names, which must not contain whitespace
+fn scope_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+/// Bits of the hex `value` without leading zeros, unknown values are x
+fn binary(index: usize, name: &str, value: &str) -> io::Result<String> {
+    if value.is_empty() {
+        return Ok(String::from("x"));
+    }
+    let mut bits = String::with_capacity(value.len() * 4);
+    for c in value.chars() {
+        let digit = c.to_digit(16).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{name} {value} of transaction {index} is no hex number"),
+            )
+        })?;
+        bits.push_str(&format!("{digit:04b}"));
+    }
+    match bits.trim_start_matches('0') {
+        "" => Ok(String::from("0")),
+        trimmed => Ok(trimmed.to_owned()),
+    }
+}
+
+fn write_header(
+    layout: &VPLayout,
+    data_bits: &[usize],
+    out: &mut dyn Write,
+) -> io::Result<Vec<Scope>> {
+    writeln!(out, "$version ProtoLens $end")?;
+    writeln!(out, "$timescale 1ns $end")?;
+    writeln!(out, "$scope module bus $end")?;
+
+    let mut scopes = Vec::new();
+    for (i, module) in layout.modules.iter().enumerate() {
+        let scope = Scope {
+            access: identifier(i * 5),
+            address: identifier(i * 5 + 1),
+            data: identifier(i * 5 + 2),
+            write: identifier(i * 5 + 3),
+            initiator: identifier(i * 5 + 4),
+        };
+        writeln!(out, "$scope module {} $end", scope_name(module))?;
+        writeln!(out, "$var event 1 {} access $end", scope.access)?;
+        writeln!(out, "$var wire 64 {} address $end", scope.address)?;
+        writeln!(out, "$var wire {} {} data $end", data_bits[i], scope.data)?;
+        writeln!(out, "$var wire 1 {} write $end", scope.write)?;
+        writeln!(out, "$var string 1 {} initiator $end", scope.initiator)?;
+        writeln!(out, "$upscope $end")?;
+        scopes.push(scope);
+    }
+
+    writeln!(out, "$upscope $end")?;
+    writeln!(out, "$enddefinitions $end")?;
+    writeln!(out, "#0")?;
+    writeln!(out, "$dumpvars")?;
+    for scope in scopes.iter() {
+        writeln!(out, "bx {}", scope.address)?;
+        writeln!(out, "bx {}", scope.data)?;
+        writeln!(out, "x{}", scope.write)?;
+    }
+    writeln!(out, "$end")?;
+    Ok(scopes)
+}
+
+fn write_transaction(
+    scope: &Scope,
+    index: usize,
+    t: &Transaction,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let address = binary(index, "address", &t.address)?;
+    let data = binary(index, "data", &t.data)?;
+    let write = match t.action {
+        TransactionCmd::Read => 0,
+        TransactionCmd::Write => 1,
+    };
+    writeln!(out, "1{}", scope.access)?;
+    writeln!(out, "b{address} {}", scope.address)?;
+    writeln!(out, "b{data} {}", scope.data)?;
+    writeln!(out, "{write}{}", scope.write)?;
+    writeln!(out, "s{} {}", scope_name(&t.initiator), scope.initiator)
+}
+
+/// Writes the transactions as Value Change Dump with one scope per module.
+/// The data wire of a module is as wide as its widest data.
+pub fn write(selection: &mut Selection, out: &mut dyn Write) -> io::Result<()> {
+    let mut data_bits = vec![DATA_BITS; selection.layout.modules.len()];
+    selection.for_each(|_, transaction| {
+        if let Some(bits) = data_bits.get_mut(transaction.target as usize) {
+            *bits = (*bits).max(transaction.data.trim_start_matches('0').len() * 4);
+        }
+        Ok(())
+    })?;
+    let scopes = write_header(selection.layout, &data_bits, out)?;
+
+    let mut time = 0;
+    selection.for_each(|index, transaction| {
+        let Some(scope) = scopes.get(transaction.target as usize) else {
+            return Ok(());
+        };
+        // VCD timestamps must not decrease
+        if transaction.sim_time > time {
+            time = transaction.sim_time;
+            writeln!(out, "#{time}")?;
+        }
+        write_transaction(scope, index, transaction, out)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{tests::exported, ExportFormat};
+
+    fn vcd(lines: &[&str]) -> io::Result<String> {
+        exported(ExportFormat::Vcd, lines).map(|out| String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn writes_one_scope_per_module() {
+        let out = vcd(&[
+            "W;core0;1;1004;5;4;2a",
+            "R;core 1;0;10;5;4;",
+            "R;core0;1;1000;3;4;0",
+        ])
+        .unwrap();
+        assert!(out.contains("$scope module rom $end"));
+        assert!(out.contains("$var wire 64 ( data $end"));
+        // the timestamp does not go back for the transaction at 3 ns
+        let changes = out.split("$end\n").last().unwrap();
+        assert_eq!(
+            changes,
+            "#5\n\
+             1&\nb1000000000100 '\nb101010 (\n1)\nscore0 *\n\
+             1!\nb10000 \"\nbx #\n0$\nscore_1 %\n\
+             1&\nb1000000000000 '\nb0 (\n0)\nscore0 *\n"
+        );
+    }
+
+    #[test]
+    fn sizes_the_data_wire_from_the_widest_value() {
+        let wide = "R;core0;1;1000;0;16;00000000000000018000000000000001";
+        let out = vcd(&[wide, "R;core0;0;0;1;4;1"]).unwrap();
+        assert!(out.contains("$var wire 64 # data $end"));
+        assert!(out.contains("$var wire 68 ( data $end"));
+        assert!(out.contains(&format!("b11{}1 (", "0".repeat(62))));
+
+        let error = vcd(&["R;core0;1;1000;0;4;xyz"]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
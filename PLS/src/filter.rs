@@ -0,0 +1,174 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::hex;
+use crate::transaction::{Transaction, TransactionCmd};
+
+#[derive(Deserialize, Debug)]
+pub struct AddressRange {
+    /// first address in hex
+    pub start: String,
+    /// last address in hex (inclusive)
+    pub end: String,
+}
+
+impl AddressRange {
+    pub fn parse(&self) -> Result<(u64, u64), String> {
+        let (start, end) = (hex::parse(&self.start)?, hex::parse(&self.end)?);
+        if start > end {
+            return Err(String::from("Start address is bigger than end address"));
+        }
+        Ok((start, end))
+    }
+}
+
+/// Filter as sent by a client, all given criteria have to match
+#[derive(Deserialize, Debug, Default)]
+pub struct FilterCommand {
+    #[serde(default)]
+    pub modules: Vec<String>,
+    #[serde(default)]
+    pub ranges: Vec<AddressRange>,
+    pub action: Option<TransactionCmd>,
+    #[serde(default)]
+    pub initiators: Vec<String>,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    /// data value in hex, compared after applying the mask
+    pub data_value: Option<String>,
+    /// data mask in hex, defaults to all bits
+    pub data_mask: Option<String>,
+}
+
+/// Parsed form of a `FilterCommand`
+#[derive(Debug)]
+pub struct TransactionFilter {
+    modules: HashSet<String>,
+    ranges: Vec<(u64, u64)>,
+    action: Option<TransactionCmd>,
+    initiators: HashSet<String>,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    data: Option<(u64, u64)>,
+}
+
+impl TryFrom<FilterCommand> for TransactionFilter {
+    type Error = String;
+
+    fn try_from(cmd: FilterCommand) -> Result<Self, Self::Error> {
+        let mut ranges = Vec::new();
+        for range in cmd.ranges.iter() {
+            ranges.push(range.parse()?);
+        }
+
+        let data = match (cmd.data_value, cmd.data_mask) {
+            (Some(value), mask) => {
+                let mask = mask.as_deref().map_or(Ok(u64::MAX), hex::parse)?;
+                Some((hex::parse(&value)? & mask, mask))
+            }
+            (None, Some(_)) => return Err(String::from("data mask without data value")),
+            (None, None) => None,
+        };
+
+        Ok(TransactionFilter {
+            modules: cmd.modules.into_iter().collect(),
+            ranges,
+            action: cmd.action,
+            initiators: cmd.initiators.into_iter().collect(),
+            start_time: cmd.start_time,
+            end_time: cmd.end_time,
+            data,
+        })
+    }
+}
+
+impl TransactionFilter {
+    /// Checks a transaction, `modules` are the module names of the VP layout
+    pub fn matches(&self, transaction: &Transaction, modules: &[String]) -> bool {
+        if let Some(action) = &self.action {
+            if *action != transaction.action {
+                return false;
+            }
+        }
+        if self.start_time.is_some_and(|t| transaction.sim_time < t)
+            || self.end_time.is_some_and(|t| transaction.sim_time > t)
+        {
+            return false;
+        }
+        if !self.initiators.is_empty() && !self.initiators.contains(&transaction.initiator) {
+            return false;
+        }
+        if !self.modules.is_empty() {
+            let module = modules.get(transaction.target as usize);
+            if !module.is_some_and(|m| self.modules.contains(m)) {
+                return false;
+            }
+        }
+        if !self.ranges.is_empty() {
+            let Ok(address) = u64::from_str_radix(&transaction.address, 16) else {
+                return false;
+            };
+            if !self
+                .ranges
+                .iter()
+                .any(|(s, e)| (*s..=*e).contains(&address))
+            {
+                return false;
+            }
+        }
+        if let Some((value, mask)) = self.data {
+            let Ok(data) = u64::from_str_radix(&transaction.data, 16) else {
+                return false;
+            };
+            if data & mask != value {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(json: &str) -> TransactionFilter {
+        let cmd: FilterCommand = serde_json::from_str(json).unwrap();
+        TransactionFilter::try_from(cmd).unwrap()
+    }
+
+    #[test]
+    fn matches_all_criteria() {
+        let modules = [String::from("ram"), String::from("uart0")];
+        let t: Transaction = "W;core0;1;10013004;100;4;1f41".parse().unwrap();
+        let matches = |json: &str| filter(json).matches(&t, &modules);
+
+        assert!(matches("{}"));
+        assert!(matches(
+            r#"{"modules": ["uart0"], "action": "Write", "initiators": ["core0"]}"#
+        ));
+        assert!(!matches(r#"{"modules": ["ram"]}"#));
+        assert!(!matches(r#"{"action": "Read"}"#));
+        assert!(matches(
+            r#"{"ranges": [{"start": "0", "end": "f"}, {"start": "0x10013000", "end": "10013004"}]}"#
+        ));
+        assert!(!matches(
+            r#"{"ranges": [{"start": "10013005", "end": "10013fff"}]}"#
+        ));
+        assert!(matches(r#"{"start_time": 100, "end_time": 100}"#));
+        assert!(!matches(r#"{"start_time": 101}"#));
+        assert!(matches(r#"{"data_value": "41", "data_mask": "ff"}"#));
+        assert!(!matches(r#"{"data_value": "41"}"#));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        let parse = |json: &str| {
+            let cmd: FilterCommand = serde_json::from_str(json).unwrap();
+            TransactionFilter::try_from(cmd).map(|_| ())
+        };
+        assert!(parse(r#"{"ranges": [{"start": "20", "end": "10"}]}"#).is_err());
+        assert!(parse(r#"{"ranges": [{"start": "x", "end": "10"}]}"#).is_err());
+        assert!(parse(r#"{"data_mask": "ff"}"#).is_err());
+    }
+}
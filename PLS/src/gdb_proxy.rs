@@ -1,16 +1,30 @@
-use futures::FutureExt;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::process::{Child, Command};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 use crate::options::GdbOptions;
 
+/// Commands sent by the server to the gdb proxy
+#[derive(Debug)]
+pub enum ProxyCmd {
+    /// Forward a packet to the gdb stub, the reply is dropped if the stub is not available
+    Request(Vec<u8>, oneshot::Sender<Vec<u8>>),
+    /// Debug port of the current VP session, None if no VP is debuggable
+    Session(Option<u16>),
+    /// Stop the VP if it is running, e.g. on an assertion violation
+    Interrupt,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum GdbStatus {
     Connected,
@@ -67,145 +81,689 @@ pub fn start_gdbgui(options: &GdbOptions, arch: String, bin_path: &str) -> Resul
     }
 }
 
+/// Error reply for requests a client is not allowed to issue
+const ERR_READ_ONLY: &[u8] = b"E01";
+/// Error reply for requests which cannot be served while the target is running
+const ERR_RUNNING: &[u8] = b"E02";
+/// Error reply for requests which cannot be served without a gdb stub
+const ERR_NO_STUB: &[u8] = b"E03";
+/// Stop reply for resume requests which were interrupted by a lost gdb stub
+const STOP_KILLED: &[u8] = b"X09";
+
+/// A single unit of the gdb remote serial protocol
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Packet(Vec<u8>),
+    Interrupt,
+    Ack,
+    Nack,
+}
+
+/// Splits a byte stream into RSP frames. Checksums are not verified since
+/// both ends of the proxy are local TCP connections.
+#[derive(Default)]
+struct FrameParser {
+    buf: Vec<u8>,
+}
+
+impl FrameParser {
+    fn feed(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.buf.extend_from_slice(data);
+        let mut frames = Vec::new();
+        let mut pos = 0;
+
+        while pos < self.buf.len() {
+            match self.buf[pos] {
+                b'+' => frames.push(Frame::Ack),
+                b'-' => frames.push(Frame::Nack),
+                0x03 => frames.push(Frame::Interrupt),
+                b'$' | b'%' => {
+                    // a packet is complete once the two checksum digits are available
+                    let Some(end) = self.buf[pos..].iter().position(|b| *b == b'#') else {
+                        break;
+                    };
+                    let end = pos + end;
+                    if end + 2 >= self.buf.len() {
+                        break;
+                    }
+                    frames.push(Frame::Packet(self.buf[pos + 1..end].to_vec()));
+                    pos = end + 2;
+                }
+                _ => {}
+            }
+            pos += 1;
+        }
+
+        self.buf.drain(..pos);
+        frames
+    }
+}
+
+fn encode_packet(payload: &[u8]) -> Vec<u8> {
+    let checksum = payload.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    let mut packet = Vec::with_capacity(payload.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(format!("#{checksum:02x}").as_bytes());
+    packet
+}
+
+/// Returns true if the packet resumes execution of the target
+fn is_resume(payload: &[u8]) -> bool {
+    matches!(payload.first(), Some(b'c' | b's' | b'C' | b'S'))
+        || (payload.starts_with(b"vCont;") && !payload.starts_with(b"vCont?"))
+}
+
+/// Returns true if the packet only inspects the target state. Thread
+/// selections (`H`) change the state of the stub for all clients.
+fn is_read_only(payload: &[u8]) -> bool {
+    match payload.first() {
+        Some(b'g' | b'p' | b'm' | b'x' | b'?' | b'T') => true,
+        Some(b'q') => !payload.starts_with(b"qRcmd"),
+        Some(b'v') => payload.starts_with(b"vCont?") || payload.starts_with(b"vMustReplyEmpty"),
+        _ => false,
+    }
+}
+
+/// Returns true for thread selections of any or all threads, e.g. `Hg0`
+/// sent by gdb on connect, which leave the selection of the stub as it is
+fn is_any_thread(payload: &[u8]) -> bool {
+    matches!(payload, [b'H', b'g' | b'c', rest @ ..] if rest == b"0" || rest == b"-1")
+}
+
+/// Returns true for stop reply packets which mark the end of a resume request
+fn is_stop_reply(payload: &[u8]) -> bool {
+    matches!(payload.first(), Some(b'S' | b'T' | b'W' | b'X' | b'N'))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Client(usize),
+    Upstream(usize),
+}
+
+enum ProxyEvent {
+    Frame(Source, Frame),
+    Closed(Source),
+}
+
+/// Issuer of a request forwarded to the gdb stub
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Origin {
+    Client(usize),
+    Internal,
+}
+
+struct Request {
+    origin: Origin,
+    payload: Vec<u8>,
+    // only set for requests issued by the server
+    reply: Option<oneshot::Sender<Vec<u8>>>,
+}
+
+struct Client {
+    id: usize,
+    writer: OwnedWriteHalf,
+    reader: JoinHandle<()>,
+    no_ack: bool,
+}
+
+struct Upstream {
+    id: usize,
+    writer: OwnedWriteHalf,
+    reader: JoinHandle<()>,
+}
+
+/// Multiplexes any number of downstream gdb clients onto a single upstream
+/// connection to the gdb stub of the VP.
+///
+/// The oldest client is the driver and may control the execution of the VP.
+/// All other clients are observers which are restricted to read-only requests.
+/// Requests are serialized, so the stub only ever sees one client.
+struct Multiplexer {
+    address: String,
+    upstream_port: Option<u16>,
+    clients: Vec<Client>,
+    upstream: Option<Upstream>,
+    queue: VecDeque<Request>,
+    pending: Option<Request>,
+    running: bool,
+    next_id: usize,
+    events: mpsc::Sender<ProxyEvent>,
+    status_channel: Sender<GdbStatus>,
+}
+
+impl Multiplexer {
+    fn spawn_reader<R>(&self, mut read: R, source: Source) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let mut parser = FrameParser::default();
+            let mut buf = [0u8; 2048];
+            loop {
+                let bytes_read = match read.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                for frame in parser.feed(&buf[..bytes_read]) {
+                    if events.send(ProxyEvent::Frame(source, frame)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            let _ = events.send(ProxyEvent::Closed(source)).await;
+        })
+    }
+
+    /// Connects to the gdb stub of the current session if not connected already
+    async fn connect_upstream(&mut self) -> bool {
+        if self.upstream.is_some() {
+            return true;
+        }
+        let Some(port) = self.upstream_port else {
+            return false;
+        };
+
+        let remote = match TcpStream::connect((self.address.as_str(), port)).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                println!("[PROXY] could not connect to gdb stub on port {port} ({e})");
+                return false;
+            }
+        };
+        let (read, writer) = remote.into_split();
+        self.next_id += 1;
+        let reader = self.spawn_reader(read, Source::Upstream(self.next_id));
+        self.upstream = Some(Upstream {
+            id: self.next_id,
+            writer,
+            reader,
+        });
+        println!("[PROXY] gdb stub connection established on port {port}");
+        // notify the server
+        let _ = self.status_channel.send(GdbStatus::Connected);
+        true
+    }
+
+    async fn add_client(&mut self, stream: TcpStream) {
+        // a missing stub is not fatal, requests are answered with errors until it is available
+        self.connect_upstream().await;
+
+        let (read, writer) = stream.into_split();
+        self.next_id += 1;
+        let id = self.next_id;
+        let reader = self.spawn_reader(read, Source::Client(id));
+        let role = if self.clients.is_empty() {
+            "driver"
+        } else {
+            "observer"
+        };
+        println!("[PROXY] gdb client [{id}] connected as {role}");
+        self.clients.push(Client {
+            id,
+            writer,
+            reader,
+            no_ack: false,
+        });
+    }
+
+    fn is_driver(&self, id: usize) -> bool {
+        self.clients.first().is_some_and(|c| c.id == id)
+    }
+
+    async fn remove_client(&mut self, id: usize) {
+        let Some(idx) = self.clients.iter().position(|c| c.id == id) else {
+            return;
+        };
+        let client = self.clients.remove(idx);
+        client.reader.abort();
+        println!("[PROXY] gdb client [{id}] disconnected");
+
+        self.queue.retain(|r| r.origin != Origin::Client(id));
+        if let Some(new_driver) = self.clients.first().filter(|_| idx == 0) {
+            println!("[PROXY] gdb client [{}] is now driver", new_driver.id);
+        }
+
+        // requests of the server, e.g. of a step, still wait for their replies
+        let internal =
+            (self.pending.iter().chain(self.queue.iter())).any(|r| r.origin == Origin::Internal);
+        if self.clients.is_empty() && !internal {
+            self.close_upstream().await;
+        }
+    }
+
+    /// Closes the stub connection and fails all requests which are waiting for it
+    async fn close_upstream(&mut self) {
+        if let Some(upstream) = self.upstream.take() {
+            upstream.reader.abort();
+            println!("[PROXY] gdb stub connection closed");
+            let _ = self.status_channel.send(GdbStatus::NotConnected);
+        }
+
+        let pending = self.pending.take();
+        let queued: Vec<Request> = self.queue.drain(..).collect();
+        for request in pending.into_iter().chain(queued) {
+            let Origin::Client(id) = request.origin else {
+                continue;
+            };
+            if is_resume(&request.payload) {
+                self.send_client(id, STOP_KILLED).await;
+            } else {
+                self.send_client(id, ERR_NO_STUB).await;
+            }
+        }
+        self.running = false;
+    }
+
+    async fn set_session(&mut self, port: Option<u16>) {
+        if port == self.upstream_port {
+            return;
+        }
+        match port {
+            Some(p) => println!("[PROXY] gdb stub of current session is on port {p}"),
+            None => println!("[PROXY] no gdb stub available in current session"),
+        }
+        self.close_upstream().await;
+        self.upstream_port = port;
+        if !self.clients.is_empty() {
+            self.connect_upstream().await;
+        }
+    }
+
+    async fn send_client(&mut self, id: usize, payload: &[u8]) {
+        if let Some(client) = self.clients.iter_mut().find(|c| c.id == id) {
+            let _ = client.writer.write_all(&encode_packet(payload)).await;
+        }
+    }
+
+    async fn send_upstream(&mut self, data: &[u8]) {
+        let Some(upstream) = self.upstream.as_mut() else {
+            return;
+        };
+        if let Err(e) = upstream.writer.write_all(data).await {
+            println!("[PROXY] could not write to gdb stub {e}");
+        }
+    }
+
+    /// Forwards the next queued request if the stub is idle
+    async fn dispatch(&mut self) {
+        if self.pending.is_some() || self.upstream.is_none() {
+            return;
+        }
+        let Some(request) = self.queue.pop_front() else {
+            return;
+        };
+        self.running = is_resume(&request.payload);
+        self.send_upstream(&encode_packet(&request.payload)).await;
+        self.pending = Some(request);
+    }
+
+    async fn handle_client_packet(&mut self, id: usize, payload: Vec<u8>) {
+        let Some(client) = self.clients.iter_mut().find(|c| c.id == id) else {
+            return;
+        };
+        if !client.no_ack {
+            let _ = client.writer.write_all(b"+").await;
+        }
+
+        // acknowledgements are handled by the proxy for each side separately
+        if payload == b"QStartNoAckMode" {
+            self.send_client(id, b"OK").await;
+            if let Some(client) = self.clients.iter_mut().find(|c| c.id == id) {
+                client.no_ack = true;
+            }
+            return;
+        }
+        // detaching or killing would affect all clients, only drop the connection
+        if payload.first() == Some(&b'D') {
+            self.send_client(id, b"OK").await;
+            self.remove_client(id).await;
+            return;
+        }
+        if payload.first() == Some(&b'k') || payload.starts_with(b"vKill") {
+            self.remove_client(id).await;
+            return;
+        }
+
+        if !self.connect_upstream().await {
+            self.send_client(id, ERR_NO_STUB).await;
+            return;
+        }
+
+        if !self.is_driver(id) {
+            // answered by the proxy, the stub keeps the selection of the driver
+            if is_any_thread(&payload) {
+                self.send_client(id, b"OK").await;
+                return;
+            }
+            if !is_read_only(&payload) {
+                self.send_client(id, ERR_READ_ONLY).await;
+                return;
+            }
+            if self.running {
+                self.send_client(id, ERR_RUNNING).await;
+                return;
+            }
+        }
+
+        self.queue.push_back(Request {
+            origin: Origin::Client(id),
+            payload,
+            reply: None,
+        });
+        self.dispatch().await;
+    }
+
+    async fn handle_upstream_packet(&mut self, payload: Vec<u8>) {
+        self.send_upstream(b"+").await;
+
+        let Some(request) = self.pending.as_ref() else {
+            println!("[PROXY] dropping unsolicited packet from gdb stub");
+            return;
+        };
+        let origin = request.origin;
+        // console output may precede the stop reply of a resume request
+        let done = !self.running || is_stop_reply(&payload);
+
+        if let Origin::Client(id) = origin {
+            self.send_client(id, &payload).await;
+        }
+
+        if done {
+            if let Some(reply) = self.pending.take().and_then(|r| r.reply) {
+                let _ = reply.send(payload);
+            }
+            self.running = false;
+            self.dispatch().await;
+        }
+    }
+
+    async fn handle_event(&mut self, event: ProxyEvent) {
+        match event {
+            ProxyEvent::Frame(Source::Client(id), frame) => match frame {
+                Frame::Packet(payload) => self.handle_client_packet(id, payload).await,
+                Frame::Interrupt => {
+                    if self.is_driver(id) {
+                        self.send_upstream(&[0x03]).await;
+                    }
+                }
+                Frame::Ack | Frame::Nack => {}
+            },
+            ProxyEvent::Frame(Source::Upstream(id), frame) => {
+                if self.upstream.as_ref().is_some_and(|u| u.id == id) {
+                    if let Frame::Packet(payload) = frame {
+                        self.handle_upstream_packet(payload).await;
+                    }
+                }
+            }
+            ProxyEvent::Closed(Source::Client(id)) => self.remove_client(id).await,
+            ProxyEvent::Closed(Source::Upstream(id)) => {
+                // clients stay connected and are served again once a new VP is started
+                if self.upstream.as_ref().is_some_and(|u| u.id == id) {
+                    self.close_upstream().await;
+                }
+            }
+        }
+    }
+
+    /// Interrupts a running VP, the stop reply is forwarded to the client which resumed it
+    async fn interrupt(&mut self) {
+        if self.running {
+            println!("[PROXY] interrupting VP");
+            self.send_upstream(&[0x03]).await;
+        }
+    }
+
+    /// Queues a request of the server, dropping the reply channel signals a missing stub
+    async fn inject(&mut self, payload: Vec<u8>, reply: oneshot::Sender<Vec<u8>>) {
+        if !self.connect_upstream().await {
+            println!("[PROXY] cannot inject packet without gdb stub connection");
+            return;
+        }
+        self.queue.push_back(Request {
+            origin: Origin::Internal,
+            payload,
+            reply: Some(reply),
+        });
+        self.dispatch().await;
+    }
+}
+
 pub async fn run(
     address: String,
-    upstream_port: u16,
     downstream_port: u16,
-    cmd_channel: Sender<u32>,
+    mut cmd_channel: mpsc::Receiver<ProxyCmd>,
     status_channel: Sender<GdbStatus>,
 ) -> Result<(), Box<dyn Error>> {
-    let mut upstream_addr = address.clone();
     let mut downstream_addr = address.clone();
 
-    upstream_addr.push(':');
-    upstream_addr.push_str(&upstream_port.to_string());
     downstream_addr.push(':');
     downstream_addr.push_str(&downstream_port.to_string());
 
     let listener_addr: SocketAddr = downstream_addr.parse().expect("socket addr");
     let listener = TcpListener::bind(&listener_addr).await?;
 
-    let mut recv = cmd_channel.subscribe();
     let _ = &mut status_channel.send(GdbStatus::NotConnected);
 
-    loop {
-        println!("[PROXY] Waiting for downstream connection on {listener_addr}");
-        let (mut client_stream, _) = listener.accept().await?;
+    let (events, mut event_recv) = mpsc::channel::<ProxyEvent>(64);
+    let mut mux = Multiplexer {
+        address,
+        upstream_port: None,
+        clients: Vec::new(),
+        upstream: None,
+        queue: VecDeque::new(),
+        pending: None,
+        running: false,
+        next_id: 0,
+        events,
+        status_channel,
+    };
 
-        let mut remote = TcpStream::connect(&upstream_addr).await?;
+    println!("[PROXY] Waiting for downstream connections on {listener_addr}");
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((client_stream, _)) => mux.add_client(client_stream).await,
+                Err(e) => println!("[PROXY] could not accept downstream connection {e}"),
+            },
+            // the multiplexer holds a sender, so the channel is never closed
+            Some(event) = event_recv.recv() => mux.handle_event(event).await,
+            // check for commands of the server
+            result = cmd_channel.recv() => match result {
+                Some(ProxyCmd::Request(payload, reply)) => mux.inject(payload, reply).await,
+                Some(ProxyCmd::Session(port)) => mux.set_session(port).await,
+                Some(ProxyCmd::Interrupt) => mux.interrupt().await,
+                None => return Ok(()),
+            }
+        }
+    }
+}
 
-        // notify the server
-        let _ = &mut status_channel.send(GdbStatus::Connected);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
 
-        let (mut client_read, mut client_write) = client_stream.split();
-        let (mut remote_read, mut remote_write) = remote.split();
+    #[test]
+    fn splits_frames() {
+        let mut parser = FrameParser::default();
+        assert_eq!(
+            parser.feed(b"+$g#67\x03$m10"),
+            vec![Frame::Ack, Frame::Packet(b"g".to_vec()), Frame::Interrupt,]
+        );
+        // incomplete packets are kept until their checksum arrived
+        assert_eq!(parser.feed(b",4#"), vec![]);
+        assert_eq!(
+            parser.feed(b"8a-"),
+            vec![Frame::Packet(b"m10,4".to_vec()), Frame::Nack]
+        );
+        assert_eq!(encode_packet(b"OK"), b"$OK#9a");
+    }
 
-        let (rx, _) = broadcast::channel::<()>(32);
+    #[test]
+    fn classifies_packets() {
+        assert!(is_resume(b"c") && is_resume(b"vCont;s:1") && !is_resume(b"vCont?"));
+        assert!(is_read_only(b"m80000000,4") && is_read_only(b"qSupported"));
+        assert!(!is_read_only(b"qRcmd,7265736574") && !is_read_only(b"Hg1"));
+        assert!(!is_read_only(b"M80000000,4:00000000") && !is_read_only(b"Z0,80000000,4"));
+        assert!(is_any_thread(b"Hg0") && is_any_thread(b"Hc-1") && !is_any_thread(b"Hg1"));
+        assert!(is_stop_reply(b"T05thread:1;") && !is_stop_reply(b"OK"));
+    }
 
-        let (down, up) = tokio::join! {
-            stream_copy(&mut remote_read, &mut client_write,rx.subscribe(),None)
-                .then(|r| { let _ = rx.send(()); async { r } }),
-            stream_copy(&mut client_read, &mut remote_write,rx.subscribe(),Some(&mut recv))
-                .then(|r| { let _ = rx.send(()); async { r } }),
+    /// gdb stub which answers `g` with registers, other packets with OK and
+    /// resumes until it is interrupted
+    async fn stub(listener: TcpListener) {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            return;
         };
-        if down.is_ok() && up.is_ok() {
-            println!(
-                "[PROXY] Up/downstream copied {}/{} bytes",
-                down.unwrap(),
-                up.unwrap()
-            );
-        }
-        let _ = &mut status_channel.send(GdbStatus::NotConnected);
-    }
-}
-
-async fn stream_copy<R, W>(
-    read: &mut R,
-    write: &mut W,
-    mut kill_signal: Receiver<()>,
-    recv: Option<&mut Receiver<u32>>,
-) -> tokio::io::Result<usize>
-where
-    R: tokio::io::AsyncRead + Unpin,
-    W: tokio::io::AsyncWrite + Unpin,
-{
-    let mut copied = 0;
-    let mut buf = [0u8; 2048];
-
-    // loop behaviour differs for up and downstream
-    if recv.is_some() {
-        println!("[PROXY] gdbgui connection established");
-        let step_buf = "$vCont;c#a8".to_string();
-        let rec = recv.unwrap();
+        let mut parser = FrameParser::default();
+        let mut buf = [0u8; 1024];
+        let mut running = false;
         loop {
-            let bytes_read;
-            let mut step_read = false;
-            tokio::select! {
-                biased;
-
-                result = read.read(&mut buf) => {
-                    use std::io::ErrorKind::{ConnectionReset, ConnectionAborted};
-                    bytes_read = result.or_else(|e| match e.kind() {
-                        ConnectionReset | ConnectionAborted => Ok(copied),
-                        _ => Err(e)
-                    })?;
-                },
-
-                // check for external inject command
-                result = rec.recv() => {
-                    bytes_read = 0;
-                    step_read = true;
-                    if result.is_ok(){
-                        let steps = result.unwrap();
-                        println!("[PROXY] injecting {steps} step packets");
-                        for _ in 0..steps{
-                            write.write_all(step_buf.as_bytes()).await?;
-                        }
+            let n = match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            for frame in parser.feed(&buf[..n]) {
+                let reply: &[u8] = match frame {
+                    Frame::Packet(payload) if is_resume(&payload) => {
+                        running = true;
+                        let _ = stream.write_all(b"+").await;
+                        continue;
                     }
-                },
-                _ = kill_signal.recv() =>{
-                    break;
-                }
+                    Frame::Packet(payload) if payload == b"g" => b"00112233",
+                    Frame::Packet(_) => b"OK",
+                    Frame::Interrupt if running => {
+                        running = false;
+                        b"S02"
+                    }
+                    _ => continue,
+                };
+                let _ = stream.write_all(b"+").await;
+                let _ = stream.write_all(&encode_packet(reply)).await;
             }
+        }
+    }
 
-            if step_read {
-                continue;
-            }
+    struct TestClient {
+        stream: TcpStream,
+        parser: FrameParser,
+    }
 
-            if bytes_read == 0 {
-                break;
+    impl TestClient {
+        /// Connects once the proxy listens
+        async fn connect(port: u16) -> TestClient {
+            for _ in 0..100 {
+                if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                    let parser = FrameParser::default();
+                    return TestClient { stream, parser };
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
+            panic!("proxy is not listening on port {port}");
+        }
 
-            write.write_all(&buf[0..bytes_read]).await?;
-            copied += bytes_read;
+        async fn send(&mut self, data: &[u8]) {
+            self.stream.write_all(data).await.unwrap();
         }
-    } else {
-        println!("[PROXY] gdb stub connection established");
-        loop {
-            let bytes_read;
-            tokio::select! {
-                biased;
-
-                result = read.read(&mut buf) => {
-                    use std::io::ErrorKind::{ConnectionReset, ConnectionAborted};
-                    bytes_read = result.or_else(|e| match e.kind() {
-                        ConnectionReset | ConnectionAborted => Ok(copied),
-                        _ => Err(e)
-                    })?;
-                },
-                _ = kill_signal.recv() =>{
-                    break;
-                }
-            }
 
-            if bytes_read == 0 {
-                break;
+        /// Returns the next packet, acknowledgements are skipped
+        async fn reply(&mut self) -> Vec<u8> {
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = self.stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "proxy closed the connection");
+                if let Some(Frame::Packet(payload)) = self
+                    .parser
+                    .feed(&buf[..n])
+                    .into_iter()
+                    .find(|f| matches!(f, Frame::Packet(_)))
+                {
+                    return payload;
+                }
             }
+        }
 
-            write.write_all(&buf[0..bytes_read]).await?;
-            copied += bytes_read;
+        async fn request(&mut self, payload: &[u8]) -> Vec<u8> {
+            self.send(&encode_packet(payload)).await;
+            self.reply().await
         }
     }
 
-    Ok(copied)
+    async fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn multiplexes_driver_and_observers() {
+        let stub_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stub_port = stub_listener.local_addr().unwrap().port();
+        tokio::spawn(stub(stub_listener));
+
+        let port = free_port().await;
+        let (commands, command_recv) = mpsc::channel(8);
+        let status = broadcast::channel(8).0;
+        let address = String::from("127.0.0.1");
+        tokio::spawn(async move {
+            let _ = run(address, port, command_recv, status).await.is_ok();
+        });
+        commands
+            .send(ProxyCmd::Session(Some(stub_port)))
+            .await
+            .unwrap();
+
+        let mut driver = TestClient::connect(port).await;
+        assert_eq!(driver.request(b"g").await, b"00112233");
+        let mut observer = TestClient::connect(port).await;
+        assert_eq!(observer.request(b"g").await, b"00112233");
+
+        // observers may only read and keep their thread selection local
+        assert_eq!(observer.request(b"c").await, ERR_READ_ONLY);
+        assert_eq!(observer.request(b"Hg0").await, b"OK");
+        assert_eq!(observer.request(b"Hg1").await, ERR_READ_ONLY);
+        assert_eq!(driver.request(b"Hg1").await, b"OK");
+
+        // while the driver runs the VP, observers cannot read it
+        driver.send(&encode_packet(b"vCont;c")).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(observer.request(b"m0,4").await, ERR_RUNNING);
+        driver.send(&[0x03]).await;
+        assert_eq!(driver.reply().await, b"S02");
+
+        // requests of the server are serialized with the ones of the clients
+        let (reply, reply_recv) = oneshot::channel();
+        let request = ProxyCmd::Request(b"g".to_vec(), reply);
+        commands.send(request).await.unwrap();
+        assert_eq!(reply_recv.await.unwrap(), b"00112233");
+
+        // the observer becomes driver once the driver is gone
+        drop(driver);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(observer.request(b"Hg1").await, b"OK");
+    }
+
+    #[tokio::test]
+    async fn answers_without_stub() {
+        let port = free_port().await;
+        let (commands, command_recv) = mpsc::channel(8);
+        let status = broadcast::channel(8).0;
+        let address = String::from("127.0.0.1");
+        tokio::spawn(async move {
+            let _ = run(address, port, command_recv, status).await.is_ok();
+        });
+        commands.send(ProxyCmd::Session(None)).await.unwrap();
+
+        let mut client = TestClient::connect(port).await;
+        assert_eq!(client.request(b"g").await, ERR_NO_STUB);
+        let (reply, reply_recv) = oneshot::channel();
+        commands
+            .send(ProxyCmd::Request(b"g".to_vec(), reply))
+            .await
+            .unwrap();
+        assert!(reply_recv.await.is_err());
+    }
 }
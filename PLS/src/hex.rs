@@ -0,0 +1,32 @@
+//! Hex numbers of traces, layouts and client commands
+
+/// Parses a hex number of at most 64 bits with an optional `0x` prefix,
+/// surrounding whitespace is ignored
+pub fn parse(text: &str) -> Result<u64, String> {
+    let trimmed = text.trim();
+    let digits = trimmed
+        .strip_prefix("0x")
+        .or(trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+    // from_str_radix would accept a sign
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("{text} is no hex number"));
+    }
+    u64::from_str_radix(digits, 16).map_err(|_| format!("{text} is wider than 64 bits"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_numbers() {
+        assert_eq!(parse("1f"), Ok(0x1f));
+        assert_eq!(parse(" 0x00FF\n"), Ok(0xff));
+        assert_eq!(parse("0Xffffffffffffffff"), Ok(u64::MAX));
+        assert!(parse("1ffffffffffffffff").unwrap_err().contains("wider"));
+        for invalid in ["", "0x", "+1", "-1", "0x0x1", "1g"] {
+            assert!(parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
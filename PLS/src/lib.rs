@@ -0,0 +1,29 @@
+//! Server of ProtoLens, the binary and the benches share these modules
+
+pub mod assertions;
+pub mod binary_trace;
+pub mod check;
+pub mod cli;
+pub mod client_handler;
+pub mod command;
+pub mod cursor;
+pub mod diff;
+pub mod export;
+pub mod filter;
+pub mod gdb_proxy;
+pub mod hex;
+pub mod options;
+pub mod query;
+pub mod ranges;
+pub mod register_map;
+pub mod scripting;
+pub mod session;
+pub mod shadow;
+pub mod sink;
+pub mod source;
+pub mod stats;
+pub mod stepper;
+pub mod store;
+pub mod trace_format;
+pub mod transaction;
+pub mod virtual_prototype;
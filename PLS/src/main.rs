@@ -1,24 +1,39 @@
 use futures::lock::Mutex;
+use std::io::{self, BufWriter, Write};
 use std::net::Ipv4Addr;
-use std::{fs, path::PathBuf, sync::Arc};
+use std::sync::atomic::AtomicBool;
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
 use tokio::signal::unix::SignalKind;
 use tokio::sync::broadcast::{self, Sender};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
 use warp::{ws::WebSocket, Filter, Rejection, Reply};
 
-use client_handler::{Gdb, State};
-use gdb_proxy::GdbStatus;
-use options::{Options, Project, ProjectTranfer};
-use virtual_prototype::{VPCtrlMsg, VP};
-
-pub mod client_handler;
-pub mod command;
-pub mod gdb_proxy;
-pub mod options;
-pub mod transaction;
-pub mod virtual_prototype;
+use pls::assertions::RuleSet;
+use pls::cli;
+use pls::client_handler::{self, Gdb, State};
+use pls::export::{self, ChannelWriter, ExportFormat};
+use pls::gdb_proxy::{self, GdbStatus, ProxyCmd};
+use pls::options::{self, Options};
+use pls::register_map::RegisterMaps;
+use pls::scripting::Scripts;
+use pls::virtual_prototype::{VPCtrlMsg, VP};
+
+/// Size of the chunks of a streamed download
+const EXPORT_CHUNK_SIZE: usize = 256 * 1024;
+/// Chunks of a download buffered until the client receives them
+const EXPORT_CHUNKS: usize = 8;
 
 #[tokio::main]
 async fn main() {
+    // subcommands work on recorded sessions and do not start the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args).await);
+    }
+
     let options = Arc::new(load_options(PathBuf::from("./appsettings.json")));
     let address: Ipv4Addr = options
         .serv_opt
@@ -41,29 +56,57 @@ async fn main() {
 
     let gdb_channels = start_gdbproxy(
         options.serv_opt.address.clone(),
-        options.vp_opt.vp_debug_port,
         options.gdb_opt.gdbproxy_port,
     );
 
+    let reg_maps = match &options.reg_map_dir {
+        Some(dir) => RegisterMaps::load(dir),
+        None => RegisterMaps::default(),
+    };
+    let rules = match &options.rules_file {
+        Some(path) => RuleSet::load(path).unwrap_or_else(|e| {
+            println!("[MAIN] {e}");
+            RuleSet::default()
+        }),
+        None => RuleSet::default(),
+    };
+    let scripts = match &options.scripts_dir {
+        Some(dir) => Scripts::load(dir).unwrap_or_else(|e| {
+            println!("[MAIN] {e}");
+            Scripts::default()
+        }),
+        None => Scripts::default(),
+    };
+
     let state = Arc::new(State {
         gdb: Gdb {
             connection_status: Arc::new(Mutex::new(GdbStatus::NotConnected)),
             proxy_receiver: gdb_channels.1,
             proxy_sender: gdb_channels.0,
+            step_lock: Arc::new(Mutex::new(())),
+            step_cancel: Arc::new(AtomicBool::new(false)),
         },
         options: options.clone(),
         vp: Arc::new(vp),
         pr: Arc::new(pr),
         vp_channel: Arc::new(vp_channel),
+        reg_maps: Arc::new(reg_maps),
+        rules: Arc::new(rules),
+        scripts: Arc::new(scripts),
     });
 
-    // setup websocket and static file routes
+    // setup websocket, export and static file routes
+    let export_state = state.clone();
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and_then(move |ws| ws_upgrade(ws, state.clone()))
         .with(warp::cors().allow_any_origin());
+    let export_route = warp::path!("export" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |format, query| download(format, query, export_state.clone()));
     let file_route = warp::fs::dir(options.serv_opt.static_dir.clone());
-    let routes = ws_route.or(file_route);
+    let routes = ws_route.or(export_route).or(file_route);
 
     let (_, local_server) = warp::serve(routes).bind_with_graceful_shutdown(
         (address, options.serv_opt.port),
@@ -92,16 +135,14 @@ async fn main() {
 
 fn start_gdbproxy(
     address: String,
-    vp_port: u16,
     gdbproxy_port: u16,
-) -> (Sender<u32>, Sender<GdbStatus>) {
-    let (gdb_cmd, _) = broadcast::channel::<u32>(32);
+) -> (mpsc::Sender<ProxyCmd>, Sender<GdbStatus>) {
+    let (gdb_cmd, gdb_cmd_recv) = mpsc::channel::<ProxyCmd>(32);
     let (gdb_status, _) = broadcast::channel::<GdbStatus>(32);
-    let gdb_cmd_cl = gdb_cmd.clone();
     let gdb_status_cl = gdb_status.clone();
 
     tokio::task::spawn(async move {
-        match gdb_proxy::run(address, vp_port, gdbproxy_port, gdb_cmd_cl, gdb_status_cl).await {
+        match gdb_proxy::run(address, gdbproxy_port, gdb_cmd_recv, gdb_status_cl).await {
             Ok(()) => println!("[PROXY] exited normally"),
             Err(e) => println!("[PROXY] exited with {e}"),
         }
@@ -121,6 +162,50 @@ async fn handle_ws_client(ws: WebSocket, state: Arc<State>) {
     println!("[MAIN] client disconnect");
 }
 
+/// Exports the current session for download, e.g. /export/csv?filter={"modules":["uart0"]}
+async fn download(
+    format: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Rejection> {
+    let Some(format) = ExportFormat::from_name(&format) else {
+        return Err(warp::reject::not_found());
+    };
+
+    let snapshot = match query.get("filter").map(|f| serde_json::from_str(f)) {
+        Some(Err(e)) => Err(format!("could not parse FilterCommand ({e})")),
+        filter => client_handler::export_session(&state, filter.and_then(Result::ok)).await,
+    };
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => return Ok(warp::reply::with_status(e, StatusCode::BAD_REQUEST).into_response()),
+    };
+
+    // the export is streamed, an error aborts the body
+    let (sender, receiver) = mpsc::channel(EXPORT_CHUNKS);
+    tokio::spawn(async move {
+        let errors = sender.clone();
+        let result = snapshot
+            .export(move |selection| {
+                let mut out = BufWriter::with_capacity(EXPORT_CHUNK_SIZE, ChannelWriter(sender));
+                export::export(format, selection, &mut out)
+                    .and_then(|_| out.flush())
+                    .map_err(|e| e.to_string())
+            })
+            .await;
+        if let Err(e) = result {
+            println!("[MAIN] export failed ({e})");
+            let _ = errors.send(Err(io::Error::other(e))).await;
+        }
+    });
+
+    let body = Body::wrap_stream(ReceiverStream::new(receiver));
+    let disposition = format!("attachment; filename=\"trace.{}\"", format.extension());
+    let reply =
+        warp::reply::with_header(Response::new(body), "Content-Type", format.content_type());
+    Ok(warp::reply::with_header(reply, "Content-Disposition", disposition).into_response())
+}
+
 fn load_options(path: PathBuf) -> Options {
     let fcont = fs::read_to_string(&path).expect("[MAIN] could not read file");
     println!(
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc};
 
 use crate::client_handler::State;
 use crate::command::StartCommand;
+use crate::source::TraceSource;
+use crate::trace_format::TraceFormat;
 use crate::virtual_prototype::VPMode;
 
 #[derive(Deserialize, Debug)]
@@ -14,6 +17,58 @@ pub struct Options {
     pub vp_dir: PathBuf,
     pub gui_vp_kit_dir: String,
     pub gui_vp_args: String,
+    /// directory with register descriptions (SVD or YAML) of the VP modules
+    #[serde(default)]
+    pub reg_map_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub store_opt: StoreOptions,
+    /// directory exported traces are written to
+    #[serde(default = "default_export_dir")]
+    pub export_dir: PathBuf,
+    /// YAML file with bus protocol assertions checked on the live trace
+    #[serde(default)]
+    pub rules_file: Option<PathBuf>,
+    /// directory with Rhai scripts hooked into the live trace
+    #[serde(default)]
+    pub scripts_dir: Option<PathBuf>,
+    /// directory each VP session is recorded to as NDJSON file
+    #[serde(default)]
+    pub record_dir: Option<PathBuf>,
+    /// directories the paths of trace sources sent by clients must lie in,
+    /// without them clients can only use TCP sources
+    #[serde(default)]
+    pub source_dirs: Vec<PathBuf>,
+    /// trace line formats of VPs which differ from the RISC-V VP++, by VP name
+    #[serde(default)]
+    pub trace_formats: HashMap<String, TraceFormat>,
+}
+
+impl Options {
+    /// Trace line format of a VP, the RISC-V VP++ format if none is configured
+    pub fn trace_format(&self, vp: &str) -> TraceFormat {
+        self.trace_formats.get(vp).cloned().unwrap_or_default()
+    }
+}
+
+fn default_export_dir() -> PathBuf {
+    PathBuf::from("./exports")
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct StoreOptions {
+    /// number of transactions kept in memory before older ones are spilled to disk
+    pub memory_cap: usize,
+    pub spill_dir: PathBuf,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            memory_cap: 1_000_000,
+            spill_dir: std::env::temp_dir(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -55,7 +110,10 @@ pub struct StartOptions {
     pub binary: String,
     pub args: Vec<String>,
     pub arch: Option<String>,
+    pub debug_port: Option<u16>,
     pub mode: VPMode,
+    pub source: TraceSource,
+    pub format: TraceFormat,
 }
 
 impl From<Arc<(Vec<Project>, Vec<PathBuf>)>> for ProjectTranfer {
@@ -90,6 +148,13 @@ fn get_vp(name: &str, vps: &Vec<PathBuf>) -> Result<String, bool> {
     Err(false)
 }
 
+/// Returns the value following `name` in a whitespace separated argument string
+fn get_arg_value<'a>(args: &'a str, name: &str) -> Option<&'a str> {
+    let mut iter = args.split_ascii_whitespace();
+    iter.find(|arg| *arg == name)?;
+    iter.next()
+}
+
 fn get_binary(proj_name: &str, projects: &Vec<Project>) -> Result<String, bool> {
     for p in projects {
         if p.directory.ends_with(proj_name) {
@@ -215,6 +280,7 @@ pub fn get_vp_args(mut start_cmd: StartCommand, state: Arc<State>) -> Option<Sta
 
     // Check if debug architecture was given (only needed in debug mode)
     let mut gdb_arch: Option<String> = None;
+    let mut debug_port: Option<u16> = None;
     let mut mode = VPMode::Stream;
     if start_cmd.args.contains("--debug-mode") {
         if start_cmd.gdb_arch != "rv32" && start_cmd.gdb_arch != "rv64" {
@@ -223,17 +289,38 @@ pub fn get_vp_args(mut start_cmd: StartCommand, state: Arc<State>) -> Option<Sta
         }
         gdb_arch = Some(start_cmd.gdb_arch);
         mode = VPMode::Step;
-        let debug_port = format!(" --debug-port {}", state.options.vp_opt.vp_debug_port);
-        start_cmd.args.push_str(&debug_port);
+        // a debug port given by the user takes precedence over the configured one
+        if let Some(port) = get_arg_value(&start_cmd.args, "--debug-port") {
+            let Ok(port) = port.parse::<u16>() else {
+                println!("[CH] Cannot parse debug port {port}");
+                return None;
+            };
+            debug_port = Some(port);
+        } else {
+            let port = state.options.vp_opt.vp_debug_port;
+            start_cmd.args.push_str(&format!(" --debug-port {port}"));
+            debug_port = Some(port);
+        }
     }
 
-    if start_cmd.args.contains("--debug-bus-mode") {
-        let debug_bus_port = format!(" --debug-bus-port {}", state.options.vp_opt.vp_trace_port);
-        start_cmd.args.push_str(&debug_bus_port);
+    // other VPs provide their trace through a source given by the user
+    let source = if let Some(source) = start_cmd.source.take() {
+        if let Err(e) = source.check_dirs(&state.options.source_dirs) {
+            println!("[CH] {e}");
+            return None;
+        }
+        source
+    } else if start_cmd.args.contains("--debug-bus-mode") {
+        let port = state.options.vp_opt.vp_trace_port;
+        start_cmd
+            .args
+            .push_str(&format!(" --debug-bus-port {port}"));
+        TraceSource::local_port(port)
     } else {
-        println!("[CH] Cannot start VP without --debug-bus-mode");
+        println!("[CH] Cannot start VP without --debug-bus-mode or trace source");
         return None;
-    }
+    };
+    let format = state.options.trace_format(&start_cmd.vp);
 
     let arg_list = start_cmd
         .args
@@ -246,6 +333,9 @@ pub fn get_vp_args(mut start_cmd: StartCommand, state: Arc<State>) -> Option<Sta
         binary: bin_res.unwrap(),
         args: arg_list,
         arch: gdb_arch,
+        debug_port,
         mode,
+        source,
+        format,
     })
 }
@@ -0,0 +1,582 @@
+use std::fmt;
+
+use crate::shadow::ShadowMemory;
+use crate::transaction::{Transaction, TransactionCmd};
+
+/// Nesting of parentheses and negations a query may contain
+const MAX_DEPTH: usize = 32;
+
+/// Transaction properties which can be used in a query
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    /// name of the target module
+    Target,
+    Initiator,
+    Action,
+    Address,
+    Data,
+    Length,
+    /// simulation time in ns
+    Time,
+    /// position of the transaction in the session
+    Index,
+    /// value of the shadow memory at an address with a length in bytes
+    Memory(u64, u8),
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "target" | "module" => Some(Field::Target),
+            "initiator" => Some(Field::Initiator),
+            "action" => Some(Field::Action),
+            "addr" | "address" => Some(Field::Address),
+            "data" => Some(Field::Data),
+            "len" | "length" => Some(Field::Length),
+            "time" => Some(Field::Time),
+            "index" => Some(Field::Index),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Field::Address
+                | Field::Data
+                | Field::Length
+                | Field::Time
+                | Field::Index
+                | Field::Memory(..)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn eval<T: PartialOrd>(&self, lhs: T, rhs: T) -> bool {
+        match self {
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(u64),
+    Text(String),
+    Action(TransactionCmd),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// operands of a chain of `&&`
+    And(Vec<Expr>),
+    /// operands of a chain of `||`
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, CmpOp, Value),
+    /// inclusive start, exclusive end
+    InRange(Field, u64, u64),
+    /// the address lies in the range of the target module
+    Mapped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Text(String),
+    Number(u64, Option<String>),
+    /// number with a fraction, only used for times
+    Decimal(String, Option<String>),
+    Cmp(CmpOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    DotDot,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "{s}"),
+            Token::Text(s) => write!(f, "\"{s}\""),
+            Token::Number(n, unit) => write!(f, "{n}{}", unit.as_deref().unwrap_or("")),
+            Token::Decimal(n, unit) => write!(f, "{n}{}", unit.as_deref().unwrap_or("")),
+            Token::Cmp(op) => write!(f, "{op:?}"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Not => write!(f, "!"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::DotDot => write!(f, ".."),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        let next = chars.get(pos + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Cmp(CmpOp::Eq), 2),
+            ('!', Some('=')) => (Token::Cmp(CmpOp::Ne), 2),
+            ('<', Some('=')) => (Token::Cmp(CmpOp::Le), 2),
+            ('>', Some('=')) => (Token::Cmp(CmpOp::Ge), 2),
+            ('.', Some('.')) => (Token::DotDot, 2),
+            ('<', _) => (Token::Cmp(CmpOp::Lt), 1),
+            ('>', _) => (Token::Cmp(CmpOp::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            (',', _) => (Token::Comma, 1),
+            ('"', _) => {
+                let Some(end) = chars[pos + 1..].iter().position(|c| *c == '"') else {
+                    return Err(String::from("unterminated string"));
+                };
+                let text: String = chars[pos + 1..pos + 1 + end].iter().collect();
+                (Token::Text(text), end + 2)
+            }
+            (c, _) if c.is_ascii_digit() => {
+                let hex = c == '0' && matches!(next, Some('x' | 'X'));
+                let start = if hex { pos + 2 } else { pos };
+                let mut end = start;
+                while end < chars.len()
+                    && (chars[end].is_ascii_hexdigit() && hex || chars[end].is_ascii_digit())
+                {
+                    end += 1;
+                }
+                let digits: String = chars[start..end].iter().collect();
+                let value = u64::from_str_radix(&digits, if hex { 16 } else { 10 })
+                    .map_err(|_| format!("could not parse number {digits}"))?;
+
+                // a fraction, but not the `..` of a range
+                let mut fraction_end = end;
+                if !hex
+                    && chars.get(end) == Some(&'.')
+                    && chars.get(end + 1).is_some_and(char::is_ascii_digit)
+                {
+                    fraction_end = end + 1;
+                    while fraction_end < chars.len() && chars[fraction_end].is_ascii_digit() {
+                        fraction_end += 1;
+                    }
+                }
+
+                let mut unit_end = fraction_end;
+                while unit_end < chars.len() && chars[unit_end].is_ascii_alphabetic() {
+                    unit_end += 1;
+                }
+                let unit: String = chars[fraction_end..unit_end].iter().collect();
+                let unit = (!unit.is_empty()).then_some(unit);
+                let token = if fraction_end > end {
+                    Token::Decimal(chars[start..fraction_end].iter().collect(), unit)
+                } else {
+                    Token::Number(value, unit)
+                };
+                (token, unit_end - pos)
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let mut end = pos;
+                while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+                    end += 1;
+                }
+                (Token::Ident(chars[pos..end].iter().collect()), end - pos)
+            }
+            (c, _) => return Err(format!("unexpected character '{c}'")),
+        };
+        tokens.push(token);
+        pos += len;
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a time with optional fraction and unit (e.g. 3.2ms) to ns
+pub fn parse_time(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format!("could not parse time {text}"))?;
+    let factor = match unit.trim() {
+        "" | "ns" => 1.0,
+        "ps" => 1e-3,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "s" => 1e9,
+        unit => return Err(format!("unknown time unit {unit}")),
+    };
+    Ok((value * factor).round() as u64)
+}
+
+/// Converts a time with unit to ns, the resolution of the simulation time
+fn scale_time(value: u64, unit: Option<&str>) -> Result<u64, String> {
+    let factor = match unit {
+        None | Some("ns") => return Ok(value),
+        Some("ps") => return Ok(value / 1000),
+        Some("us") => 1_000,
+        Some("ms") => 1_000_000,
+        Some("s") => 1_000_000_000,
+        Some(unit) => return Err(format!("unknown time unit {unit}")),
+    };
+    value
+        .checked_mul(factor)
+        .ok_or_else(|| String::from("time value is too large"))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// current nesting of parentheses and negations
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| String::from("unexpected end of query"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("expected {expected} but found {token}"));
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut operands = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            operands.push(self.and()?);
+        }
+        Ok(match operands.len() {
+            1 => operands.remove(0),
+            _ => Expr::Or(operands),
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut operands = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            operands.push(self.unary()?);
+        }
+        Ok(match operands.len() {
+            1 => operands.remove(0),
+            _ => Expr::And(operands),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if !matches!(self.peek(), Some(Token::Not | Token::LParen)) {
+            return self.comparison();
+        }
+        // bounds the recursion of the parser and of the evaluation
+        if self.depth == MAX_DEPTH {
+            return Err(format!("query is nested deeper than {MAX_DEPTH} levels"));
+        }
+        self.depth += 1;
+        let expr = match self.next()? {
+            Token::Not => Expr::Not(Box::new(self.unary()?)),
+            _ => {
+                let expr = self.or()?;
+                self.expect(Token::RParen)?;
+                expr
+            }
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn number(&mut self, field: Field) -> Result<u64, String> {
+        match self.next()? {
+            Token::Number(value, unit) if field == Field::Time => {
+                scale_time(value, unit.as_deref())
+            }
+            Token::Decimal(value, unit) if field == Field::Time => {
+                parse_time(&format!("{value}{}", unit.as_deref().unwrap_or("")))
+            }
+            Token::Number(value, None) => Ok(value),
+            token => Err(format!("expected number for {field:?} but found {token}")),
+        }
+    }
+
+    /// Parses the arguments of `mem(address[, length])`, the length defaults to 4 bytes
+    fn memory(&mut self) -> Result<Field, String> {
+        self.expect(Token::LParen)?;
+        let address = self.number(Field::Address)?;
+        let mut length = 4;
+        if self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            length = self.number(Field::Length)?;
+        }
+        self.expect(Token::RParen)?;
+        match u8::try_from(length) {
+            Ok(length @ 1..=8) => Ok(Field::Memory(address, length)),
+            _ => Err(format!("memory length {length} is not in 1..=8")),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let field = match self.next()? {
+            Token::Ident(name) if name == "mapped" => return Ok(Expr::Mapped),
+            Token::Ident(name) if name == "mem" => self.memory()?,
+            Token::Ident(name) => {
+                Field::from_name(&name).ok_or_else(|| format!("unknown field {name}"))?
+            }
+            token => return Err(format!("expected field but found {token}")),
+        };
+
+        let op = match self.next()? {
+            Token::Cmp(op) => op,
+            Token::Ident(kw) if kw == "in" && field.is_numeric() => {
+                let start = self.number(field)?;
+                self.expect(Token::DotDot)?;
+                let end = self.number(field)?;
+                return Ok(Expr::InRange(field, start, end));
+            }
+            token => return Err(format!("expected comparison but found {token}")),
+        };
+
+        let value = match field {
+            f if f.is_numeric() => Value::Number(self.number(f)?),
+            Field::Action => match self.next()? {
+                Token::Ident(a) | Token::Text(a) if matches!(a.as_str(), "R" | "Read") => {
+                    Value::Action(TransactionCmd::Read)
+                }
+                Token::Ident(a) | Token::Text(a) if matches!(a.as_str(), "W" | "Write") => {
+                    Value::Action(TransactionCmd::Write)
+                }
+                token => return Err(format!("expected R or W but found {token}")),
+            },
+            _ => match self.next()? {
+                Token::Text(text) | Token::Ident(text) => Value::Text(text),
+                token => return Err(format!("expected string but found {token}")),
+            },
+        };
+
+        if !field.is_numeric() && !matches!(op, CmpOp::Eq | CmpOp::Ne) {
+            return Err(format!("{field:?} can only be compared with == and !="));
+        }
+
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+/// Session state beyond the transaction which a query may refer to
+#[derive(Default)]
+pub struct Context<'a> {
+    pub modules: &'a [String],
+    /// inclusive address range of each module, used by `mapped`
+    pub ranges: &'a [(u64, u64)],
+    /// memory contents for `mem()`, queries on the history have none
+    pub shadow: Option<&'a ShadowMemory>,
+}
+
+/// A parsed query, e.g. `target == "plic" && action == W && time > 1ms`
+#[derive(Debug, Clone)]
+pub struct Query {
+    expr: Expr,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, String> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {token} after query"));
+        }
+        Ok(Query { expr })
+    }
+
+    /// Evaluates the query for the transaction at `index` of a session
+    pub fn matches(&self, transaction: &Transaction, index: usize, modules: &[String]) -> bool {
+        let context = Context {
+            modules,
+            ..Default::default()
+        };
+        eval(&self.expr, transaction, index, &context)
+    }
+
+    /// Evaluates the query with the layout ranges and shadow memory of a session
+    pub fn matches_in(&self, transaction: &Transaction, index: usize, context: &Context) -> bool {
+        eval(&self.expr, transaction, index, context)
+    }
+}
+
+fn number(field: Field, transaction: &Transaction, index: usize, context: &Context) -> Option<u64> {
+    match field {
+        Field::Address => u64::from_str_radix(&transaction.address, 16).ok(),
+        Field::Data => u64::from_str_radix(&transaction.data, 16).ok(),
+        Field::Length => Some(transaction.data_length as u64),
+        Field::Time => Some(transaction.sim_time),
+        Field::Index => Some(index as u64),
+        Field::Memory(address, length) => context.shadow?.value(address, length),
+        _ => None,
+    }
+}
+
+fn eval(expr: &Expr, transaction: &Transaction, index: usize, context: &Context) -> bool {
+    match expr {
+        Expr::And(operands) => operands
+            .iter()
+            .all(|expr| eval(expr, transaction, index, context)),
+        Expr::Or(operands) => operands
+            .iter()
+            .any(|expr| eval(expr, transaction, index, context)),
+        Expr::Not(inner) => !eval(inner, transaction, index, context),
+        Expr::InRange(field, start, end) => {
+            number(*field, transaction, index, context).is_some_and(|n| (*start..*end).contains(&n))
+        }
+        Expr::Mapped => {
+            let address = u64::from_str_radix(&transaction.address, 16);
+            match (context.ranges.get(transaction.target as usize), address) {
+                (Some((start, end)), Ok(address)) => (*start..=*end).contains(&address),
+                _ => false,
+            }
+        }
+        Expr::Compare(field, op, value) => match (field, value) {
+            // actions can only be compared for (in)equality
+            (Field::Action, Value::Action(action)) => {
+                (transaction.action == *action) == (*op == CmpOp::Eq)
+            }
+            (Field::Target, Value::Text(name)) => context
+                .modules
+                .get(transaction.target as usize)
+                .is_some_and(|m| op.eval(m.as_str(), name.as_str())),
+            (Field::Initiator, Value::Text(name)) => {
+                op.eval(transaction.initiator.as_str(), name.as_str())
+            }
+            (field, Value::Number(rhs)) => {
+                number(*field, transaction, index, context).is_some_and(|n| op.eval(n, *rhs))
+            }
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(line: &str) -> Transaction {
+        line.parse().unwrap()
+    }
+
+    #[test]
+    fn tokenizes_ranges_and_decimals() {
+        let tokens = tokenize("addr in 0x10..0x20 && time > 1.5ms").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident(String::from("addr")),
+                Token::Ident(String::from("in")),
+                Token::Number(0x10, None),
+                Token::DotDot,
+                Token::Number(0x20, None),
+                Token::And,
+                Token::Ident(String::from("time")),
+                Token::Cmp(CmpOp::Gt),
+                Token::Decimal(String::from("1.5"), Some(String::from("ms"))),
+            ]
+        );
+        assert!(tokenize("target == \"uart0").is_err());
+    }
+
+    #[test]
+    fn parses_chains_as_one_expression() {
+        let query = Query::parse("len == 1 && len == 2 && len == 4 || mapped").unwrap();
+        let Expr::Or(operands) = query.expr else {
+            panic!("expected Or");
+        };
+        assert!(matches!(&operands[0], Expr::And(operands) if operands.len() == 3));
+        assert_eq!(operands[1], Expr::Mapped);
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| format!("{}len == 4{}", "!(".repeat(depth), ")".repeat(depth));
+        assert!(Query::parse(&nested(MAX_DEPTH / 2)).is_ok());
+        assert!(Query::parse(&nested(MAX_DEPTH / 2 + 1)).is_err());
+    }
+
+    #[test]
+    fn accepts_decimals_only_for_times() {
+        let query = Query::parse("time == 1.5us").unwrap();
+        assert_eq!(
+            query.expr,
+            Expr::Compare(Field::Time, CmpOp::Eq, Value::Number(1500))
+        );
+        assert_eq!(parse_time("2.25 ms"), Ok(2_250_000));
+        assert!(Query::parse("len == 1.5").is_err());
+        assert!(Query::parse("time == 2ks").is_err());
+    }
+
+    #[test]
+    fn evaluates_transactions() {
+        let modules = [String::from("ram"), String::from("uart0")];
+        let t = transaction("W;core0;1;10013004;1500000;4;41");
+        let matches = |query: &str| Query::parse(query).unwrap().matches(&t, 7, &modules);
+        assert!(matches(
+            "target == \"uart0\" && action == W && time > 1ms && addr in 0x10013000..0x10014000"
+        ));
+        assert!(matches("index == 7 && initiator != \"core1\""));
+        assert!(!matches("!(data == 0x41)"));
+        assert!(!matches("action == R || len < 4"));
+
+        let context = Context {
+            modules: &modules,
+            ranges: &[(0, 0xffff), (0x10013000, 0x10013fff)],
+            shadow: None,
+        };
+        let mapped = Query::parse("mapped").unwrap();
+        assert!(mapped.matches_in(&t, 7, &context));
+        assert!(!mapped.matches_in(&transaction("W;core0;0;10013004;0;4;41"), 7, &context));
+    }
+}
@@ -0,0 +1,284 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
+
+use crate::hex;
+use crate::sink::{TraceState, TransactionSink};
+use crate::transaction::Transaction;
+use crate::virtual_prototype::{VPCtrlMsg, VPLayout};
+
+/// Number of access warnings reported per target module, later ones are only counted
+const MAX_WARNINGS: usize = 100;
+
+/// Parses the inclusive address range of a module, None if it is invalid
+pub fn parse_range(start: &str, end: &str) -> Option<(u64, u64)> {
+    let (start, end) = (hex::parse(start).ok()?, hex::parse(end).ok()?);
+    (start <= end).then_some((start, end))
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind")]
+pub enum LayoutWarning {
+    /// the range of a module cannot be parsed or ends before it starts
+    InvalidRange {
+        module: String,
+        start: String,
+        end: String,
+    },
+    /// the ranges of two modules share the addresses from start to end
+    Overlap {
+        module: String,
+        other: String,
+        start: String,
+        end: String,
+    },
+    /// the address lies in no module range
+    OutOfRange {
+        index: usize,
+        transaction: String,
+        module: String,
+        /// number of warnings for the target module so far
+        count: usize,
+    },
+    /// the address lies in the range of another module than the target
+    Misrouted {
+        index: usize,
+        transaction: String,
+        module: String,
+        owner: String,
+        count: usize,
+    },
+}
+
+/// Validates the layout and the addresses of all transactions against the
+/// ranges of their target modules
+pub struct RangeChecker {
+    modules: Vec<String>,
+    ranges: Vec<Option<(u64, u64)>>,
+    counts: HashMap<u8, usize>,
+    channel: Arc<Sender<VPCtrlMsg>>,
+}
+
+impl RangeChecker {
+    pub fn new(channel: Arc<Sender<VPCtrlMsg>>) -> RangeChecker {
+        RangeChecker {
+            modules: Vec::new(),
+            ranges: Vec::new(),
+            counts: HashMap::new(),
+            channel,
+        }
+    }
+
+    fn warn(&self, warning: LayoutWarning) {
+        println!("[RANGE] {warning:?}");
+        let _ = self.channel.send(VPCtrlMsg::Warning(warning));
+    }
+
+    /// Reports all pairs of modules with overlapping ranges
+    fn check_overlaps(&self) {
+        for (i, a) in self.ranges.iter().enumerate() {
+            for (j, b) in self.ranges.iter().enumerate().skip(i + 1) {
+                let (Some(a), Some(b)) = (a, b) else {
+                    continue;
+                };
+                let (start, end) = (a.0.max(b.0), a.1.min(b.1));
+                if start <= end {
+                    self.warn(LayoutWarning::Overlap {
+                        module: self.modules[i].clone(),
+                        other: self.modules[j].clone(),
+                        start: format!("{start:x}"),
+                        end: format!("{end:x}"),
+                    });
+                }
+            }
+        }
+    }
+
+    fn owner(&self, address: u64) -> Option<&str> {
+        self.ranges
+            .iter()
+            .position(|r| r.is_some_and(|(start, end)| (start..=end).contains(&address)))
+            .map(|m| self.modules[m].as_str())
+    }
+
+    /// Checks that the address of a transaction lies in the range of its target
+    fn check(&mut self, transaction: &Transaction, index: usize) {
+        let Ok(address) = hex::parse(&transaction.address) else {
+            return;
+        };
+        let target = transaction.target as usize;
+        if self
+            .ranges
+            .get(target)
+            .is_some_and(|r| r.is_some_and(|(start, end)| (start..=end).contains(&address)))
+        {
+            return;
+        }
+
+        let count = self.counts.entry(transaction.target).or_default();
+        *count += 1;
+        if *count > MAX_WARNINGS {
+            return;
+        }
+        let count = *count;
+        let module = self
+            .modules
+            .get(target)
+            .cloned()
+            .unwrap_or_else(|| target.to_string());
+        let transaction = transaction.to_string();
+        let warning = match self.owner(address) {
+            Some(owner) => LayoutWarning::Misrouted {
+                index,
+                transaction,
+                module,
+                owner: owner.to_owned(),
+                count,
+            },
+            None => LayoutWarning::OutOfRange {
+                index,
+                transaction,
+                module,
+                count,
+            },
+        };
+        self.warn(warning);
+    }
+}
+
+#[async_trait]
+impl TransactionSink for RangeChecker {
+    async fn on_layout(&mut self, layout: &VPLayout) {
+        self.modules.clone_from(&layout.modules);
+        self.ranges.clear();
+        for (module, (start, end)) in layout
+            .modules
+            .iter()
+            .zip(layout.start_addrs.iter().zip(layout.end_addrs.iter()))
+        {
+            let range = parse_range(start, end);
+            if range.is_none() {
+                self.warn(LayoutWarning::InvalidRange {
+                    module: module.clone(),
+                    start: start.clone(),
+                    end: end.clone(),
+                });
+            }
+            self.ranges.push(range);
+        }
+        self.check_overlaps();
+    }
+
+    async fn on_transaction(&mut self, transaction: &Transaction, state: &TraceState<'_>) {
+        self.check(transaction, state.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::{self, Receiver};
+
+    fn layout(ranges: &[(&str, &str, &str)]) -> VPLayout {
+        VPLayout {
+            modules: ranges.iter().map(|r| r.0.to_owned()).collect(),
+            start_addrs: ranges.iter().map(|r| r.1.to_owned()).collect(),
+            end_addrs: ranges.iter().map(|r| r.2.to_owned()).collect(),
+        }
+    }
+
+    fn warnings(receiver: &mut Receiver<VPCtrlMsg>) -> Vec<LayoutWarning> {
+        let mut warnings = Vec::new();
+        while let Ok(VPCtrlMsg::Warning(warning)) = receiver.try_recv() {
+            warnings.push(warning);
+        }
+        warnings
+    }
+
+    #[tokio::test]
+    async fn validates_the_layout() {
+        let (channel, mut receiver) = broadcast::channel(16);
+        let mut checker = RangeChecker::new(Arc::new(channel));
+        let ranges = [
+            ("rom", "0", "0x0FFF"),
+            ("ram", "800", "1fff"),
+            ("uart", "2000", "1000"),
+            ("gpio", "zz", "3000"),
+        ];
+        checker.on_layout(&layout(&ranges)).await;
+        assert_eq!(
+            warnings(&mut receiver),
+            [
+                LayoutWarning::InvalidRange {
+                    module: "uart".to_owned(),
+                    start: "2000".to_owned(),
+                    end: "1000".to_owned(),
+                },
+                LayoutWarning::InvalidRange {
+                    module: "gpio".to_owned(),
+                    start: "zz".to_owned(),
+                    end: "3000".to_owned(),
+                },
+                LayoutWarning::Overlap {
+                    module: "rom".to_owned(),
+                    other: "ram".to_owned(),
+                    start: "800".to_owned(),
+                    end: "fff".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_accesses_outside_of_the_target() {
+        let (channel, mut receiver) = broadcast::channel(4 * MAX_WARNINGS);
+        let mut checker = RangeChecker::new(Arc::new(channel));
+        checker
+            .on_layout(&layout(&[("rom", "0", "fff"), ("ram", "1000", "1fff")]))
+            .await;
+        let access = |line: &str| line.parse::<Transaction>().unwrap();
+
+        checker.check(&access("R;core0;0;ffc;0;4;1"), 0);
+        checker.check(&access("R;core0;1;1000;0;4;1"), 1);
+        checker.check(&access("R;core0;0;invalid;0;4;1"), 2);
+        assert!(warnings(&mut receiver).is_empty());
+
+        checker.check(&access("R;core0;0;1004;0;4;1"), 3);
+        checker.check(&access("W;core0;1;2000;0;4;1"), 4);
+        checker.check(&access("W;core0;7;0;0;4;1"), 5);
+        assert_eq!(
+            warnings(&mut receiver),
+            [
+                LayoutWarning::Misrouted {
+                    index: 3,
+                    transaction: "R;core0;0;1004;0;4;1".to_owned(),
+                    module: "rom".to_owned(),
+                    owner: "ram".to_owned(),
+                    count: 1,
+                },
+                LayoutWarning::OutOfRange {
+                    index: 4,
+                    transaction: "W;core0;1;2000;0;4;1".to_owned(),
+                    module: "ram".to_owned(),
+                    count: 1,
+                },
+                LayoutWarning::Misrouted {
+                    index: 5,
+                    transaction: "W;core0;7;0;0;4;1".to_owned(),
+                    module: "7".to_owned(),
+                    owner: "rom".to_owned(),
+                    count: 1,
+                },
+            ]
+        );
+
+        // later warnings of a module are only counted
+        for index in 0..2 * MAX_WARNINGS {
+            checker.check(&access("W;core0;1;2000;0;4;1"), index);
+        }
+        assert_eq!(warnings(&mut receiver).len(), MAX_WARNINGS - 1);
+        assert_eq!(checker.counts[&1], 2 * MAX_WARNINGS + 1);
+    }
+}
@@ -0,0 +1,371 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hex;
+use crate::transaction::Transaction;
+
+#[derive(Deserialize, Debug)]
+pub struct Field {
+    pub name: String,
+    pub bit_offset: u32,
+    pub bit_width: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Register {
+    pub name: String,
+    /// offset to the start address of the module in bytes
+    pub offset: u64,
+    /// register width in bits
+    #[serde(default = "default_register_size")]
+    pub size: u32,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+}
+
+fn default_register_size() -> u32 {
+    32
+}
+
+/// Schema of YAML register description files
+#[derive(Deserialize, Debug)]
+struct YamlModule {
+    registers: Vec<Register>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DecodedField {
+    pub name: String,
+    pub value: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DecodedTransaction {
+    /// index of the transaction in the session
+    pub index: usize,
+    pub register: String,
+    pub fields: Vec<DecodedField>,
+    /// human readable form, e.g. UART0.TXDATA.data='A'
+    pub text: String,
+}
+
+/// Register descriptions of all modules, keyed by lower case module name
+#[derive(Default, Debug)]
+pub struct RegisterMaps {
+    modules: HashMap<String, Vec<Register>>,
+}
+
+fn parse_svd_int(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if text.starts_with("0x") || text.starts_with("0X") {
+        hex::parse(text).ok()
+    } else if let Some(bin) = text.strip_prefix('#') {
+        u64::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, 'a>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|c| c.has_tag_name(name))
+        .and_then(|c| c.text())
+}
+
+/// Reads the bit position of a SVD field in any of the three allowed notations
+fn parse_svd_field(node: roxmltree::Node) -> Option<Field> {
+    let name = child_text(node, "name")?.to_owned();
+    let (lsb, msb) = if let Some(offset) = child_text(node, "bitOffset") {
+        let offset = parse_svd_int(offset)?;
+        let width = child_text(node, "bitWidth").map_or(Some(1), parse_svd_int)?;
+        (offset, (offset + width).checked_sub(1)?)
+    } else if let Some(range) = child_text(node, "bitRange") {
+        let (msb, lsb) = range.trim().trim_matches(['[', ']']).split_once(':')?;
+        (parse_svd_int(lsb)?, parse_svd_int(msb)?)
+    } else {
+        (
+            parse_svd_int(child_text(node, "lsb")?)?,
+            parse_svd_int(child_text(node, "msb")?)?,
+        )
+    };
+
+    Some(Field {
+        name,
+        bit_offset: lsb as u32,
+        bit_width: (msb.checked_sub(lsb)? + 1) as u32,
+    })
+}
+
+fn parse_svd(content: &str) -> Result<HashMap<String, Vec<Register>>, String> {
+    let doc = roxmltree::Document::parse(content).map_err(|e| e.to_string())?;
+    let mut modules = HashMap::new();
+
+    for peripheral in doc.descendants().filter(|n| n.has_tag_name("peripheral")) {
+        let Some(name) = child_text(peripheral, "name") else {
+            continue;
+        };
+        let mut registers = Vec::new();
+        for register in peripheral
+            .descendants()
+            .filter(|n| n.has_tag_name("register"))
+        {
+            let (Some(reg_name), Some(offset)) = (
+                child_text(register, "name"),
+                child_text(register, "addressOffset").and_then(parse_svd_int),
+            ) else {
+                continue;
+            };
+            registers.push(Register {
+                name: reg_name.to_owned(),
+                offset,
+                size: child_text(register, "size")
+                    .and_then(parse_svd_int)
+                    .map_or(default_register_size(), |s| s as u32),
+                fields: register
+                    .descendants()
+                    .filter(|n| n.has_tag_name("field"))
+                    .filter_map(parse_svd_field)
+                    .collect(),
+            });
+        }
+        modules.insert(name.to_lowercase(), registers);
+    }
+
+    Ok(modules)
+}
+
+/// Drops the fields which do not fit in the 64 bits a transaction carries
+fn retain_decodable(module: &str, registers: &mut [Register]) {
+    for register in registers.iter_mut() {
+        register.fields.retain(|field| {
+            let fits =
+                field.bit_width > 0 && field.bit_offset as u64 + field.bit_width as u64 <= 64;
+            if !fits {
+                println!(
+                    "[REG] skipping field {module}.{}.{} at bits {}+{}",
+                    register.name, field.name, field.bit_offset, field.bit_width
+                );
+            }
+            fits
+        });
+    }
+}
+
+impl RegisterMaps {
+    /// Loads all YAML (one module per file, named after the module) and
+    /// CMSIS-SVD (one module per peripheral) files of a directory
+    pub fn load(dir: &Path) -> RegisterMaps {
+        let mut maps = RegisterMaps::default();
+        let Ok(dir_entries) = fs::read_dir(dir) else {
+            println!("[REG] could not read register map dir {}", dir.display());
+            return maps;
+        };
+
+        let mut paths: Vec<PathBuf> = dir_entries.flatten().map(|e| e.path()).collect();
+        paths.sort();
+        for path in paths {
+            if let Err(e) = maps.load_file(&path) {
+                println!("[REG] could not load {} ({e})", path.display());
+            }
+        }
+
+        println!(
+            "[REG] loaded register maps of {} module(s)",
+            maps.modules.len()
+        );
+        maps
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if !matches!(ext, "yaml" | "yml" | "svd") {
+            return Ok(());
+        }
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        let modules = if ext == "svd" {
+            parse_svd(&content)?
+        } else {
+            let module: YamlModule = serde_yaml::from_str(&content).map_err(|e| e.to_string())?;
+            let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            HashMap::from([(name.to_lowercase(), module.registers)])
+        };
+        for (name, mut registers) in modules {
+            retain_decodable(&name, &mut registers);
+            self.modules.insert(name, registers);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// Decodes the register and field values accessed by a transaction
+    pub fn decode(
+        &self,
+        index: usize,
+        module: &str,
+        module_start: u64,
+        transaction: &Transaction,
+    ) -> Option<DecodedTransaction> {
+        let registers = self.modules.get(&module.to_lowercase())?;
+        let address = hex::parse(&transaction.address).ok()?;
+        let offset = address.checked_sub(module_start)?;
+        let register = registers
+            .iter()
+            .find(|r| offset >= r.offset && offset < r.offset + (r.size as u64).div_ceil(8))?;
+
+        // sub-word accesses only carry the bytes starting at the accessed offset
+        let data = hex::parse(&transaction.data).ok()?;
+        let shift = (offset - register.offset) * 8;
+        let accessed = shift..shift + transaction.data_length as u64 * 8;
+        let value = data.checked_shl(shift as u32).unwrap_or(0);
+
+        let mut fields = Vec::new();
+        let mut parts = Vec::new();
+        for field in register.fields.iter() {
+            let (lsb, width) = (field.bit_offset as u64, field.bit_width as u64);
+            // fields are non empty and within 64 bits, see retain_decodable
+            if !accessed.contains(&lsb) || !accessed.contains(&(lsb + width - 1)) {
+                continue;
+            }
+            let mask = u64::MAX >> (64 - width);
+            let field_value = (value >> lsb) & mask;
+            let shown = match char::from_u32(field_value as u32) {
+                Some(c) if width == 8 && c.is_ascii_graphic() => format!("'{c}'"),
+                _ => format!("0x{field_value:x}"),
+            };
+            parts.push(format!("{module}.{}.{}={shown}", register.name, field.name));
+            fields.push(DecodedField {
+                name: field.name.clone(),
+                value: field_value,
+            });
+        }
+
+        let text = if parts.is_empty() {
+            format!("{module}.{}=0x{value:x}", register.name)
+        } else {
+            parts.join(" ")
+        };
+
+        Some(DecodedTransaction {
+            index,
+            register: register.name.clone(),
+            fields,
+            text,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVD: &str = r#"<device><peripherals><peripheral>
+        <name>UART0</name>
+        <registers>
+            <register>
+                <name>CTRL</name>
+                <addressOffset>0x0</addressOffset>
+                <fields>
+                    <field><name>en</name><bitOffset>0</bitOffset><bitWidth>1</bitWidth></field>
+                    <field><name>baud</name><bitRange>[15:8]</bitRange></field>
+                    <field><name>mode</name><lsb>16</lsb><msb>17</msb></field>
+                    <field><name>wide</name><bitOffset>60</bitOffset><bitWidth>8</bitWidth></field>
+                    <field><name>far</name><lsb>64</lsb><msb>65</msb></field>
+                </fields>
+            </register>
+            <register>
+                <name>TXDATA</name>
+                <addressOffset>0x4</addressOffset>
+                <fields>
+                    <field><name>data</name><bitOffset>0</bitOffset><bitWidth>8</bitWidth></field>
+                </fields>
+            </register>
+        </registers>
+    </peripheral></peripherals></device>"#;
+
+    const YAML: &str = "registers:
+  - name: STATUS
+    offset: 0
+    fields:
+      - { name: busy, bit_offset: 0, bit_width: 1 }
+      - { name: level, bit_offset: 16, bit_width: 8 }
+      - { name: empty, bit_offset: 8, bit_width: 0 }
+  - name: WIDE
+    offset: 8
+    size: 64
+    fields:
+      - { name: all, bit_offset: 0, bit_width: 64 }
+";
+
+    fn load(test: &str) -> RegisterMaps {
+        let dir = std::env::temp_dir().join(format!("pls-regs-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("soc.svd"), SVD).unwrap();
+        fs::write(dir.join("Timer.yaml"), YAML).unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        let maps = RegisterMaps::load(&dir);
+        let _ = fs::remove_dir_all(dir);
+        maps
+    }
+
+    fn decode(maps: &RegisterMaps, module: &str, line: &str) -> Option<String> {
+        let transaction = line.parse::<Transaction>().unwrap();
+        maps.decode(0, module, 0x1000, &transaction).map(|d| d.text)
+    }
+
+    #[test]
+    fn loads_svd_and_yaml_and_skips_wide_fields() {
+        let maps = load("loads");
+        let names = |module: &str, register: usize| -> Vec<String> {
+            maps.modules[module][register]
+                .fields
+                .iter()
+                .map(|f| f.name.clone())
+                .collect()
+        };
+        assert_eq!(maps.modules.len(), 2);
+        assert_eq!(names("uart0", 0), ["en", "baud", "mode"]);
+        let baud = &maps.modules["uart0"][0].fields[1];
+        assert_eq!((baud.bit_offset, baud.bit_width), (8, 8));
+        assert_eq!(names("timer", 0), ["busy", "level"]);
+        assert_eq!(names("timer", 1), ["all"]);
+    }
+
+    #[test]
+    fn decodes_fields_of_sub_word_accesses() {
+        let maps = load("decodes");
+        assert_eq!(
+            decode(&maps, "UART0", "W;core0;1;1000;0;4;00030501").unwrap(),
+            "UART0.CTRL.en=0x1 UART0.CTRL.baud=0x5 UART0.CTRL.mode=0x3"
+        );
+        // a byte access at offset 2 only carries the mode field
+        assert_eq!(
+            decode(&maps, "uart0", "W;core0;1;1002;0;1;02").unwrap(),
+            "uart0.CTRL.mode=0x2"
+        );
+        assert_eq!(
+            decode(&maps, "timer", "R;core0;1;1008;0;8;ffffffffffffffff").unwrap(),
+            "timer.WIDE.all=0xffffffffffffffff"
+        );
+        assert_eq!(decode(&maps, "uart0", "R;core0;1;1010;0;4;0"), None);
+        assert_eq!(decode(&maps, "gpio", "R;core0;1;1000;0;4;0"), None);
+    }
+
+    #[test]
+    fn renders_printable_bytes_as_ascii() {
+        let maps = load("renders");
+        assert_eq!(
+            decode(&maps, "UART0", "W;core0;1;1004;0;1;41").unwrap(),
+            "UART0.TXDATA.data='A'"
+        );
+        assert_eq!(
+            decode(&maps, "UART0", "W;core0;1;1004;0;1;0a").unwrap(),
+            "UART0.TXDATA.data=0xa"
+        );
+    }
+}
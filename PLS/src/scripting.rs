@@ -0,0 +1,461 @@
+use async_trait::async_trait;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::gdb_proxy::ProxyCmd;
+use crate::hex;
+use crate::sink::{TraceState, TransactionSink, SLOW_TICK};
+use crate::transaction::{Transaction, TransactionCmd};
+use crate::virtual_prototype::{VPCtrlMsg, VPLayout};
+
+/// Upper bound of operations per hook call, so a script cannot stall the trace receiver
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// Nesting limits of expressions at global and function level, fixed so debug
+/// builds accept the same scripts as release builds
+const MAX_EXPR_DEPTHS: (usize, usize) = (64, 32);
+/// Annotations and events sent to the clients per flush, later ones are only
+/// counted, so scripts cannot crowd the VP channel
+const MAX_EVENTS: usize = 16;
+/// Packets sent to the gdb stub by the control functions of scripts
+const SINGLE_STEP: &[u8] = b"vCont;s";
+const CONTINUE: &[u8] = b"vCont;c";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind")]
+pub enum ScriptEvent {
+    /// note attached to a transaction, emitted with `annotate(text)`
+    Annotation {
+        script: String,
+        index: Option<usize>,
+        text: String,
+    },
+    /// value of a custom metric, set with `metric(name, value)`
+    Metric {
+        script: String,
+        name: String,
+        value: serde_json::Value,
+    },
+    /// any derived event, emitted with `emit(name, value)`
+    Event {
+        script: String,
+        index: Option<usize>,
+        name: String,
+        value: serde_json::Value,
+    },
+    /// the script failed and was disabled
+    Error { script: String, message: String },
+    /// number of annotations and events of the script dropped since the last flush
+    Dropped { script: String, count: usize },
+}
+
+/// Compiled scripts of the scripts directory
+#[derive(Default)]
+pub struct Scripts {
+    scripts: Vec<(String, AST)>,
+}
+
+impl Scripts {
+    /// Compiles all `.rhai` files of a directory
+    pub fn load(dir: &Path) -> Result<Scripts, String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("could not read scripts dir {} ({e})", dir.display()))?;
+        let mut engine = Engine::new();
+        engine.set_max_expr_depths(MAX_EXPR_DEPTHS.0, MAX_EXPR_DEPTHS.1);
+        let mut scripts = Vec::new();
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().is_none_or(|ext| ext != "rhai") {
+                continue;
+            }
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let ast = engine
+                .compile_file(path.clone())
+                .map_err(|e| format!("could not compile {} ({e})", path.display()))?;
+            println!("[SCRIPT] loaded {name}");
+            scripts.push((name, ast));
+        }
+        scripts.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Scripts { scripts })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+}
+
+/// Values shared between the host and the functions registered in the engine
+#[derive(Default)]
+struct Context {
+    script: String,
+    index: Option<usize>,
+    events: Vec<ScriptEvent>,
+    /// events dropped per script since the last flush
+    dropped: BTreeMap<String, usize>,
+    metrics: BTreeMap<(String, String), serde_json::Value>,
+    metrics_changed: bool,
+}
+
+impl Context {
+    /// Queues an annotation or event for the next flush
+    fn push(&mut self, event: ScriptEvent) {
+        if self.events.len() < MAX_EVENTS {
+            self.events.push(event);
+        } else {
+            *self.dropped.entry(self.script.clone()).or_default() += 1;
+        }
+    }
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    /// value of `this` in the hooks, returned by the optional `init()`
+    state: Dynamic,
+    failed: bool,
+}
+
+/// Runs the hooks of the scripts for the events of the live trace
+pub struct ScriptHost {
+    engine: Engine,
+    scripts: Vec<Script>,
+    context: Arc<Mutex<Context>>,
+    channel: Arc<Sender<VPCtrlMsg>>,
+    /// time of the last flush of metrics and events
+    last_flush: Instant,
+}
+
+fn to_json(value: Dynamic) -> serde_json::Value {
+    rhai::serde::from_dynamic(&value).unwrap_or(serde_json::Value::Null)
+}
+
+/// Queues a command for the gdb proxy, fails if it cannot take it. The reply
+/// of the stub is not awaited, as hooks must not block the trace receiver.
+fn control(proxy: &mpsc::Sender<ProxyCmd>, cmd: ProxyCmd) -> Result<(), Box<EvalAltResult>> {
+    proxy.try_send(cmd).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => "gdb proxy is busy".into(),
+        mpsc::error::TrySendError::Closed(_) => "gdb proxy is not running".into(),
+    })
+}
+
+fn request(packet: &[u8]) -> ProxyCmd {
+    let (reply, _) = oneshot::channel();
+    ProxyCmd::Request(packet.to_vec(), reply)
+}
+
+/// Rhai integers are i64, larger values are () like missing ones
+fn int(value: Option<u64>) -> Dynamic {
+    value
+        .and_then(|v| i64::try_from(v).ok())
+        .map_or(Dynamic::UNIT, Dynamic::from)
+}
+
+fn transaction_map(transaction: &Transaction, index: usize, modules: &[String]) -> Map {
+    let mut map = Map::new();
+    let number = |value: &str| int(hex::parse(value).ok());
+    let action = match transaction.action {
+        TransactionCmd::Read => "R",
+        TransactionCmd::Write => "W",
+    };
+    map.insert("index".into(), (index as i64).into());
+    map.insert("time".into(), int(Some(transaction.sim_time)));
+    map.insert("action".into(), action.into());
+    map.insert("initiator".into(), transaction.initiator.clone().into());
+    let module = modules.get(transaction.target as usize).cloned();
+    map.insert("target".into(), module.unwrap_or_default().into());
+    map.insert("target_index".into(), (transaction.target as i64).into());
+    map.insert("address".into(), number(&transaction.address));
+    map.insert("address_hex".into(), transaction.address.clone().into());
+    map.insert("length".into(), (transaction.data_length as i64).into());
+    map.insert("data".into(), number(&transaction.data));
+    map.insert("data_hex".into(), transaction.data.clone().into());
+    let attributes: Map = transaction
+        .attributes
+        .iter()
+        .map(|(name, value)| (name.into(), value.clone().into()))
+        .collect();
+    map.insert("attributes".into(), attributes.into());
+    map
+}
+
+impl ScriptHost {
+    pub fn new(
+        scripts: &Scripts,
+        channel: Arc<Sender<VPCtrlMsg>>,
+        proxy: mpsc::Sender<ProxyCmd>,
+    ) -> ScriptHost {
+        let context = Arc::new(Mutex::new(Context::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let ctx = context.clone();
+        engine.register_fn("annotate", move |text: &str| {
+            let mut ctx = ctx.lock().unwrap();
+            let event = ScriptEvent::Annotation {
+                script: ctx.script.clone(),
+                index: ctx.index,
+                text: text.to_owned(),
+            };
+            ctx.push(event);
+        });
+        let ctx = context.clone();
+        engine.register_fn("emit", move |name: &str, value: Dynamic| {
+            let mut ctx = ctx.lock().unwrap();
+            let event = ScriptEvent::Event {
+                script: ctx.script.clone(),
+                index: ctx.index,
+                name: name.to_owned(),
+                value: to_json(value),
+            };
+            ctx.push(event);
+        });
+        let ctx = context.clone();
+        engine.register_fn("metric", move |name: &str, value: Dynamic| {
+            let mut ctx = ctx.lock().unwrap();
+            let key = (ctx.script.clone(), name.to_owned());
+            ctx.metrics.insert(key, to_json(value));
+            ctx.metrics_changed = true;
+        });
+        let p = proxy.clone();
+        engine.register_fn("halt", move || control(&p, ProxyCmd::Interrupt));
+        let p = proxy.clone();
+        engine.register_fn("step", move || control(&p, request(SINGLE_STEP)));
+        engine.register_fn("resume", move || control(&proxy, request(CONTINUE)));
+        engine.on_print(|text| println!("[SCRIPT] {text}"));
+
+        let mut host = ScriptHost {
+            engine,
+            scripts: scripts
+                .scripts
+                .iter()
+                .map(|(name, ast)| Script {
+                    name: name.clone(),
+                    ast: ast.clone(),
+                    state: Map::new().into(),
+                    failed: false,
+                })
+                .collect(),
+            context,
+            channel,
+            last_flush: Instant::now(),
+        };
+        host.call("init", None, ());
+        host
+    }
+
+    /// Calls a hook of all scripts which define it. Scripts are disabled after
+    /// their first error.
+    fn call(&mut self, hook: &str, index: Option<usize>, args: impl rhai::FuncArgs + Clone) {
+        for script in self.scripts.iter_mut().filter(|s| !s.failed) {
+            if !script.ast.iter_functions().any(|f| f.name == hook) {
+                continue;
+            }
+            {
+                let mut ctx = self.context.lock().unwrap();
+                ctx.script.clone_from(&script.name);
+                ctx.index = index;
+            }
+
+            let mut this = std::mem::take(&mut script.state);
+            let options = CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut this);
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                options,
+                &mut Scope::new(),
+                &script.ast,
+                hook,
+                args.clone(),
+            );
+            match result {
+                // init returns the initial state of the script
+                Ok(state) if hook == "init" => script.state = state,
+                Ok(_) => script.state = this,
+                Err(e) => {
+                    println!("[SCRIPT] {} failed in {hook} ({e})", script.name);
+                    script.failed = true;
+                    self.context
+                        .lock()
+                        .unwrap()
+                        .events
+                        // errors bypass the limit, each script fails once
+                        .push(ScriptEvent::Error {
+                            script: script.name.clone(),
+                            message: e.to_string(),
+                        });
+                }
+            }
+        }
+    }
+
+    /// Sends the queued events and the changed metrics to the clients, called
+    /// periodically to keep per transaction updates off the client connections
+    fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        let mut ctx = self.context.lock().unwrap();
+        for event in std::mem::take(&mut ctx.events) {
+            let _ = self.channel.send(VPCtrlMsg::Script(event));
+        }
+        for (script, count) in std::mem::take(&mut ctx.dropped) {
+            println!("[SCRIPT] dropped {count} events of {script}");
+            let _ = self
+                .channel
+                .send(VPCtrlMsg::Script(ScriptEvent::Dropped { script, count }));
+        }
+        if !std::mem::take(&mut ctx.metrics_changed) {
+            return;
+        }
+        for ((script, name), value) in ctx.metrics.iter() {
+            let _ = self.channel.send(VPCtrlMsg::Script(ScriptEvent::Metric {
+                script: script.clone(),
+                name: name.clone(),
+                value: value.clone(),
+            }));
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionSink for ScriptHost {
+    /// Passes the complete layout as an array of `#{name, start, end}`
+    async fn on_layout(&mut self, layout: &VPLayout) {
+        let modules: rhai::Array = layout
+            .modules
+            .iter()
+            .zip(layout.start_addrs.iter().zip(layout.end_addrs.iter()))
+            .map(|(name, (start, end))| {
+                let mut map = Map::new();
+                map.insert("name".into(), name.clone().into());
+                map.insert("start".into(), start.clone().into());
+                map.insert("end".into(), end.clone().into());
+                Dynamic::from_map(map)
+            })
+            .collect();
+        self.call("on_layout", None, (modules,));
+    }
+
+    async fn on_transaction(&mut self, transaction: &Transaction, state: &TraceState<'_>) {
+        let map = transaction_map(transaction, state.index, &state.layout.modules);
+        self.call("on_transaction", Some(state.index), (map,));
+    }
+
+    async fn on_tick(&mut self) {
+        if self.last_flush.elapsed() >= SLOW_TICK {
+            self.flush();
+        }
+    }
+
+    async fn on_shutdown(&mut self) {
+        self.call("on_finish", None, ());
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    use crate::shadow::ShadowMemory;
+
+    fn map(line: &str) -> Map {
+        transaction_map(&line.parse().unwrap(), 3, &["ram".to_owned()])
+    }
+
+    #[test]
+    fn passes_values_which_do_not_fit_as_unit() {
+        let t = map("W;core0;0;1004;20;4;2a");
+        assert_eq!(t["index"].as_int(), Ok(3));
+        assert_eq!(t["target"].clone().into_string().unwrap(), "ram");
+        assert_eq!(t["address"].as_int(), Ok(0x1004));
+        assert_eq!(t["data"].as_int(), Ok(0x2a));
+        assert_eq!(t["data_hex"].clone().into_string().unwrap(), "2a");
+
+        let t = map("R;core0;0;8000000000000000;20;4;");
+        assert!(t["address"].is_unit() && t["data"].is_unit());
+        assert_eq!(
+            t["address_hex"].clone().into_string().unwrap(),
+            "8000000000000000"
+        );
+        assert_eq!(t["data_hex"].clone().into_string().unwrap(), "");
+
+        let t = map("R;core0;1;7fffffffffffffff;20;16;00112233445566778899aabbccddeeff");
+        assert_eq!(t["address"].as_int(), Ok(i64::MAX));
+        assert!(t["data"].is_unit());
+        assert_eq!(t["target"].clone().into_string().unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn runs_the_hooks_of_scripts() {
+        let dir = std::env::temp_dir().join(format!("pls-scripts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("data.rhai"),
+            r#"
+            fn init() { #{ count: 0 } }
+            fn on_transaction(t) {
+                this.count += 1;
+                if t.data == () { annotate("wide " + t.data_hex) } else { emit("data", t.data) }
+            }
+            fn on_finish() { metric("count", this.count) }
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("broken.rhai"),
+            "fn on_transaction(t) { t.missing() }",
+        )
+        .unwrap();
+        let scripts = Scripts::load(&dir);
+        let _ = fs::remove_dir_all(dir);
+
+        let (channel, mut events) = broadcast::channel(16);
+        let mut host = ScriptHost::new(&scripts.unwrap(), Arc::new(channel), mpsc::channel(1).0);
+        let layout = VPLayout::default();
+        let shadow = futures::lock::Mutex::new(ShadowMemory::default());
+        for (index, line) in ["R;core0;0;10;1;4;ff", "R;core0;0;10;2;16;1ffffffffffffffff"]
+            .iter()
+            .enumerate()
+        {
+            let state = TraceState {
+                index,
+                layout: &layout,
+                shadow: &shadow,
+            };
+            host.on_transaction(&line.parse().unwrap(), &state).await;
+        }
+        host.on_shutdown().await;
+
+        let mut received = Vec::new();
+        while let Ok(VPCtrlMsg::Script(event)) = events.try_recv() {
+            received.push(event);
+        }
+        assert!(matches!(&received[0], ScriptEvent::Error { script, .. } if script == "broken"));
+        assert_eq!(
+            received[1..],
+            [
+                ScriptEvent::Event {
+                    script: "data".to_owned(),
+                    index: Some(0),
+                    name: "data".to_owned(),
+                    value: serde_json::json!(255),
+                },
+                ScriptEvent::Annotation {
+                    script: "data".to_owned(),
+                    index: Some(1),
+                    text: "wide 1ffffffffffffffff".to_owned(),
+                },
+                ScriptEvent::Metric {
+                    script: "data".to_owned(),
+                    name: "count".to_owned(),
+                    value: serde_json::json!(2),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::binary_trace::{self, MAGIC};
+use crate::export::ndjson::Header;
+use crate::export::Record;
+use crate::options::StoreOptions;
+use crate::store::TransactionStore;
+use crate::trace_format::{TraceFormat, TraceLine};
+use crate::transaction::Transaction;
+use crate::virtual_prototype::VPLayout;
+
+/// Transactions of a recorded session together with the layout of its VP
+pub struct Session {
+    pub layout: VPLayout,
+    pub store: TransactionStore,
+}
+
+impl Session {
+    /// Loads a NDJSON export or a raw trace as sent by the VP on its trace port,
+    /// text traces are parsed with the given format
+    pub fn load(path: &Path, format: &TraceFormat) -> Result<Session, String> {
+        let file =
+            File::open(path).map_err(|e| format!("could not open {} ({e})", path.display()))?;
+        let options = StoreOptions::default();
+        let mut session = Session {
+            layout: VPLayout::default(),
+            store: TransactionStore::new(options.memory_cap, options.spill_dir),
+        };
+
+        let mut reader = BufReader::new(file);
+        if reader.fill_buf().is_ok_and(|buf| buf.starts_with(MAGIC)) {
+            let mut data = Vec::new();
+            reader
+                .read_to_end(&mut data)
+                .map_err(|e| format!("could not read {} ({e})", path.display()))?;
+            session
+                .add_binary(&data[MAGIC.len()..])
+                .map_err(|e| format!("{} {e}", path.display()))?;
+            return Ok(session);
+        }
+
+        let mut lines = reader.lines().map_while(Result::ok).peekable();
+        let is_ndjson = lines.peek().is_some_and(|l| l.starts_with('{'));
+        for (number, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = if is_ndjson {
+                session.add_record(number, &line)
+            } else {
+                session.add_trace_line(&line, format)
+            };
+            parsed.map_err(|e| format!("{}:{} {e}", path.display(), number + 1))?;
+        }
+
+        Ok(session)
+    }
+
+    fn add_record(&mut self, number: usize, line: &str) -> Result<(), String> {
+        if number == 0 {
+            let header: Header = serde_json::from_str(line).map_err(|e| e.to_string())?;
+            self.layout = header.layout;
+        } else {
+            let record: Record = serde_json::from_str(line).map_err(|e| e.to_string())?;
+            self.store.push(Transaction::from(record));
+        }
+        Ok(())
+    }
+
+    /// Adds a line in the format of the VP, either a module of the layout or a transaction
+    fn add_trace_line(&mut self, line: &str, format: &TraceFormat) -> Result<(), String> {
+        self.add_line(format.parse(line)?);
+        Ok(())
+    }
+
+    /// Adds the frames of a binary trace following its magic
+    fn add_binary(&mut self, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            let Some((line, size)) = binary_trace::decode(data)? else {
+                return Err(String::from("binary trace ended within a frame"));
+            };
+            self.add_line(line);
+            data = &data[size..];
+        }
+        Ok(())
+    }
+
+    fn add_line(&mut self, line: TraceLine) {
+        match line {
+            TraceLine::Transaction(transaction) => self.store.push(transaction),
+            TraceLine::Module(name, start, end) => {
+                self.layout.modules.push(name);
+                self.layout.start_addrs.push(start);
+                self.layout.end_addrs.push(end);
+            }
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use futures::lock::{Mutex, MutexGuard};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use crate::store::TransactionStore;
+use crate::transaction::Transaction;
+
+/// Number of byte changes kept as history, older ones are folded into the
+/// base values and looked up by replaying the store
+const MAX_HISTORY: usize = 4_000_000;
+/// Transactions replayed per store lock when rebuilding older states
+const REPLAY_CHUNK: usize = 65_536;
+
+#[derive(Serialize, Debug)]
+pub struct ShadowValue {
+    pub module: usize,
+    pub address: String,
+    pub length: u8,
+    /// value in hex, None if not all bytes were observed
+    pub value: Option<String>,
+}
+
+/// Memory contents of one module reconstructed from its transactions
+#[derive(Default, Debug)]
+struct ModuleShadow {
+    // value of each byte before the history starts
+    base: BTreeMap<u64, u8>,
+    // value history of each byte as (transaction index, value)
+    bytes: BTreeMap<u64, Vec<(usize, u8)>>,
+    // width of the last access at each address
+    slots: BTreeMap<u64, u8>,
+}
+
+impl ModuleShadow {
+    /// Returns the number of recorded changes
+    fn write(&mut self, index: usize, address: u64, length: u8, data: u64, history: bool) -> usize {
+        self.slots.insert(address, length);
+        let mut changes = 0;
+        for i in 0..length.min(8) {
+            let value = (data >> (8 * i)) as u8;
+            let address = address + i as u64;
+            if !history {
+                self.base.insert(address, value);
+                continue;
+            }
+            let latest = match self.bytes.get(&address).and_then(|h| h.last()) {
+                Some((_, v)) => Some(*v),
+                None => self.base.get(&address).copied(),
+            };
+            // only changes are recorded to keep polled registers cheap
+            if latest != Some(value) {
+                self.bytes.entry(address).or_default().push((index, value));
+                changes += 1;
+            }
+        }
+        changes
+    }
+
+    /// Folds the changes before `start` into the base values, returns the
+    /// number of removed changes
+    fn fold(&mut self, start: usize) -> usize {
+        let mut removed = 0;
+        self.bytes.retain(|address, history| {
+            let old = history.partition_point(|(i, _)| *i < start);
+            if let Some((_, value)) = old.checked_sub(1).map(|i| history[i]) {
+                self.base.insert(*address, value);
+            }
+            history.drain(..old);
+            removed += old;
+            !history.is_empty()
+        });
+        removed
+    }
+
+    fn byte(&self, address: u64, at: usize) -> Option<u8> {
+        let history = self.bytes.get(&address).map_or(&[][..], Vec::as_slice);
+        let idx = history.partition_point(|(i, _)| *i <= at);
+        match idx.checked_sub(1) {
+            Some(i) => Some(history[i].1),
+            None => self.base.get(&address).copied(),
+        }
+    }
+
+    fn read(&self, address: u64, length: u8, at: usize) -> Option<u64> {
+        let mut value = 0;
+        for i in 0..length.min(8) {
+            value |= (self.byte(address + i as u64, at)? as u64) << (8 * i);
+        }
+        Some(value)
+    }
+}
+
+/// Sparse shadow memory per target module, built from observed writes and
+/// read results. The history is bounded, states before `history_start` are
+/// rebuilt from the transaction store.
+#[derive(Debug)]
+pub struct ShadowMemory {
+    modules: Vec<ModuleShadow>,
+    // number of transactions applied, used as index of the next one
+    count: usize,
+    // first transaction whose changes are kept individually
+    history_start: usize,
+    // number of changes kept individually
+    changes: usize,
+    keep_history: bool,
+}
+
+impl Default for ShadowMemory {
+    fn default() -> Self {
+        ShadowMemory {
+            modules: Vec::new(),
+            count: 0,
+            history_start: 0,
+            changes: 0,
+            keep_history: true,
+        }
+    }
+}
+
+/// Shadow memory of a session or a state rebuilt for an older position
+pub enum ShadowView<'a> {
+    Live(MutexGuard<'a, ShadowMemory>),
+    Replayed(ShadowMemory),
+}
+
+impl Deref for ShadowView<'_> {
+    type Target = ShadowMemory;
+
+    fn deref(&self) -> &ShadowMemory {
+        match self {
+            ShadowView::Live(shadow) => shadow,
+            ShadowView::Replayed(shadow) => shadow,
+        }
+    }
+}
+
+/// Returns the shadow memory which can answer reads as of `at`. States older
+/// than the history are replayed from the store in chunks, so the trace
+/// receiver is not blocked meanwhile.
+pub async fn view<'a>(
+    shadow: &'a Mutex<ShadowMemory>,
+    store: &Mutex<TransactionStore>,
+    at: Option<usize>,
+) -> ShadowView<'a> {
+    let live = shadow.lock().await;
+    let Some(at) = at.filter(|at| *at < live.history_start) else {
+        return ShadowView::Live(live);
+    };
+    drop(live);
+
+    let mut replayed = ShadowMemory {
+        keep_history: false,
+        ..ShadowMemory::default()
+    };
+    let mut start = 0;
+    while start <= at {
+        let end = (at + 1).min(start + REPLAY_CHUNK);
+        let transactions = store.lock().await.range(start..end);
+        if transactions.is_empty() {
+            break;
+        }
+        start += transactions.len();
+        for transaction in transactions.iter() {
+            replayed.apply(transaction);
+        }
+    }
+    ShadowView::Replayed(replayed)
+}
+
+impl ShadowMemory {
+    pub fn apply(&mut self, transaction: &Transaction) {
+        let index = self.count;
+        self.count += 1;
+
+        let (Ok(address), Ok(data)) = (
+            u64::from_str_radix(&transaction.address, 16),
+            u64::from_str_radix(&transaction.data, 16),
+        ) else {
+            return;
+        };
+        let target = transaction.target as usize;
+        if self.modules.len() <= target {
+            self.modules.resize_with(target + 1, ModuleShadow::default);
+        }
+        let length = transaction.data_length;
+        let history = self.keep_history;
+        self.changes += self.modules[target].write(index, address, length, data, history);
+        if self.changes > MAX_HISTORY {
+            self.fold_history();
+        }
+    }
+
+    /// Halves the history by moving its start towards the latest transaction
+    fn fold_history(&mut self) {
+        while self.changes > MAX_HISTORY / 2 && self.history_start < self.count {
+            self.history_start += (self.count - self.history_start).div_ceil(2);
+            let start = self.history_start;
+            let removed: usize = self.modules.iter_mut().map(|m| m.fold(start)).sum();
+            self.changes -= removed;
+        }
+    }
+
+    /// Returns the value at an address as of transaction `at`, or the latest one
+    pub fn read(
+        &self,
+        module: Option<usize>,
+        address: u64,
+        length: u8,
+        at: Option<usize>,
+    ) -> Option<ShadowValue> {
+        let at = at.unwrap_or(usize::MAX);
+        let candidates: Vec<usize> = match module {
+            Some(m) => vec![m],
+            None => (0..self.modules.len()).collect(),
+        };
+
+        // without a module the first one which observed the address is used
+        let module = candidates.into_iter().find(|m| {
+            self.modules
+                .get(*m)
+                .is_some_and(|s| s.byte(address, at).is_some())
+        })?;
+        let value = self.modules[module].read(address, length, at);
+
+        Some(ShadowValue {
+            module,
+            address: format!("{address:x}"),
+            length,
+            value: value.map(|v| format!("{v:x}")),
+        })
+    }
+
+    /// Returns the latest value at an address of the first module which observed it
+    pub fn value(&self, address: u64, length: u8) -> Option<u64> {
+        self.modules
+            .iter()
+            .find(|m| m.byte(address, usize::MAX).is_some())?
+            .read(address, length, usize::MAX)
+    }
+
+    /// Returns all registers of a module accessed up to transaction `at`
+    pub fn registers(&self, module: usize, at: Option<usize>) -> Vec<ShadowValue> {
+        let at = at.unwrap_or(usize::MAX);
+        let Some(shadow) = self.modules.get(module) else {
+            return Vec::new();
+        };
+
+        shadow
+            .slots
+            .iter()
+            .filter(|(address, _)| shadow.byte(**address, at).is_some())
+            .map(|(address, length)| ShadowValue {
+                module,
+                address: format!("{address:x}"),
+                length: *length,
+                value: shadow.read(*address, *length, at).map(|v| format!("{v:x}")),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(shadow: &mut ShadowMemory, address: u64, data: &str) {
+        let line = format!("W;core0;0;{address:x};0;4;{data}");
+        shadow.apply(&line.parse().unwrap());
+    }
+
+    #[test]
+    fn reads_values_of_older_transactions() {
+        let mut shadow = ShadowMemory::default();
+        write(&mut shadow, 0x10, "11223344");
+        write(&mut shadow, 0x10, "11223355");
+        write(&mut shadow, 0x14, "1");
+
+        assert_eq!(shadow.value(0x10, 4), Some(0x11223355));
+        assert_eq!(shadow.value(0x12, 2), Some(0x1122));
+        assert_eq!(shadow.value(0x16, 4), None);
+        let old = shadow.read(Some(0), 0x10, 4, Some(0)).unwrap();
+        assert_eq!(old.value.as_deref(), Some("11223344"));
+        assert!(shadow.read(Some(0), 0x14, 4, Some(1)).is_none());
+        assert_eq!(shadow.registers(0, Some(0)).len(), 1);
+        assert_eq!(shadow.registers(0, None).len(), 2);
+    }
+
+    #[test]
+    fn folds_the_history_into_base_values() {
+        let mut shadow = ShadowMemory::default();
+        write(&mut shadow, 0x10, "1");
+        write(&mut shadow, 0x10, "2");
+        write(&mut shadow, 0x10, "3");
+        // the first write sets all 4 bytes, the second one only the lowest
+        assert_eq!(shadow.modules[0].fold(2), 5);
+        assert_eq!(shadow.modules[0].byte(0x10, 2), Some(3));
+        // states before the history are only kept as the base value
+        assert_eq!(shadow.modules[0].byte(0x10, 0), Some(2));
+        assert_eq!(shadow.value(0x10, 4), Some(3));
+    }
+
+    #[tokio::test]
+    async fn replays_states_before_the_history() {
+        let mut store = TransactionStore::new(0, std::env::temp_dir());
+        let mut shadow = ShadowMemory::default();
+        for data in ["1", "2", "3"] {
+            let transaction: Transaction = format!("W;core0;0;10;0;4;{data}").parse().unwrap();
+            shadow.apply(&transaction);
+            store.push(transaction);
+        }
+        shadow.history_start = 2;
+        shadow.modules[0].fold(2);
+        let (shadow, store) = (Mutex::new(shadow), Mutex::new(store));
+
+        let replayed = view(&shadow, &store, Some(0)).await;
+        assert!(matches!(replayed, ShadowView::Replayed(_)));
+        assert_eq!(replayed.value(0x10, 4), Some(1));
+        drop(replayed);
+        let live = view(&shadow, &store, Some(2)).await;
+        assert!(matches!(live, ShadowView::Live(_)));
+        assert_eq!(live.value(0x10, 4), Some(3));
+    }
+}
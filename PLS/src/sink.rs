@@ -0,0 +1,304 @@
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::Sender;
+
+use crate::export::ndjson;
+use crate::shadow::ShadowMemory;
+use crate::stats::TrafficStats;
+use crate::store::TransactionStore;
+use crate::transaction::Transaction;
+use crate::virtual_prototype::{VPCtrlMsg, VPLayout};
+
+/// Interval in which statistics and other slow updates are pushed to clients
+pub const SLOW_TICK: Duration = Duration::from_secs(1);
+
+/// Session state passed along with each transaction. The layout is a copy
+/// owned by the trace receiver. Sinks lock at most one of the session mutexes
+/// (shadow, store, stats) at a time and release it before returning, clients
+/// may lock them in any order.
+pub struct TraceState<'a> {
+    /// position of the transaction in the session
+    pub index: usize,
+    pub layout: &'a VPLayout,
+    /// memory contents before the transaction is applied
+    pub shadow: &'a Mutex<ShadowMemory>,
+}
+
+/// Consumer of the live trace. The trace receiver calls the sinks of a VP in
+/// the order they were added.
+#[async_trait]
+pub trait TransactionSink: Send {
+    /// Called once the layout is complete, before its first transaction
+    async fn on_layout(&mut self, _layout: &VPLayout) {}
+
+    async fn on_transaction(&mut self, transaction: &Transaction, state: &TraceState<'_>);
+
+    /// Called every few milliseconds, e.g. to batch updates
+    async fn on_tick(&mut self) {}
+
+    /// Called once the trace ended, either because the VP closed the
+    /// connection or because the VP was stopped
+    async fn on_shutdown(&mut self) {}
+}
+
+/// Notifies the client handlers about new layouts and transactions
+pub struct Broadcaster {
+    channel: Arc<Sender<VPCtrlMsg>>,
+    layout_changed: bool,
+    transactions_changed: bool,
+}
+
+impl Broadcaster {
+    pub fn new(channel: Arc<Sender<VPCtrlMsg>>) -> Broadcaster {
+        Broadcaster {
+            channel,
+            layout_changed: false,
+            transactions_changed: false,
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionSink for Broadcaster {
+    async fn on_layout(&mut self, _layout: &VPLayout) {
+        self.layout_changed = true;
+    }
+
+    async fn on_transaction(&mut self, _transaction: &Transaction, _state: &TraceState<'_>) {
+        self.transactions_changed = true;
+    }
+
+    async fn on_tick(&mut self) {
+        // Send archticture first otherwise transaction cannot be displayed
+        if self.layout_changed {
+            let _ = self.channel.send(VPCtrlMsg::RecvModule);
+            self.layout_changed = false;
+        } else if self.transactions_changed {
+            let _ = self.channel.send(VPCtrlMsg::RecvTransaction);
+            self.transactions_changed = false;
+        }
+    }
+
+    async fn on_shutdown(&mut self) {
+        if self.layout_changed {
+            let _ = self.channel.send(VPCtrlMsg::RecvModule);
+        }
+        let _ = self.channel.send(VPCtrlMsg::Finished);
+    }
+}
+
+/// Records the transactions in the store of the VP, the history of all clients
+pub struct StoreSink {
+    store: Arc<Mutex<TransactionStore>>,
+}
+
+impl StoreSink {
+    pub fn new(store: Arc<Mutex<TransactionStore>>) -> StoreSink {
+        StoreSink { store }
+    }
+}
+
+#[async_trait]
+impl TransactionSink for StoreSink {
+    async fn on_transaction(&mut self, transaction: &Transaction, _state: &TraceState<'_>) {
+        self.store.lock().await.push(transaction.clone());
+    }
+}
+
+/// Collects the traffic statistics and notifies clients about changes
+pub struct StatsSink {
+    stats: Arc<Mutex<TrafficStats>>,
+    channel: Arc<Sender<VPCtrlMsg>>,
+    changed: bool,
+    last_update: Instant,
+}
+
+impl StatsSink {
+    pub fn new(stats: Arc<Mutex<TrafficStats>>, channel: Arc<Sender<VPCtrlMsg>>) -> StatsSink {
+        StatsSink {
+            stats,
+            channel,
+            changed: false,
+            last_update: Instant::now(),
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionSink for StatsSink {
+    async fn on_transaction(&mut self, transaction: &Transaction, _state: &TraceState<'_>) {
+        self.stats.lock().await.apply(transaction);
+        self.changed = true;
+    }
+
+    async fn on_tick(&mut self) {
+        if self.changed && self.last_update.elapsed() >= SLOW_TICK {
+            let _ = self.channel.send(VPCtrlMsg::Stats);
+            self.changed = false;
+            self.last_update = Instant::now();
+        }
+    }
+}
+
+/// Writes the session as NDJSON file while it is received, so it survives
+/// the server and can be loaded by the CLI
+pub struct FileRecorder {
+    out: BufWriter<File>,
+    header_written: bool,
+    last_flush: Instant,
+}
+
+impl FileRecorder {
+    /// Creates `session-<secs>.ndjson` in the given directory
+    pub fn create(dir: &Path) -> Result<FileRecorder, String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create record dir {} ({e})", dir.display()))?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = dir.join(format!("session-{secs}.ndjson"));
+        let file = File::create(&path)
+            .map_err(|e| format!("could not create {} ({e})", path.display()))?;
+        println!("[SINK] recording session to {}", path.display());
+        Ok(FileRecorder {
+            out: BufWriter::new(file),
+            header_written: false,
+            last_flush: Instant::now(),
+        })
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.out.flush() {
+            println!("[SINK] could not write recording ({e})");
+        }
+        self.last_flush = Instant::now();
+    }
+}
+
+#[async_trait]
+impl TransactionSink for FileRecorder {
+    async fn on_layout(&mut self, layout: &VPLayout) {
+        // a session file has a single layout, later changes are not recorded
+        if !self.header_written {
+            let _ = ndjson::write_header(layout, &mut self.out);
+            self.header_written = true;
+        }
+    }
+
+    async fn on_transaction(&mut self, transaction: &Transaction, state: &TraceState<'_>) {
+        let _ = ndjson::write_record(state.index, transaction, state.layout, &mut self.out);
+    }
+
+    async fn on_tick(&mut self) {
+        if self.last_flush.elapsed() >= SLOW_TICK {
+            self.flush();
+        }
+    }
+
+    async fn on_shutdown(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::{self, Receiver};
+
+    fn layout() -> VPLayout {
+        VPLayout {
+            modules: vec!["ram".to_owned()],
+            start_addrs: vec!["0".to_owned()],
+            end_addrs: vec!["fff".to_owned()],
+        }
+    }
+
+    /// Passes the transactions to the sinks like the trace receiver does
+    async fn feed(sinks: &mut [Box<dyn TransactionSink>], lines: &[&str]) {
+        let (layout, shadow) = (layout(), Mutex::new(ShadowMemory::default()));
+        for sink in sinks.iter_mut() {
+            sink.on_layout(&layout).await;
+        }
+        for (index, line) in lines.iter().enumerate() {
+            let state = TraceState {
+                index,
+                layout: &layout,
+                shadow: &shadow,
+            };
+            for sink in sinks.iter_mut() {
+                sink.on_transaction(&line.parse().unwrap(), &state).await;
+            }
+        }
+    }
+
+    fn received(receiver: &mut Receiver<VPCtrlMsg>) -> Vec<VPCtrlMsg> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn notifies_clients_once_per_tick() {
+        let (channel, mut receiver) = broadcast::channel(16);
+        let mut sinks: Vec<Box<dyn TransactionSink>> =
+            vec![Box::new(Broadcaster::new(Arc::new(channel)))];
+        feed(&mut sinks, &["R;core0;0;10;1;4;1", "R;core0;0;14;2;4;1"]).await;
+        let sink = &mut sinks[0];
+        // the layout is announced before its transactions
+        for _ in 0..3 {
+            sink.on_tick().await;
+        }
+        assert!(received(&mut receiver) == [VPCtrlMsg::RecvModule, VPCtrlMsg::RecvTransaction]);
+        sink.on_shutdown().await;
+        assert!(received(&mut receiver) == [VPCtrlMsg::Finished]);
+    }
+
+    #[tokio::test]
+    async fn records_and_counts_transactions() {
+        let (channel, mut receiver) = broadcast::channel(16);
+        let store = Arc::new(Mutex::new(TransactionStore::new(0, std::env::temp_dir())));
+        let stats = Arc::new(Mutex::new(TrafficStats::default()));
+        let mut stats_sink = StatsSink::new(stats.clone(), Arc::new(channel));
+        // statistics are sent at most once per SLOW_TICK
+        stats_sink.last_update = Instant::now() - SLOW_TICK;
+        let mut sinks: Vec<Box<dyn TransactionSink>> = vec![
+            Box::new(StoreSink::new(store.clone())),
+            Box::new(stats_sink),
+        ];
+        feed(&mut sinks, &["R;core0;0;10;1;4;1", "W;core0;0;14;2;2;1"]).await;
+
+        assert_eq!(store.lock().await.len(), 2);
+        assert_eq!(store.lock().await.get(1).unwrap().address, "14");
+        let report = stats.lock().await.report(&layout().modules);
+        assert_eq!(
+            (report.total, report.modules[0].counters.bytes_written),
+            (2, 2)
+        );
+        sinks[1].on_tick().await;
+        sinks[1].on_tick().await;
+        assert!(received(&mut receiver) == [VPCtrlMsg::Stats]);
+    }
+
+    #[tokio::test]
+    async fn records_sessions_to_files() {
+        let dir = std::env::temp_dir().join(format!("pls-recorder-{}", std::process::id()));
+        let mut sinks: Vec<Box<dyn TransactionSink>> =
+            vec![Box::new(FileRecorder::create(&dir).unwrap())];
+        feed(&mut sinks, &["R;core0;0;10;1;4;1", "W;core0;0;14;2;2;1"]).await;
+        // later layouts are not recorded
+        sinks[0].on_layout(&VPLayout::default()).await;
+        sinks[0].on_shutdown().await;
+
+        let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let content = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_dir_all(dir);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("{\"layout\":"));
+        assert!(lines[2].contains("\"index\":1,"));
+    }
+}
@@ -0,0 +1,373 @@
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::unix::pipe;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::Sleep;
+
+use crate::binary_trace::{self, MAGIC};
+use crate::trace_format::{TraceFormat, TraceLine};
+
+/// Time waited for new data at the end of a tailed file
+const TAIL_INTERVAL: Duration = Duration::from_millis(50);
+/// Bytes requested from the source per read
+const READ_SIZE: usize = 64 * 1024;
+
+pub type TraceReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Origin of the trace lines of a session, written as `<kind>:<location>`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum TraceSource {
+    /// `tcp:<host>:<port>`, the debug bus port of the RISC-V VP++
+    Tcp(String),
+    /// `unix:<path>`, a Unix domain socket
+    Unix(PathBuf),
+    /// `pipe:<path>`, a named pipe, ends once the writer closes it
+    Pipe(PathBuf),
+    /// `file:<path>`, a complete trace file
+    File(PathBuf),
+    /// `tail:<path>`, a trace file which is still written
+    Tail(PathBuf),
+}
+
+impl TraceSource {
+    /// TCP port on the local host, the default source of the RISC-V VP++
+    pub fn local_port(port: u16) -> TraceSource {
+        TraceSource::Tcp(format!("127.0.0.1:{port}"))
+    }
+
+    /// Path of the source, None for TCP sources
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            TraceSource::Tcp(_) => None,
+            TraceSource::Unix(path)
+            | TraceSource::Pipe(path)
+            | TraceSource::File(path)
+            | TraceSource::Tail(path) => Some(path),
+        }
+    }
+
+    /// Checks that the path of the source lies in one of the directories.
+    /// Links and `..` are resolved, so the path must exist.
+    pub fn check_dirs(&self, dirs: &[PathBuf]) -> Result<(), String> {
+        let Some(path) = self.path() else {
+            return Ok(());
+        };
+        let path = path
+            .canonicalize()
+            .map_err(|e| format!("could not resolve trace source {self} ({e})"))?;
+        if dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| path.starts_with(dir))
+        {
+            Ok(())
+        } else {
+            Err(format!("trace source {self} is outside of the source dirs"))
+        }
+    }
+
+    /// Opens the source, on sockets the binary trace encoding can be offered
+    pub async fn open(&self, offer_binary: bool) -> io::Result<TraceReader> {
+        Ok(match self {
+            TraceSource::Tcp(address) => {
+                let mut stream = TcpStream::connect(address).await?;
+                if offer_binary {
+                    stream.write_all(MAGIC).await?;
+                }
+                Box::new(stream)
+            }
+            TraceSource::Unix(path) => {
+                let mut stream = UnixStream::connect(path).await?;
+                if offer_binary {
+                    stream.write_all(MAGIC).await?;
+                }
+                Box::new(stream)
+            }
+            TraceSource::Pipe(path) => Box::new(pipe::OpenOptions::new().open_receiver(path)?),
+            TraceSource::File(path) => Box::new(File::open(path).await?),
+            TraceSource::Tail(path) => Box::new(Tail {
+                file: File::open(path).await?,
+                delay: None,
+            }),
+        })
+    }
+}
+
+impl FromStr for TraceSource {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let Some((kind, location)) = text.split_once(':') else {
+            return Err(format!("trace source {text} is not <kind>:<location>"));
+        };
+        let path = PathBuf::from(location);
+        match kind {
+            "tcp" => Ok(TraceSource::Tcp(location.to_owned())),
+            "unix" => Ok(TraceSource::Unix(path)),
+            "pipe" => Ok(TraceSource::Pipe(path)),
+            "file" => Ok(TraceSource::File(path)),
+            "tail" => Ok(TraceSource::Tail(path)),
+            kind => Err(format!("unknown trace source {kind}")),
+        }
+    }
+}
+
+impl TryFrom<String> for TraceSource {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for TraceSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceSource::Tcp(address) => write!(f, "tcp:{address}"),
+            TraceSource::Unix(path) => write!(f, "unix:{}", path.display()),
+            TraceSource::Pipe(path) => write!(f, "pipe:{}", path.display()),
+            TraceSource::File(path) => write!(f, "file:{}", path.display()),
+            TraceSource::Tail(path) => write!(f, "tail:{}", path.display()),
+        }
+    }
+}
+
+/// Reads a file which is still written, at its end it waits for new data
+/// instead of returning EOF
+struct Tail {
+    file: File,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncRead for Tail {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut self.file).poll_read(cx, buf))?;
+            if buf.filled().len() > filled {
+                return Poll::Ready(Ok(()));
+            }
+            self.delay = Some(Box::pin(tokio::time::sleep(TAIL_INTERVAL)));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Encoding {
+    Text,
+    Binary,
+}
+
+/// Decodes the lines of a trace source, either text in the given format or
+/// the binary encoding if the source starts with its magic. Lines are decoded
+/// in place from the receive buffer.
+pub struct TraceStream {
+    reader: TraceReader,
+    format: TraceFormat,
+    buf: Vec<u8>,
+    /// start of the bytes not decoded yet
+    start: usize,
+    /// None until enough bytes were received to detect the encoding
+    encoding: Option<Encoding>,
+    eof: bool,
+}
+
+impl TraceStream {
+    pub fn new(reader: TraceReader, format: TraceFormat) -> TraceStream {
+        TraceStream {
+            reader,
+            format,
+            buf: Vec::with_capacity(READ_SIZE),
+            start: 0,
+            encoding: None,
+            eof: false,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.encoding == Some(Encoding::Binary)
+    }
+
+    /// Returns the next line or None at the end of the trace. Invalid lines
+    /// are returned as error message, errors of the binary encoding end the
+    /// trace as they cannot be skipped. Cancel safe.
+    pub async fn next(&mut self) -> io::Result<Option<Result<TraceLine, String>>> {
+        loop {
+            let pending = &self.buf[self.start..];
+            match self.encoding {
+                None if pending.starts_with(MAGIC) => {
+                    self.encoding = Some(Encoding::Binary);
+                    self.start += MAGIC.len();
+                    continue;
+                }
+                None if self.eof || !MAGIC.starts_with(pending) => {
+                    self.encoding = Some(Encoding::Text);
+                    continue;
+                }
+                None => {}
+                Some(Encoding::Text) => {
+                    let end = pending.iter().position(|b| *b == b'\n');
+                    if let Some(len) =
+                        end.or((self.eof && !pending.is_empty()).then_some(pending.len()))
+                    {
+                        let line = &pending[..len];
+                        self.start += (len + 1).min(pending.len());
+                        if line.is_empty() {
+                            continue;
+                        }
+                        let parsed = std::str::from_utf8(line)
+                            .map_err(|e| e.to_string())
+                            .and_then(|line| {
+                                self.format
+                                    .parse(line)
+                                    .map_err(|e| format!("{line:?} ({e})"))
+                            });
+                        return Ok(Some(parsed));
+                    }
+                }
+                Some(Encoding::Binary) => match binary_trace::decode(pending) {
+                    Ok(Some((line, len))) => {
+                        self.start += len;
+                        return Ok(Some(Ok(line)));
+                    }
+                    Ok(None) if self.eof && !pending.is_empty() => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "binary trace ended within a frame",
+                        ));
+                    }
+                    Ok(None) => {}
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                },
+            }
+            if self.eof {
+                return Ok(None);
+            }
+
+            // keep the incomplete line and read more
+            self.buf.drain(..self.start);
+            self.start = 0;
+            self.buf.reserve(READ_SIZE);
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pls-source-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_sources() {
+        for text in [
+            "tcp:127.0.0.1:5006",
+            "unix:/tmp/vp.sock",
+            "pipe:/tmp/vp.fifo",
+            "file:trace.log",
+            "tail:/var/log/vp trace.log",
+        ] {
+            let source: TraceSource = text.parse().unwrap();
+            assert_eq!(source.to_string(), text);
+        }
+        assert_eq!(
+            "tcp:[::1]:5006".parse(),
+            Ok(TraceSource::Tcp("[::1]:5006".to_owned()))
+        );
+        assert!("127.0.0.1:5006".parse::<TraceSource>().is_err());
+        assert!("trace.log".parse::<TraceSource>().is_err());
+        let source: TraceSource = serde_json::from_str("\"file:/tmp/a:b\"").unwrap();
+        assert_eq!(source, TraceSource::File(PathBuf::from("/tmp/a:b")));
+        assert!(serde_json::from_str::<TraceSource>("\"http://vp\"").is_err());
+    }
+
+    #[test]
+    fn restricts_paths_to_the_source_dirs() {
+        let dir = temp_dir("dirs");
+        let allowed = dir.join("traces");
+        fs::create_dir_all(&allowed).unwrap();
+        fs::write(allowed.join("trace.log"), "").unwrap();
+        fs::write(dir.join("secret"), "").unwrap();
+        let dirs = [PathBuf::from("/nonexistent"), allowed.clone()];
+        let check = |text: &str| text.parse::<TraceSource>().unwrap().check_dirs(&dirs);
+
+        assert!(check(&format!("file:{}/trace.log", allowed.display())).is_ok());
+        assert!(check(&format!("tail:{}/../traces/trace.log", allowed.display())).is_ok());
+        assert!(check("tcp:127.0.0.1:5006").is_ok());
+        assert!(check(&format!("file:{}/../secret", allowed.display())).is_err());
+        assert!(check(&format!("pipe:{}/missing", allowed.display())).is_err());
+        std::os::unix::fs::symlink(dir.join("secret"), allowed.join("link")).unwrap();
+        assert!(check(&format!("file:{}/link", allowed.display())).is_err());
+        let source = TraceSource::File(allowed.join("trace.log"));
+        assert!(source.check_dirs(&[]).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn tails_files_which_are_still_written() {
+        let dir = temp_dir("tail");
+        let path = dir.join("trace.log");
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(b"I;ram;0;fff\nR;core0;0;10;1;4;1\nR;core0;0;1")
+            .unwrap();
+
+        let source = TraceSource::Tail(path.clone());
+        let mut trace = TraceStream::new(source.open(false).await.unwrap(), TraceFormat::default());
+        assert!(matches!(
+            trace.next().await,
+            Ok(Some(Ok(TraceLine::Module(..))))
+        ));
+        assert!(matches!(
+            trace.next().await,
+            Ok(Some(Ok(TraceLine::Transaction(_))))
+        ));
+        // the incomplete line is kept until the writer finishes it
+        let next = tokio::time::timeout(4 * TAIL_INTERVAL, trace.next()).await;
+        assert!(next.is_err());
+
+        file.write_all(b"4;2;4;2\n").unwrap();
+        let next = tokio::time::timeout(Duration::from_secs(2), trace.next()).await;
+        let Ok(Ok(Some(Ok(TraceLine::Transaction(t))))) = next else {
+            panic!("expected the appended transaction");
+        };
+        assert_eq!((t.address.as_str(), t.data.as_str()), ("14", "2"));
+
+        // a complete file ends instead
+        let source = TraceSource::File(path);
+        let mut trace = TraceStream::new(source.open(false).await.unwrap(), TraceFormat::default());
+        let mut lines = 0;
+        while let Some(line) = trace.next().await.unwrap() {
+            assert!(line.is_ok());
+            lines += 1;
+        }
+        assert_eq!(lines, 3);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,296 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::transaction::{Transaction, TransactionCmd};
+
+/// Initial length of a statistics window in ns of simulation time
+const WINDOW: u64 = 1_000_000;
+/// Number of windows kept for the report. Once a session needs more, the
+/// window length is doubled, so the report always covers the whole session.
+const MAX_WINDOWS: usize = 1000;
+/// Number of addresses in the hottest address list
+const HOT_ADDRESSES: usize = 10;
+/// Distinct addresses which are counted. At twice as many the least accessed
+/// are dropped, so the counts of rarely accessed addresses are approximate.
+const MAX_ADDRESSES: usize = 50_000;
+
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+pub struct Counters {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl Counters {
+    fn add(&mut self, transaction: &Transaction) {
+        let bytes = transaction.data_length as u64;
+        match transaction.action {
+            TransactionCmd::Read => {
+                self.reads += 1;
+                self.bytes_read += bytes;
+            }
+            TransactionCmd::Write => {
+                self.writes += 1;
+                self.bytes_written += bytes;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WindowStats {
+    /// simulation time of the window start in ns
+    pub start: u64,
+    /// accesses and bytes of each module, indexed like `VPLayout.modules`
+    pub accesses: Vec<u64>,
+    pub bytes: Vec<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModuleStats {
+    pub module: String,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InitiatorStats {
+    pub initiator: String,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HotAddress {
+    /// address in hex
+    pub address: String,
+    pub module: Option<String>,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatsReport {
+    pub total: u64,
+    /// length of the windows in ns
+    pub window: u64,
+    pub modules: Vec<ModuleStats>,
+    pub initiators: Vec<InitiatorStats>,
+    pub windows: Vec<WindowStats>,
+    pub hottest: Vec<HotAddress>,
+}
+
+/// Running traffic aggregates of a session
+#[derive(Debug)]
+pub struct TrafficStats {
+    total: u64,
+    /// current window length in ns
+    window: u64,
+    targets: BTreeMap<u8, Counters>,
+    initiators: BTreeMap<String, Counters>,
+    windows: VecDeque<WindowStats>,
+    addresses: HashMap<u64, (u8, Counters)>,
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        TrafficStats {
+            total: 0,
+            window: WINDOW,
+            targets: BTreeMap::new(),
+            initiators: BTreeMap::new(),
+            windows: VecDeque::new(),
+            addresses: HashMap::new(),
+        }
+    }
+}
+
+impl TrafficStats {
+    pub fn apply(&mut self, transaction: &Transaction) {
+        self.total += 1;
+        self.targets
+            .entry(transaction.target)
+            .or_default()
+            .add(transaction);
+        if let Some(counters) = self.initiators.get_mut(&transaction.initiator) {
+            counters.add(transaction);
+        } else {
+            let mut counters = Counters::default();
+            counters.add(transaction);
+            self.initiators
+                .insert(transaction.initiator.clone(), counters);
+        }
+        if let Ok(address) = u64::from_str_radix(&transaction.address, 16) {
+            let entry = self
+                .addresses
+                .entry(address)
+                .or_insert((transaction.target, Counters::default()));
+            entry.1.add(transaction);
+            if self.addresses.len() >= 2 * MAX_ADDRESSES {
+                self.prune_addresses();
+            }
+        }
+
+        let start = transaction.sim_time - transaction.sim_time % self.window;
+        if !matches!(self.windows.back(), Some(w) if w.start >= start) {
+            self.windows.push_back(WindowStats {
+                start,
+                accesses: Vec::new(),
+                bytes: Vec::new(),
+            });
+            while self.windows.len() > MAX_WINDOWS {
+                self.widen_windows();
+            }
+        }
+        // transactions out of order are counted in the latest window
+        let window = self.windows.back_mut().expect("[STATS] no window");
+        let target = transaction.target as usize;
+        if window.accesses.len() <= target {
+            window.accesses.resize(target + 1, 0);
+            window.bytes.resize(target + 1, 0);
+        }
+        window.accesses[target] += 1;
+        window.bytes[target] += transaction.data_length as u64;
+    }
+
+    /// Doubles the window length and merges the windows which now share a start
+    fn widen_windows(&mut self) {
+        self.window *= 2;
+        let mut merged: VecDeque<WindowStats> = VecDeque::with_capacity(MAX_WINDOWS);
+        for mut window in self.windows.drain(..) {
+            window.start -= window.start % self.window;
+            match merged.back_mut() {
+                Some(last) if last.start == window.start => {
+                    for (list, other) in [
+                        (&mut last.accesses, window.accesses),
+                        (&mut last.bytes, window.bytes),
+                    ] {
+                        if list.len() < other.len() {
+                            list.resize(other.len(), 0);
+                        }
+                        list.iter_mut().zip(other).for_each(|(a, b)| *a += b);
+                    }
+                }
+                _ => merged.push_back(window),
+            }
+        }
+        self.windows = merged;
+    }
+
+    /// Keeps the most accessed addresses
+    fn prune_addresses(&mut self) {
+        let mut addresses: Vec<(u64, (u8, Counters))> = self.addresses.drain().collect();
+        addresses.select_nth_unstable_by_key(MAX_ADDRESSES, |(address, (_, c))| {
+            (Reverse(c.reads + c.writes), *address)
+        });
+        addresses.truncate(MAX_ADDRESSES);
+        self.addresses.extend(addresses);
+    }
+
+    /// Summarizes the aggregates with the module names of the layout
+    pub fn report(&self, modules: &[String]) -> StatsReport {
+        let module_name = |target: u8| modules.get(target as usize).cloned();
+
+        let mut hottest: Vec<(&u64, &(u8, Counters))> = self.addresses.iter().collect();
+        let key =
+            |(address, (_, c)): &(&u64, &(u8, Counters))| (Reverse(c.reads + c.writes), **address);
+        if hottest.len() > HOT_ADDRESSES {
+            hottest.select_nth_unstable_by_key(HOT_ADDRESSES, key);
+            hottest.truncate(HOT_ADDRESSES);
+        }
+        hottest.sort_unstable_by_key(key);
+
+        StatsReport {
+            total: self.total,
+            window: self.window,
+            modules: self
+                .targets
+                .iter()
+                .map(|(target, counters)| ModuleStats {
+                    module: module_name(*target).unwrap_or_else(|| target.to_string()),
+                    counters: *counters,
+                })
+                .collect(),
+            initiators: self
+                .initiators
+                .iter()
+                .map(|(initiator, counters)| InitiatorStats {
+                    initiator: initiator.clone(),
+                    counters: *counters,
+                })
+                .collect(),
+            windows: self.windows.iter().cloned().collect(),
+            hottest: hottest
+                .into_iter()
+                .map(|(address, (target, counters))| HotAddress {
+                    address: format!("{address:x}"),
+                    module: module_name(*target),
+                    counters: *counters,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(target: u8, address: u64, time: u64) -> Transaction {
+        format!("R;core0;{target};{address:x};{time};4;0")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn widens_the_windows_to_cover_the_session() {
+        let mut stats = TrafficStats::default();
+        for i in 0..=MAX_WINDOWS as u64 {
+            stats.apply(&access((i % 2) as u8, 0x1000, i * WINDOW));
+        }
+        let report = stats.report(&[]);
+        assert_eq!(report.window, 2 * WINDOW);
+        assert_eq!(report.windows.len(), MAX_WINDOWS / 2 + 1);
+        assert_eq!(report.windows[0].start, 0);
+        assert_eq!(report.windows[0].accesses, [1, 1]);
+        assert_eq!(report.windows[0].bytes, [4, 4]);
+        assert_eq!(report.windows.last().unwrap().accesses, [1]);
+        let counted: u64 = report.windows.iter().flat_map(|w| &w.accesses).sum();
+        assert_eq!(counted, report.total);
+
+        // sparse windows are widened until they fit
+        let mut stats = TrafficStats::default();
+        for i in 0..=MAX_WINDOWS as u64 {
+            stats.apply(&access(0, 0x1000, i * 8 * WINDOW));
+        }
+        let report = stats.report(&[]);
+        assert_eq!(report.window, 16 * WINDOW);
+        assert_eq!(report.windows.len(), MAX_WINDOWS / 2 + 1);
+    }
+
+    #[test]
+    fn keeps_the_hottest_addresses_when_pruning() {
+        let mut stats = TrafficStats::default();
+        for address in 0..10 {
+            for _ in 0..3 {
+                stats.apply(&access(1, address, 0));
+            }
+        }
+        for address in 10..2 * MAX_ADDRESSES as u64 {
+            stats.apply(&access(1, address, 0));
+        }
+        assert!(stats.addresses.len() <= MAX_ADDRESSES + 1);
+        assert!((0..10).all(|a| stats.addresses[&a].1.reads == 3));
+
+        let report = stats.report(&["rom".to_owned(), "ram".to_owned()]);
+        assert_eq!(report.total, 2 * MAX_ADDRESSES as u64 + 20);
+        assert_eq!(report.hottest.len(), HOT_ADDRESSES);
+        assert_eq!(report.hottest[0].address, "0");
+        assert_eq!(report.hottest[9].address, "9");
+        assert_eq!(report.hottest[0].module.as_deref(), Some("ram"));
+        assert_eq!(report.modules[0].module, "ram");
+        assert_eq!(report.initiators[0].counters.reads, report.total);
+    }
+}
@@ -0,0 +1,293 @@
+use futures::lock::Mutex;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::client_handler::State;
+use crate::command::{StepCommand, StepResponse, StepUnit};
+use crate::gdb_proxy::ProxyCmd;
+use crate::virtual_prototype::{VPMode, VP};
+
+/// Packet which executes a single instruction on the VP
+const SINGLE_STEP: &[u8] = b"vCont;s";
+/// Packet which resumes the VP until it is interrupted
+const CONTINUE: &[u8] = b"vCont;c";
+/// Time the trace receiver gets to catch up after the VP stopped
+const TRACE_SETTLE: Duration = Duration::from_millis(2);
+/// Interval in which the trace is checked while the VP runs
+const TRACE_POLL: Duration = Duration::from_millis(1);
+/// Interval in which the interrupt is repeated until the VP stopped, it is
+/// dropped by the proxy if it arrives before the VP was resumed
+const INTERRUPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Sends a packet through the gdb proxy, the reply of the stub is received
+/// on the returned channel
+async fn send(
+    proxy: &mpsc::Sender<ProxyCmd>,
+    packet: &[u8],
+) -> Result<oneshot::Receiver<Vec<u8>>, String> {
+    let (reply, reply_recv) = oneshot::channel();
+    proxy
+        .send(ProxyCmd::Request(packet.to_vec(), reply))
+        .await
+        .map_err(|_| String::from("gdb proxy is not running"))?;
+    Ok(reply_recv)
+}
+
+/// Sends a packet through the gdb proxy and waits for the reply of the stub
+async fn request(proxy: &mpsc::Sender<ProxyCmd>, packet: &[u8]) -> Result<Vec<u8>, String> {
+    send(proxy, packet)
+        .await?
+        .await
+        .map_err(|_| String::from("gdb stub is not available"))
+}
+
+/// Returns the recorded transactions in the given range in trace line format
+async fn collect_transactions(vp: &Mutex<Option<VP>>, start: usize, end: usize) -> Vec<String> {
+    let vp_lock = vp.lock().await;
+    let Some(vp) = vp_lock.as_ref() else {
+        return Vec::new();
+    };
+    let mut steps = vp.steps.lock().await;
+    steps
+        .range(start..end)
+        .iter()
+        .map(|t| t.to_string())
+        .collect()
+}
+
+/// Returns the number of recorded transactions and the latest simulation time
+async fn trace_position(vp: &Mutex<Option<VP>>) -> Result<(usize, u64), String> {
+    let vp_lock = vp.lock().await;
+    let Some(vp) = vp_lock.as_ref() else {
+        return Err(String::from("no VP is running"));
+    };
+    if vp.mode != VPMode::Step {
+        return Err(String::from("VP was not started in debug mode"));
+    }
+    let steps = vp.steps.lock().await;
+    Ok((steps.len(), steps.last_time().unwrap_or(0)))
+}
+
+/// Executes the instructions of the command one by one, returns the number
+/// of executed instructions
+async fn step_instructions(state: &State, steps: u64) -> Result<u32, String> {
+    let steps = u32::try_from(steps)
+        .map_err(|_| format!("at most {} instructions can be stepped", u32::MAX))?;
+    let mut steps_done = 0;
+    while steps_done < steps && !state.gdb.step_cancel.load(Ordering::Relaxed) {
+        let reply = request(&state.gdb.proxy_sender, SINGLE_STEP).await?;
+        steps_done += 1;
+        // the program exited or was terminated
+        if matches!(reply.first(), Some(b'W' | b'X')) {
+            break;
+        }
+    }
+    Ok(steps_done)
+}
+
+/// Lets the VP run and interrupts it once the trace reached the amount of the
+/// command. The VP may run a bit further until the interrupt takes effect.
+async fn run_until(
+    state: &State,
+    cmd: &StepCommand,
+    (start_count, start_time): (usize, u64),
+) -> Result<(), String> {
+    let mut reply = send(&state.gdb.proxy_sender, CONTINUE).await?;
+    let mut poll = tokio::time::interval(TRACE_POLL);
+    let mut interrupted: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            // the stop reply, also sent if the program exited or hit a breakpoint
+            result = &mut reply => {
+                return result
+                    .map(|_| ())
+                    .map_err(|_| String::from("gdb stub is not available"));
+            }
+            _ = poll.tick() => {
+                if interrupted.is_some_and(|at| at.elapsed() < INTERRUPT_RETRY) {
+                    continue;
+                }
+                let (count, time) = trace_position(&state.vp).await?;
+                // the trace only shrinks if the VP was restarted meanwhile
+                let (Some(trans_done), Some(time_done)) =
+                    (count.checked_sub(start_count), time.checked_sub(start_time))
+                else {
+                    return Err(String::from("VP was restarted while stepping"));
+                };
+                let reached = match cmd.unit {
+                    StepUnit::Transactions => trans_done as u64 >= cmd.steps,
+                    _ => time_done >= cmd.steps,
+                };
+                if interrupted.is_some() || reached || state.gdb.step_cancel.load(Ordering::Relaxed) {
+                    let _ = state.gdb.proxy_sender.send(ProxyCmd::Interrupt).await;
+                    interrupted = Some(Instant::now());
+                }
+            }
+        }
+    }
+}
+
+/// Steps the VP by the amount given in the command, measured in its unit.
+/// Instructions are stepped one by one, for the other units the VP runs
+/// until the trace shows that the amount was reached.
+pub async fn step(state: Arc<State>, cmd: &StepCommand) -> Result<StepResponse, String> {
+    if cmd.steps < 1 {
+        return Err(String::from("at least one step is required"));
+    }
+    let Some(_guard) = state.gdb.step_lock.try_lock() else {
+        return Err(String::from("another step is in progress"));
+    };
+    // a cancel request of an earlier step must not stop this one
+    state.gdb.step_cancel.store(false, Ordering::Relaxed);
+
+    let (start_count, start_time) = trace_position(&state.vp).await?;
+    let steps_done = match cmd.unit {
+        StepUnit::Instructions => Some(step_instructions(&state, cmd.steps).await?),
+        _ => {
+            run_until(&state, cmd, (start_count, start_time)).await?;
+            None
+        }
+    };
+
+    tokio::time::sleep(TRACE_SETTLE).await;
+    let (count, _) = trace_position(&state.vp).await?;
+    let Some(trans_count) = count.checked_sub(start_count) else {
+        return Err(String::from("VP was restarted while stepping"));
+    };
+
+    let mut trans = Vec::new();
+    if cmd.batch_trans {
+        trans = collect_transactions(&state.vp, start_count, count).await;
+    }
+
+    Ok(StepResponse {
+        trans,
+        start: start_count,
+        steps_done,
+        trans_count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_handler::tests::{state, vp};
+    use crate::store::TransactionStore;
+
+    /// Session in step mode whose gdb requests are answered by the test
+    fn step_session() -> (
+        Arc<State>,
+        mpsc::Receiver<ProxyCmd>,
+        Arc<Mutex<TransactionStore>>,
+    ) {
+        let mut vp = vp(&["R;core0;0;10;100;4;1"]);
+        vp.mode = VPMode::Step;
+        let steps = vp.steps.clone();
+        let mut state = state(Some(vp));
+        let (proxy, requests) = mpsc::channel(32);
+        state.gdb.proxy_sender = proxy;
+        (Arc::new(state), requests, steps)
+    }
+
+    fn command(steps: u64, unit: StepUnit) -> StepCommand {
+        StepCommand {
+            steps,
+            unit,
+            batch_trans: true,
+            cancel: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn steps_instructions() {
+        let (state, mut requests, _) = step_session();
+        let stub = tokio::spawn(async move {
+            let mut stepped = 0;
+            while let Some(ProxyCmd::Request(packet, reply)) = requests.recv().await {
+                assert_eq!(packet, SINGLE_STEP);
+                stepped += 1;
+                let _ = reply.send(b"S05".to_vec());
+            }
+            stepped
+        });
+        let response = step(state.clone(), &command(5, StepUnit::Instructions))
+            .await
+            .unwrap();
+        assert_eq!(response.steps_done, Some(5));
+        assert_eq!(response.trans_count, 0);
+        drop(state);
+        assert_eq!(stub.await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn runs_until_transactions_and_time_are_reached() {
+        for (steps, unit) in [(3, StepUnit::Transactions), (25, StepUnit::Time)] {
+            let (state, mut requests, store) = step_session();
+            let stub = tokio::spawn(async move {
+                let Some(ProxyCmd::Request(packet, reply)) = requests.recv().await else {
+                    panic!("expected request");
+                };
+                assert_eq!(packet, CONTINUE);
+                // the VP runs until it is interrupted
+                for time in (110..200).step_by(10) {
+                    let line = format!("W;core0;0;10;{time};4;2");
+                    store.lock().await.push(line.parse().unwrap());
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                assert!(matches!(requests.recv().await, Some(ProxyCmd::Interrupt)));
+                let _ = reply.send(b"S02".to_vec());
+            });
+            let response = step(state, &command(steps, unit)).await.unwrap();
+            stub.await.unwrap();
+            assert_eq!(response.steps_done, None);
+            assert_eq!(response.trans_count, 9);
+            assert_eq!(response.trans.len(), 9);
+            assert_eq!(response.trans[0], "W;core0;0;10;110;4;2");
+        }
+    }
+
+    #[tokio::test]
+    async fn cancels_a_running_step() {
+        let (state, mut requests, _) = step_session();
+        let cancel = state.gdb.step_cancel.clone();
+        let stub = tokio::spawn(async move {
+            let Some(ProxyCmd::Request(_, reply)) = requests.recv().await else {
+                panic!("expected request");
+            };
+            // the trace never reaches the time of the step
+            cancel.store(true, Ordering::Relaxed);
+            assert!(matches!(requests.recv().await, Some(ProxyCmd::Interrupt)));
+            let _ = reply.send(b"S02".to_vec());
+        });
+        let response = step(state.clone(), &command(1_000_000, StepUnit::Time))
+            .await
+            .unwrap();
+        stub.await.unwrap();
+        assert_eq!(response.trans_count, 0);
+        assert!(step(state, &command(0, StepUnit::Time)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_without_stub_or_after_restarts() {
+        let (state, requests, _) = step_session();
+        drop(requests);
+        let e = step(state, &command(1, StepUnit::Instructions)).await;
+        assert_eq!(e.unwrap_err(), "gdb proxy is not running");
+
+        let (state, mut requests, store) = step_session();
+        let stub = tokio::spawn(async move {
+            let Some(ProxyCmd::Request(_, mut reply)) = requests.recv().await else {
+                panic!("expected request");
+            };
+            *store.lock().await = TransactionStore::new(0, std::env::temp_dir());
+            // the step gives up and drops the reply channel
+            reply.closed().await;
+        });
+        let e = step(state, &command(1, StepUnit::Transactions)).await;
+        assert_eq!(e.unwrap_err(), "VP was restarted while stepping");
+        stub.await.unwrap();
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::{ControlFlow, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::transaction::Transaction;
+
+/// Number of transactions per segment
+const SEGMENT_SIZE: usize = 16_384;
+
+/// Distinguishes the spill directories of the stores of one server
+static STORE_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+enum SegmentData {
+    Memory(Vec<Transaction>),
+    /// spilled to a file with one JSON object per transaction
+    Disk(PathBuf),
+}
+
+/// Summary of the transactions of a segment, kept in memory when the segment
+/// is spilled so lookups only read segments which can match
+#[derive(Debug, Default)]
+struct SegmentIndex {
+    /// number of transactions of each target
+    targets: HashMap<u8, u32>,
+    /// lowest and highest address
+    addresses: Option<(u64, u64)>,
+}
+
+impl SegmentIndex {
+    fn add(&mut self, transaction: &Transaction) {
+        *self.targets.entry(transaction.target).or_default() += 1;
+        if let Ok(address) = u64::from_str_radix(&transaction.address, 16) {
+            self.addresses = Some(match self.addresses {
+                Some((low, high)) => (low.min(address), high.max(address)),
+                None => (address, address),
+            });
+        }
+    }
+
+    fn has_target(&self, target: u8) -> bool {
+        self.targets.contains_key(&target)
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.addresses
+            .is_some_and(|(low, high)| low <= end && start <= high)
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    /// simulation time of the last transaction
+    end_time: u64,
+    index: SegmentIndex,
+    data: SegmentData,
+}
+
+/// Recorded transactions of a session, split into segments of fixed size.
+/// Once more than `memory_cap` transactions are held in memory the oldest
+/// segments are written to disk and read back on demand. Only a small
+/// summary per segment stays in memory.
+#[derive(Debug)]
+pub struct TransactionStore {
+    segments: Vec<Segment>,
+    len: usize,
+    memory_cap: usize,
+    in_memory: usize,
+    spill_dir: PathBuf,
+    /// last segment read back from disk
+    cache: Option<(usize, Vec<Transaction>)>,
+}
+
+impl Drop for TransactionStore {
+    fn drop(&mut self) {
+        if self.spill_dir.exists() {
+            let _ = fs::remove_dir_all(&self.spill_dir);
+        }
+    }
+}
+
+/// Runs file IO of the store without stalling the other tasks of the worker
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+fn write_segment(path: &Path, transactions: &[Transaction]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for transaction in transactions {
+        serde_json::to_writer(&mut writer, transaction)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Reads a spilled segment, fails on any invalid line as the positions of
+/// the following transactions would be wrong
+fn read_segment(path: &Path) -> io::Result<Vec<Transaction>> {
+    let mut transactions = Vec::with_capacity(SEGMENT_SIZE);
+    for line in BufReader::new(File::open(path)?).lines() {
+        transactions.push(serde_json::from_str(&line?)?);
+    }
+    Ok(transactions)
+}
+
+impl TransactionStore {
+    /// Creates a store which keeps at most `memory_cap` transactions in
+    /// memory and spills the rest to a new directory in `spill_dir`
+    pub fn new(memory_cap: usize, spill_dir: PathBuf) -> TransactionStore {
+        let id = STORE_ID.fetch_add(1, Ordering::Relaxed);
+        TransactionStore {
+            segments: Vec::new(),
+            len: 0,
+            // the segment currently written is always kept in memory
+            memory_cap: memory_cap.max(SEGMENT_SIZE),
+            in_memory: 0,
+            spill_dir: spill_dir.join(format!("pls-{}-{id}", std::process::id())),
+            cache: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Simulation time of the latest transaction
+    pub fn last_time(&self) -> Option<u64> {
+        self.segments.last().map(|s| s.end_time)
+    }
+
+    pub fn push(&mut self, transaction: Transaction) {
+        if self.len == self.segments.len() * SEGMENT_SIZE {
+            self.segments.push(Segment {
+                end_time: transaction.sim_time,
+                index: SegmentIndex::default(),
+                data: SegmentData::Memory(Vec::with_capacity(SEGMENT_SIZE)),
+            });
+        }
+        let segment = self.segments.last_mut().expect("[STORE] no open segment");
+        segment.end_time = transaction.sim_time;
+        segment.index.add(&transaction);
+        if let SegmentData::Memory(transactions) = &mut segment.data {
+            transactions.push(transaction);
+        }
+        self.len += 1;
+        self.in_memory += 1;
+
+        if self.in_memory > self.memory_cap {
+            self.spill();
+        }
+    }
+
+    /// Writes the oldest segment held in memory to disk
+    fn spill(&mut self) {
+        let last = self.segments.len() - 1;
+        let Some(index) = self.segments[..last]
+            .iter()
+            .position(|s| matches!(s.data, SegmentData::Memory(_)))
+        else {
+            return;
+        };
+        let path = self.spill_dir.join(format!("{index}.trace"));
+        let SegmentData::Memory(transactions) = &self.segments[index].data else {
+            return;
+        };
+
+        let written = blocking(|| {
+            fs::create_dir_all(&self.spill_dir).and_then(|_| write_segment(&path, transactions))
+        });
+        match written {
+            Ok(()) => {
+                self.in_memory -= transactions.len();
+                self.segments[index].data = SegmentData::Disk(path);
+            }
+            Err(e) => {
+                println!(
+                    "[STORE] could not spill to {} ({e}), keeping transactions in memory",
+                    self.spill_dir.display()
+                );
+                self.memory_cap = usize::MAX;
+            }
+        }
+    }
+
+    /// Returns the transactions of a segment, reading them back from disk if needed
+    fn segment(&mut self, index: usize) -> &[Transaction] {
+        if let SegmentData::Disk(path) = &self.segments[index].data {
+            if !matches!(&self.cache, Some((cached, _)) if *cached == index) {
+                // an unreadable segment is empty rather than shifting positions
+                let transactions = blocking(|| read_segment(path)).unwrap_or_else(|e| {
+                    println!("[STORE] could not read {} ({e})", path.display());
+                    Vec::new()
+                });
+                self.cache = Some((index, transactions));
+            }
+        }
+        match (&self.segments[index].data, &self.cache) {
+            (SegmentData::Memory(transactions), _) => transactions,
+            (SegmentData::Disk(_), Some((_, transactions))) => transactions,
+            (SegmentData::Disk(_), None) => &[],
+        }
+    }
+
+    pub fn get(&mut self, index: usize) -> Option<&Transaction> {
+        if index >= self.len {
+            return None;
+        }
+        self.segment(index / SEGMENT_SIZE).get(index % SEGMENT_SIZE)
+    }
+
+    /// Calls `f` with each transaction from `start` on until it breaks
+    pub fn scan<F>(&mut self, start: usize, mut f: F)
+    where
+        F: FnMut(usize, &Transaction) -> ControlFlow<()>,
+    {
+        let mut index = start;
+        while index < self.len {
+            let segment = index / SEGMENT_SIZE;
+            let offset = index % SEGMENT_SIZE;
+            let transactions = self.segment(segment);
+            if transactions.len() <= offset {
+                return;
+            }
+            for transaction in &transactions[offset..] {
+                if f(index, transaction).is_break() {
+                    return;
+                }
+                index += 1;
+            }
+        }
+    }
+
+    /// Returns a copy of the transactions in the given range
+    pub fn range(&mut self, range: Range<usize>) -> Vec<Transaction> {
+        let mut transactions = Vec::with_capacity(range.len());
+        self.scan(range.start, |index, transaction| {
+            if index >= range.end {
+                return ControlFlow::Break(());
+            }
+            transactions.push(transaction.clone());
+            ControlFlow::Continue(())
+        });
+        transactions
+    }
+
+    /// Returns the position of the first transaction at or after `time`,
+    /// the length of the store if there is none
+    pub fn position_at(&mut self, time: u64) -> usize {
+        let segment = self.segments.partition_point(|s| s.end_time < time);
+        if segment == self.segments.len() {
+            return self.len;
+        }
+        let offset = self.segment(segment).partition_point(|t| t.sim_time < time);
+        segment * SEGMENT_SIZE + offset
+    }
+
+    /// Positions of the matching transactions in ascending order, only the
+    /// segments accepted by `may_match` are read
+    fn positions<S, F>(&mut self, may_match: S, matches: F) -> Vec<usize>
+    where
+        S: Fn(&SegmentIndex) -> bool,
+        F: Fn(&Transaction) -> bool,
+    {
+        let mut positions = Vec::new();
+        for segment in 0..self.segments.len() {
+            if !may_match(&self.segments[segment].index) {
+                continue;
+            }
+            let first = segment * SEGMENT_SIZE;
+            let transactions = self.segment(segment).iter().enumerate();
+            positions.extend(
+                transactions
+                    .filter(|(_, t)| matches(t))
+                    .map(|(i, _)| first + i),
+            );
+        }
+        positions
+    }
+
+    /// Positions of all transactions of a target in ascending order
+    pub fn target_positions(&mut self, target: u8) -> Vec<usize> {
+        self.positions(|s| s.has_target(target), |t| t.target == target)
+    }
+
+    /// Positions of all transactions of an address range (inclusive) in
+    /// ascending order
+    pub fn address_positions(&mut self, start: u64, end: u64) -> Vec<usize> {
+        self.positions(
+            |s| s.overlaps(start, end),
+            |t| u64::from_str_radix(&t.address, 16).is_ok_and(|a| start <= a && a <= end),
+        )
+    }
+
+    /// Position of the latest transaction of a target up to and including `position`
+    pub fn last_of_target(&mut self, target: u8, position: usize) -> Option<usize> {
+        let last = position.min(self.len.checked_sub(1)?);
+        for segment in (0..=last / SEGMENT_SIZE).rev() {
+            if !self.segments[segment].index.has_target(target) {
+                continue;
+            }
+            let first = segment * SEGMENT_SIZE;
+            let transactions = self.segment(segment);
+            let end = transactions.len().min(last + 1 - first);
+            if let Some(offset) = transactions[..end].iter().rposition(|t| t.target == target) {
+                return Some(first + offset);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store with three spilled segments and one in memory
+    fn spilled_store() -> TransactionStore {
+        let mut store = TransactionStore::new(0, std::env::temp_dir());
+        for i in 0..3 * SEGMENT_SIZE + 5 {
+            let target = u8::from(i % 100 == 0);
+            let line = format!("W;core0;{target};{:x};{};4;{i:x}", 4 * i, 2 * i);
+            store.push(line.parse().unwrap());
+        }
+        store
+    }
+
+    #[test]
+    fn reads_back_spilled_segments() {
+        let mut store = spilled_store();
+        assert_eq!(store.len(), 3 * SEGMENT_SIZE + 5);
+        assert!(matches!(store.segments[0].data, SegmentData::Disk(_)));
+        assert!(matches!(store.segments[3].data, SegmentData::Memory(_)));
+        assert!(store.in_memory <= store.memory_cap);
+
+        for index in [0, 1, SEGMENT_SIZE + 7, 3 * SEGMENT_SIZE + 4] {
+            assert_eq!(store.get(index).unwrap().data, format!("{index:x}"));
+        }
+        assert!(store.get(3 * SEGMENT_SIZE + 5).is_none());
+
+        let range = store.range(SEGMENT_SIZE - 2..SEGMENT_SIZE + 2);
+        let times: Vec<u64> = range.iter().map(|t| t.sim_time).collect();
+        let first = 2 * SEGMENT_SIZE as u64;
+        assert_eq!(times, vec![first - 4, first - 2, first, first + 2]);
+
+        assert_eq!(
+            store.position_at(2 * SEGMENT_SIZE as u64 + 1),
+            SEGMENT_SIZE + 1
+        );
+        assert_eq!(store.position_at(u64::MAX), store.len());
+        assert_eq!(store.last_time(), Some(2 * (store.len() as u64 - 1)));
+    }
+
+    #[test]
+    fn looks_up_positions() {
+        let mut store = spilled_store();
+        let targets = store.target_positions(1);
+        assert_eq!(targets.len(), (store.len() - 1) / 100 + 1);
+        assert!(targets.iter().all(|i| i % 100 == 0));
+        assert_eq!(store.last_of_target(1, 250), Some(200));
+        assert_eq!(store.last_of_target(2, 250), None);
+
+        let start = 4 * SEGMENT_SIZE as u64 - 8;
+        let positions = store.address_positions(start, start + 8);
+        assert_eq!(
+            positions,
+            vec![SEGMENT_SIZE - 2, SEGMENT_SIZE - 1, SEGMENT_SIZE]
+        );
+    }
+
+    #[test]
+    fn removes_spilled_segments() {
+        let store = spilled_store();
+        let dir = store.spill_dir.clone();
+        assert!(dir.exists());
+        drop(store);
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spills_inside_the_runtime() {
+        let mut store = tokio::spawn(async { spilled_store() }).await.unwrap();
+        assert_eq!(store.get(5).unwrap().data, "5");
+        let mut store = tokio::task::spawn_blocking(move || {
+            store.cache = None;
+            assert_eq!(store.get(6).unwrap().data, "6");
+            store
+        })
+        .await
+        .unwrap();
+        assert_eq!(store.get(7).unwrap().data, "7");
+    }
+}
@@ -0,0 +1,326 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::hex;
+use crate::transaction::{Transaction, TransactionCmd};
+
+/// Fields every transaction line must contain
+const REQUIRED: [&str; 6] = ["action", "initiator", "target", "address", "time", "length"];
+
+/// Line of a trace, either a module of the layout or a transaction
+pub enum TraceLine {
+    /// name, start and end address in hex
+    Module(String, String, String),
+    Transaction(Transaction),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Column {
+    Action,
+    Initiator,
+    Target(u32),
+    Address(u32),
+    Time(u32),
+    Length(u32),
+    Data(u32),
+    /// field unknown to PLS, kept as named attribute
+    Attribute(String),
+}
+
+/// Format descriptor as written in the options
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct Descriptor {
+    separator: String,
+    /// first field of the layout lines `<prefix>;<name>;<start>;<end>`
+    layout_prefix: String,
+    /// names of the fields of a transaction line in order
+    fields: Vec<String>,
+    /// radix of numeric fields by name, address and data default to 16, others to 10
+    radix: HashMap<String, u32>,
+    /// offer the binary encoding on socket sources, only for VPs which read
+    /// the offer, others reset the connection on close and lose its end
+    binary: bool,
+}
+
+impl Default for Descriptor {
+    fn default() -> Self {
+        Descriptor {
+            separator: String::from(";"),
+            layout_prefix: String::from("I"),
+            fields: [
+                "action",
+                "initiator",
+                "target",
+                "address",
+                "time",
+                "length",
+                "data",
+            ]
+            .map(String::from)
+            .to_vec(),
+            radix: HashMap::new(),
+            binary: false,
+        }
+    }
+}
+
+/// Trace line format of a VP. The default is the format of the RISC-V VP++,
+/// `action;initiator;target;address;time;length[;data]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Descriptor")]
+pub struct TraceFormat {
+    separator: String,
+    layout_prefix: String,
+    columns: Vec<Column>,
+    address_radix: u32,
+    /// number of fields up to the last required one, later fields may be missing
+    required: usize,
+    pub binary: bool,
+}
+
+impl Default for TraceFormat {
+    fn default() -> Self {
+        TraceFormat::try_from(Descriptor::default()).expect("[FORMAT] invalid default format")
+    }
+}
+
+impl TryFrom<Descriptor> for TraceFormat {
+    type Error = String;
+
+    fn try_from(descriptor: Descriptor) -> Result<Self, Self::Error> {
+        if descriptor.separator.is_empty() {
+            return Err(String::from("separator of trace format is empty"));
+        }
+        let radix = |name: &str| {
+            let default = if name == "address" || name == "data" {
+                16
+            } else {
+                10
+            };
+            let radix = descriptor.radix.get(name).copied().unwrap_or(default);
+            match radix {
+                2..=36 => Ok(radix),
+                _ => Err(format!("radix {radix} of {name} is not in 2..=36")),
+            }
+        };
+
+        let mut columns = Vec::new();
+        for (position, name) in descriptor.fields.iter().enumerate() {
+            if descriptor.fields[..position].contains(name) {
+                return Err(format!("field {name} appears twice in trace format"));
+            }
+            columns.push(match name.as_str() {
+                "action" => Column::Action,
+                "initiator" => Column::Initiator,
+                "target" => Column::Target(radix(name)?),
+                "address" => Column::Address(radix(name)?),
+                "time" => Column::Time(radix(name)?),
+                "length" => Column::Length(radix(name)?),
+                "data" => Column::Data(radix(name)?),
+                _ => Column::Attribute(name.clone()),
+            });
+        }
+
+        let mut required = 0;
+        for name in REQUIRED {
+            let Some(position) = descriptor.fields.iter().position(|f| f == name) else {
+                return Err(format!("trace format has no {name} field"));
+            };
+            required = required.max(position + 1);
+        }
+
+        Ok(TraceFormat {
+            separator: descriptor.separator,
+            layout_prefix: descriptor.layout_prefix,
+            columns,
+            address_radix: radix("address")?,
+            required,
+            binary: descriptor.binary,
+        })
+    }
+}
+
+fn number(value: &str, radix: u32) -> Result<u64, String> {
+    if radix == 16 {
+        return hex::parse(value).map_err(|e| format!("invalid number {e}"));
+    }
+    u64::from_str_radix(value, radix).map_err(|e| format!("invalid number {value} ({e})"))
+}
+
+/// Addresses are kept in hex without leading zeros, like binary traces
+fn address(value: &str, radix: u32) -> Result<String, String> {
+    Ok(format!("{:x}", number(value, radix)?))
+}
+
+/// Data is kept in hex without leading zeros, like binary traces. It may be
+/// wider than 64 bits, e.g. on wide buses, and is empty if the line has none.
+fn data(value: &str, radix: u32) -> Result<String, String> {
+    if value.is_empty() {
+        return Ok(String::new());
+    }
+    if radix != 16 {
+        return Ok(format!("{:x}", number(value, radix)?));
+    }
+    let digits = value.trim_start_matches("0x");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid number {value}"));
+    }
+    let digits = digits.trim_start_matches('0');
+    Ok(if digits.is_empty() { "0" } else { digits }.to_ascii_lowercase())
+}
+
+impl TraceFormat {
+    pub fn parse(&self, line: &str) -> Result<TraceLine, String> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        let fields: Vec<&str> = line.split(self.separator.as_str()).collect();
+
+        // like PLS always did, any line of 4 fields which cannot be a
+        // transaction is a layout line as well
+        let layout = fields.len() == 4 && fields.len() < self.required;
+        if fields[0] == self.layout_prefix || layout {
+            let [_, name, start, end] = fields[..] else {
+                return Err(String::from("layout line does not have 4 fields"));
+            };
+            return Ok(TraceLine::Module(
+                name.to_owned(),
+                address(start, self.address_radix)?,
+                address(end, self.address_radix)?,
+            ));
+        }
+        if fields.len() < self.required {
+            let n = self.required;
+            return Err(format!("transaction line has less than {n} fields"));
+        }
+
+        let mut transaction = Transaction {
+            sim_time: 0,
+            action: TransactionCmd::Read,
+            initiator: String::new(),
+            target: 0,
+            address: String::new(),
+            data_length: 0,
+            data: String::new(),
+            attributes: BTreeMap::new(),
+        };
+        // fields beyond the descriptor are ignored
+        for (column, value) in self.columns.iter().zip(fields) {
+            match column {
+                Column::Action => {
+                    transaction.action = TransactionCmd::from_str(value)
+                        .map_err(|_| format!("invalid action {value}"))?
+                }
+                Column::Initiator => transaction.initiator = value.to_owned(),
+                Column::Target(radix) => {
+                    transaction.target = u8::try_from(number(value, *radix)?)
+                        .map_err(|_| format!("target {value} is out of range"))?
+                }
+                Column::Address(radix) => transaction.address = address(value, *radix)?,
+                Column::Time(radix) => transaction.sim_time = number(value, *radix)?,
+                Column::Length(radix) => {
+                    transaction.data_length = u8::try_from(number(value, *radix)?)
+                        .map_err(|_| format!("length {value} is out of range"))?
+                }
+                Column::Data(radix) => transaction.data = data(value, *radix)?,
+                Column::Attribute(name) => {
+                    transaction
+                        .attributes
+                        .insert(name.clone(), value.to_owned());
+                }
+            }
+        }
+        Ok(TraceLine::Transaction(transaction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(format: &TraceFormat, line: &str) -> Transaction {
+        match format.parse(line) {
+            Ok(TraceLine::Transaction(transaction)) => transaction,
+            Ok(TraceLine::Module(..)) => panic!("{line} was read as layout line"),
+            Err(e) => panic!("{line} ({e})"),
+        }
+    }
+
+    fn module(format: &TraceFormat, line: &str) -> (String, String, String) {
+        match format.parse(line) {
+            Ok(TraceLine::Module(name, start, end)) => (name, start, end),
+            Ok(TraceLine::Transaction(_)) => panic!("{line} was read as transaction"),
+            Err(e) => panic!("{line} ({e})"),
+        }
+    }
+
+    #[test]
+    fn parses_riscv_vp_lines() {
+        let format = TraceFormat::default();
+        let t = transaction(&format, "R;core0;1;10013004;10;4;1\n");
+        assert_eq!(t.to_string(), "R;core0;1;10013004;10;4;1");
+        let t = transaction(
+            &format,
+            "W;core0;0;80000000;20;16;112233445566778899aabbccddeeff00",
+        );
+        assert_eq!(t.data, "112233445566778899aabbccddeeff00");
+        assert_eq!(transaction(&format, "R;core0;1;10;10;4").data, "");
+        // leading zeros are dropped like in binary traces
+        let t = transaction(&format, "R;;1;0x00001000;10;4;00AB");
+        assert_eq!(
+            (t.address.as_str(), t.data.as_str(), t.initiator.as_str()),
+            ("1000", "ab", "")
+        );
+        assert_eq!(transaction(&format, "R;core0;1;10;10;4;000").data, "0");
+        assert!(format.parse("R;core0;1;112233445566778899;10;4;1").is_err());
+
+        let dram = (
+            String::from("dram"),
+            String::from("80000000"),
+            String::from("8fffffff"),
+        );
+        assert_eq!(module(&format, "I;dram;80000000;8fffffff"), dram);
+        // any other line of 4 fields is a layout line as well
+        assert_eq!(module(&format, "X;dram;80000000;8fffffff"), dram);
+
+        assert!(format.parse("R;core0;1;10;10").is_err());
+        assert!(format.parse("I;dram;80000000").is_err());
+        assert!(format.parse("R;core0;1;1001300g;10;4;1").is_err());
+        assert!(format.parse("X;core0;1;10013004;10;4;1").is_err());
+    }
+
+    #[test]
+    fn parses_custom_formats() {
+        let format: TraceFormat = serde_json::from_str(
+            r#"{
+                "separator": ",",
+                "layout_prefix": "M",
+                "fields": ["time", "action", "initiator", "target", "address", "length", "data", "burst"],
+                "radix": { "time": 16, "data": 10 }
+            }"#,
+        )
+        .unwrap();
+        let t = transaction(&format, "ff,W,dma,2,0x1000,4,255,incr");
+        assert_eq!(t.sim_time, 255);
+        assert_eq!(t.address, "1000");
+        assert_eq!(t.data, "ff");
+        assert_eq!(t.attributes.get("burst").map(String::as_str), Some("incr"));
+        // the layout line of this format has 4 fields, like a short transaction line
+        assert_eq!(module(&format, "M,uart0,0x10,0x1f").0, "uart0");
+        assert_eq!(module(&format, "N,uart0,0x10,0x1f").0, "uart0");
+    }
+
+    #[test]
+    fn rejects_invalid_formats() {
+        let parse = |json: &str| serde_json::from_str::<TraceFormat>(json).map(|_| ());
+        assert!(parse(r#"{ "separator": "" }"#).is_err());
+        assert!(
+            parse(r#"{ "fields": ["action", "initiator", "target", "address", "time"] }"#).is_err()
+        );
+        assert!(parse(r#"{ "radix": { "time": 1 } }"#).is_err());
+        assert!(parse(
+            r#"{ "fields": ["action", "initiator", "target", "address", "time", "length", "time"] }"#
+        )
+        .is_err());
+    }
+}
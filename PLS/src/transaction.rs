@@ -1,8 +1,9 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TransactionCmd {
     Read,
     Write,
@@ -30,8 +31,8 @@ impl Display for TransactionCmd {
 }
 
 impl TransactionCmd {
-    fn to_byte(&self) -> u8 {
-        if *self == TransactionCmd::Read {
+    fn to_byte(self) -> u8 {
+        if self == TransactionCmd::Read {
             return 0;
         }
         1
@@ -43,7 +44,7 @@ pub trait ToBinary {
     fn to_binary(&self) -> [u8; Transaction::BIN_SIZE];
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub sim_time: u64,
     pub action: TransactionCmd,
@@ -52,29 +53,66 @@ pub struct Transaction {
     pub address: String,
     pub data_length: u8,
     pub data: String,
+    /// fields of custom trace formats which are unknown to PLS
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
 }
 
+/// Data wider than 64 bits is sent with its low 64 bits. An empty initiator
+/// and missing or invalid addresses and data are sent as 0.
 impl ToBinary for Transaction {
     fn to_binary(&self) -> [u8; Transaction::BIN_SIZE] {
         let mut arr = [0; Transaction::BIN_SIZE];
+        let low_digits = self.data.len().saturating_sub(16);
+        let data_value = self
+            .data
+            .get(low_digits..)
+            .and_then(|digits| u64::from_str_radix(digits, 16).ok());
         let mut data: Vec<u8> = Vec::new();
         data.extend_from_slice(&self.sim_time.to_le_bytes());
         data.push(self.action.to_byte());
-        data.push(self.initiator.chars().last().unwrap() as u8);
+        data.push(self.initiator.chars().last().map_or(0, |c| c as u8));
         data.extend_from_slice(&self.target.to_le_bytes());
-        data.extend_from_slice(&(u64::from_str_radix(&self.address, 16).unwrap()).to_le_bytes());
+        let address = u64::from_str_radix(&self.address, 16).unwrap_or(0);
+        data.extend_from_slice(&address.to_le_bytes());
         data.push(self.data_length);
-        data.extend_from_slice(&(u64::from_str_radix(&self.data, 16).unwrap()).to_le_bytes());
+        data.extend_from_slice(&data_value.unwrap_or(0).to_le_bytes());
         arr.copy_from_slice(data.as_slice()); // Panics if slice sizes do not match
         arr
     }
 }
 
+/// Formats the transaction in the trace line format of the VP, attributes
+/// are appended as `name=value`
+impl Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            TransactionCmd::Read => "R",
+            TransactionCmd::Write => "W",
+        };
+        write!(
+            f,
+            "{};{};{};{};{};{};{}",
+            action,
+            self.initiator,
+            self.target,
+            self.address,
+            self.sim_time,
+            self.data_length,
+            self.data
+        )?;
+        for (name, value) in self.attributes.iter() {
+            write!(f, ";{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
 impl FromStr for Transaction {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut data: Vec<&str> = s.split(';').collect();
+        let mut data: Vec<&str> = s.trim_end_matches('\n').split(';').collect();
 
         if data.len() < 6 || data[0].starts_with("I") {
             return Err(());
@@ -85,18 +123,18 @@ impl FromStr for Transaction {
         }
 
         Ok(Transaction {
-            action: TransactionCmd::from_str(data[0]).expect("[VP] could not parse action"),
+            action: TransactionCmd::from_str(data[0])?,
             initiator: data[1].to_owned(),
-            target: data[2].parse::<u8>().expect("[VP] could not parse target"),
+            target: data[2].parse::<u8>().map_err(|_| ())?,
             address: data[3].to_owned(),
-            sim_time: data[4]
-                .parse::<u64>()
-                .expect("[VP] could not parse simulation time"),
-            data_length: data[5]
-                .trim_end_matches("\n")
-                .parse::<u8>()
-                .expect("[VP] could not parse data length"),
-            data: data[6].trim_end_matches("\n").to_owned(),
+            sim_time: data[4].parse::<u64>().map_err(|_| ())?,
+            data_length: data[5].parse::<u8>().map_err(|_| ())?,
+            data: data[6].to_owned(),
+            attributes: data[7..]
+                .iter()
+                .filter_map(|field| field.split_once('='))
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
         })
     }
 }
@@ -1,21 +1,33 @@
 use futures::lock::Mutex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::process::{Child, Command};
-use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Sender;
 use tokio::time::{self};
 
-use crate::transaction::Transaction;
+use crate::assertions::Violation;
+use crate::ranges::{LayoutWarning, RangeChecker};
+use crate::scripting::ScriptEvent;
+use crate::shadow::ShadowMemory;
+use crate::sink::{Broadcaster, StatsSink, StoreSink, TraceState, TransactionSink};
+use crate::source::{TraceReader, TraceSource, TraceStream};
+use crate::stats::TrafficStats;
+use crate::store::TransactionStore;
+use crate::trace_format::{TraceFormat, TraceLine};
 
 #[derive(PartialEq, Clone)]
 pub enum VPCtrlMsg {
     RecvTransaction,
     RecvModule,
+    Stats,
+    /// the trace ended, the VP closed the connection or was stopped
+    Finished,
+    Violation(Violation),
+    Warning(LayoutWarning),
+    Script(ScriptEvent),
     Shutdown,
 }
 
@@ -25,7 +37,7 @@ pub enum VPMode {
     Step,
 }
 
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct VPLayout {
     pub modules: Vec<String>,
     pub start_addrs: Vec<String>,
@@ -34,14 +46,16 @@ pub struct VPLayout {
 
 #[derive(Debug)]
 pub struct VP {
-    pub subproc: Child,
+    /// None if the VP was started outside of PLS and attached to
+    pub subproc: Option<Child>,
     pub gdbgui: Option<Child>,
     pub channel: Arc<Sender<VPCtrlMsg>>,
     pub is_running: bool,
-    pub steps: Arc<Mutex<Vec<Transaction>>>,
+    pub steps: Arc<Mutex<TransactionStore>>,
     pub arch: Arc<Mutex<VPLayout>>,
+    pub shadow: Arc<Mutex<ShadowMemory>>,
+    pub stats: Arc<Mutex<TrafficStats>>,
     pub mode: VPMode,
-    pub tcount: usize,
 }
 
 impl Drop for VP {
@@ -51,13 +65,17 @@ impl Drop for VP {
 }
 
 impl VP {
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
         vp_path: String,
         bin_path: String,
         mut args: Vec<String>,
         mode: VPMode,
         channel: Arc<Sender<VPCtrlMsg>>,
-        port: Option<u16>,
+        source: TraceSource,
+        format: TraceFormat,
+        store: TransactionStore,
+        sinks: Vec<Box<dyn TransactionSink>>,
     ) -> Result<VP, ()> {
         args.push(bin_path);
 
@@ -74,14 +92,29 @@ impl VP {
         };
 
         if let Ok(subproc) = vp.spawn() {
-            return connect_vp(subproc, mode, channel, port.unwrap_or(5006)).await;
+            let vp_process = Some(subproc);
+            return connect_vp(vp_process, mode, channel, source, format, store, sinks).await;
         }
 
         Err(())
     }
 
+    /// Connects to the trace of a VP which is already running, e.g. under
+    /// valgrind or on another machine. Stopping the session only disconnects.
+    pub async fn attach(
+        mode: VPMode,
+        channel: Arc<Sender<VPCtrlMsg>>,
+        source: TraceSource,
+        format: TraceFormat,
+        store: TransactionStore,
+        sinks: Vec<Box<dyn TransactionSink>>,
+    ) -> Result<VP, ()> {
+        println!("[VP] {mode:?} attaching to {source}");
+        connect_vp(None, mode, channel, source, format, store, sinks).await
+    }
+
     pub fn stop(&mut self) -> bool {
-        self.is_running = false;
+        let was_running = std::mem::replace(&mut self.is_running, false);
         let mut receiver_shutdown = false;
         if let Ok(received) = self.channel.send(VPCtrlMsg::Shutdown) {
             receiver_shutdown = received > 0;
@@ -93,39 +126,61 @@ impl VP {
                 println!("[VP] gdbgui with PID [{}] maybe alive", gdbgui.id());
             }
         }
-        if self.subproc.kill().is_ok() && receiver_shutdown {
-            println!("[VP] VP with PID [{}] was killed", self.subproc.id());
+        let Some(subproc) = self.subproc.as_mut() else {
+            if was_running {
+                println!("[VP] detached from VP");
+            }
+            return true;
+        };
+        if subproc.kill().is_ok() && receiver_shutdown {
+            println!("[VP] VP with PID [{}] was killed", subproc.id());
             true
         } else {
-            println!("[VP] VP with PID [{}] maybe alive", self.subproc.id());
+            println!("[VP] VP with PID [{}] maybe alive", subproc.id());
             false
         }
     }
 }
 
 async fn connect_vp(
-    vp_process: Child,
+    vp_process: Option<Child>,
     mode: VPMode,
     channel: Arc<Sender<VPCtrlMsg>>,
-    port: u16,
+    source: TraceSource,
+    format: TraceFormat,
+    store: TransactionStore,
+    extra_sinks: Vec<Box<dyn TransactionSink>>,
 ) -> Result<VP, ()> {
     // sleep to let the VP startup
-    let dur = time::Duration::from_millis(2000);
-    thread::sleep(dur);
+    if vp_process.is_some() {
+        let dur = time::Duration::from_millis(2000);
+        thread::sleep(dur);
+    }
 
-    match TcpStream::connect(("127.0.0.1", port)).await {
+    match source.open(format.binary).await {
         Ok(stream) => {
-            println!("[VP] listening on bus dump");
+            println!("[VP] listening on {source}");
 
-            let responses = Arc::new(Mutex::new(Vec::<Transaction>::new()));
+            let responses = Arc::new(Mutex::new(store));
             let arch = Arc::new(Mutex::new(VPLayout::default()));
-            let cp = responses.clone();
+            let shadow = Arc::new(Mutex::new(ShadowMemory::default()));
+            let stats = Arc::new(Mutex::new(TrafficStats::default()));
             let ac = arch.clone();
+            let sc = shadow.clone();
             let ch = channel.clone();
 
+            // clients are notified last, once all other sinks processed a transaction
+            let mut sinks: Vec<Box<dyn TransactionSink>> = vec![
+                Box::new(RangeChecker::new(channel.clone())),
+                Box::new(StoreSink::new(responses.clone())),
+                Box::new(StatsSink::new(stats.clone(), channel.clone())),
+            ];
+            sinks.extend(extra_sinks);
+            sinks.push(Box::new(Broadcaster::new(channel.clone())));
+
             // spawn task for receiving Transactions
             tokio::spawn(async move {
-                recv_loop(stream, cp, ac, ch.clone()).await;
+                recv_loop(stream, format, ac, sc, sinks, ch).await;
             });
 
             Ok(VP {
@@ -134,29 +189,47 @@ async fn connect_vp(
                 is_running: true,
                 steps: responses,
                 arch,
+                shadow,
+                stats,
                 mode,
-                tcount: 0,
                 gdbgui: None,
             })
         }
-        Err(_) => {
-            println!("[VP] Could not connect to VP trace port (check args)");
+        Err(e) => {
+            println!("[VP] Could not open trace source {source} ({e}), check args");
             Err(())
         }
     }
 }
 
+/// Number of invalid trace lines which are logged
+const MAX_INVALID_LINES: usize = 10;
+
+/// Position in the trace and the layout as seen by the sinks
+struct Receiver {
+    layout: VPLayout,
+    parsing_layout: bool,
+    count: usize,
+    invalid: usize,
+}
+
 async fn recv_loop(
-    mut stream: TcpStream,
-    mut responses: Arc<Mutex<Vec<Transaction>>>,
-    mut layout: Arc<Mutex<VPLayout>>,
+    stream: TraceReader,
+    format: TraceFormat,
+    layout: Arc<Mutex<VPLayout>>,
+    shadow: Arc<Mutex<ShadowMemory>>,
+    mut sinks: Vec<Box<dyn TransactionSink>>,
     channel: Arc<Sender<VPCtrlMsg>>,
 ) {
-    let (sock_rx, _) = stream.split();
-    let mut socket_recv = BufReader::new(sock_rx).lines();
-    let mut layout_parsed = false;
+    let mut trace = TraceStream::new(stream, format);
+    let mut binary = false;
+    let mut receiver = Receiver {
+        layout: VPLayout::default(),
+        parsing_layout: false,
+        count: 0,
+        invalid: 0,
+    };
     let mut interval = time::interval(Duration::from_millis(10));
-    let mut status = (false, false);
     let mut cmd_recv = channel.subscribe();
 
     loop {
@@ -165,64 +238,84 @@ async fn recv_loop(
             // This block handles shutdown commands from the server
             cmd_res = cmd_recv.recv() =>{
                 match cmd_res{
-                    Ok(cmd) => if cmd == VPCtrlMsg::Shutdown {
-                        break;
-                    },
-                    Err(_) => break
+                    Ok(VPCtrlMsg::Shutdown) | Err(RecvError::Closed) => break,
+                    // events of the sinks may outpace this receiver
+                    _ => {}
                 }
             },
-            // This block lets the server push updates to clients
-            _ = interval.tick() => if status.0 || status.1{
-                // Send archticture first otherwise transaction cannot be displayed
-                if status.1{
-                    let _ = channel.send(VPCtrlMsg::RecvModule);
-                    status.1 = !status.1;
-                }else if status.0{
-                    let _ = channel.send(VPCtrlMsg::RecvTransaction);
-                    status.0 = !status.0;
-                }
+            // This block lets the sinks push updates to clients
+            _ = interval.tick() => for sink in sinks.iter_mut() {
+                sink.on_tick().await;
             },
-            // This block handles incomming TCP socket packets
-            line_res = socket_recv.next_line() => {
+            // This block handles incomming trace lines
+            line_res = trace.next() => {
+                if !binary && trace.is_binary() {
+                    binary = true;
+                    println!("[VP] receiving binary trace");
+                }
                 match line_res {
-                    Ok(line) => if line.is_some() {
-                            if let Some(res) = handle_response(&line.unwrap(),&mut responses,&mut layout,&mut layout_parsed).await{
-                                status.0 = res.0;
-                                if !status.1 {
-                                    status.1 = res.1;
-                                }
-                            }
-                        },
-                    Err(e) => println!("{e}")
+                    Ok(Some(line)) => handle_response(line, &mut receiver, &layout, &shadow, &mut sinks).await,
+                    // the VP closed the trace connection
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("[VP] could not read trace ({e})");
+                        break;
+                    }
                 }
             }
         };
     }
-    println!("[VP] exiting tcp receiver");
+    for sink in sinks.iter_mut() {
+        sink.on_shutdown().await;
+    }
+    println!("[VP] exiting trace receiver");
 }
 
 async fn handle_response(
-    buffer: &str,
-    responses: &mut Arc<Mutex<Vec<Transaction>>>,
-    layout: &mut Arc<Mutex<VPLayout>>,
-    parsing: &mut bool,
-) -> Option<(bool, bool)> {
-    if let Ok(step) = Transaction::from_str(buffer) {
-        responses.lock().await.push(step);
-        if *parsing {
-            *parsing = !*parsing;
-            return Some((true, true));
-        }
-        return Some((true, false));
-    } else {
-        *parsing = true;
-        let data: Vec<&str> = buffer.split(';').collect();
-        if data.len() == 4 {
+    line: Result<TraceLine, String>,
+    receiver: &mut Receiver,
+    layout: &Mutex<VPLayout>,
+    shadow: &Mutex<ShadowMemory>,
+    sinks: &mut [Box<dyn TransactionSink>],
+) {
+    let step = match line {
+        Ok(TraceLine::Transaction(step)) => step,
+        Ok(TraceLine::Module(name, start, end)) => {
+            receiver.parsing_layout = true;
             let mut l_lock = layout.lock().await;
-            l_lock.modules.push(data[1].to_string());
-            l_lock.start_addrs.push(data[2].to_string());
-            l_lock.end_addrs.push(data[3].to_string());
+            l_lock.modules.push(name.clone());
+            l_lock.start_addrs.push(start.clone());
+            l_lock.end_addrs.push(end.clone());
+            receiver.layout.modules.push(name);
+            receiver.layout.start_addrs.push(start);
+            receiver.layout.end_addrs.push(end);
+            return;
         }
+        Err(e) => {
+            if receiver.invalid < MAX_INVALID_LINES {
+                println!("[VP] ignoring trace line {e}");
+            }
+            receiver.invalid += 1;
+            return;
+        }
+    };
+
+    if receiver.parsing_layout {
+        receiver.parsing_layout = false;
+        for sink in sinks.iter_mut() {
+            sink.on_layout(&receiver.layout).await;
+        }
+    }
+
+    let state = TraceState {
+        index: receiver.count,
+        layout: &receiver.layout,
+        shadow,
+    };
+    for sink in sinks.iter_mut() {
+        sink.on_transaction(&step, &state).await;
     }
-    None
+    // applied after all sinks saw the transaction, without holding another lock
+    shadow.lock().await.apply(&step);
+    receiver.count += 1;
 }
@@ -2,10 +2,20 @@ import type { Transaction } from "@/types";
 
 const PKT_LENGTHB = 28;
 const PREAMBLE = 8;
+const INDEX_LENGTHB = 8;
+// set in the preamble of filtered streams, each transaction is preceded by its index
+const INDEXED = BigInt(1) << BigInt(63);
 const one = BigInt(1);
 
-function isTransactionPacket(byteLength: number) {
-  return byteLength % PKT_LENGTHB === PREAMBLE;
+function recordLength(indexed: boolean) {
+  return indexed ? PKT_LENGTHB + INDEX_LENGTHB : PKT_LENGTHB;
+}
+
+function isTransactionPacket(byteLength: number, indexed: boolean) {
+  return (
+    byteLength >= PREAMBLE &&
+    (byteLength - PREAMBLE) % recordLength(indexed) === 0
+  );
 }
 
 export default function parseBinary(
@@ -13,26 +23,39 @@ export default function parseBinary(
   modules: Array<string>,
 ): Array<Transaction> {
   let transactions = new Array<Transaction>();
-  if (!isTransactionPacket(buffer.byteLength)) {
+  if (buffer.byteLength < PREAMBLE) {
     console.log("Received an incorrect amount of bytes");
     return transactions;
   }
 
   let data = new DataView(buffer);
-  // PREAMBLE contains transaction counter
-  let t_count = data.getBigUint64(0, true);
-  for (let i = PREAMBLE; i < data.byteLength; i += PKT_LENGTHB) {
-    t_count += one;
+  // PREAMBLE contains the index of the first transaction
+  let preamble = data.getBigUint64(0, true);
+  let indexed = (preamble & INDEXED) !== BigInt(0);
+  if (!isTransactionPacket(buffer.byteLength, indexed)) {
+    console.log("Received an incorrect amount of bytes");
+    return transactions;
+  }
+
+  let t_count = preamble & ~INDEXED;
+  for (let i = PREAMBLE; i < data.byteLength; i += recordLength(indexed)) {
+    let offset = i;
+    if (indexed) {
+      t_count = data.getBigUint64(i, true);
+      offset += INDEX_LENGTHB;
+    }
+    // trans_cnt counts from one
     let trans: Transaction = {
-      sim_time: data.getBigUint64(i, true),
-      action: data.getUint8(i + 8),
-      initiator: "Core-" + String.fromCharCode(data.getUint8(i + 9)),
-      target: modules[data.getUint8(i + 10)],
-      address: data.getBigUint64(i + 11, true),
-      data_length: data.getUint8(i + 19),
-      data: data.getBigUint64(i + 20, true),
-      trans_cnt: t_count,
+      sim_time: data.getBigUint64(offset, true),
+      action: data.getUint8(offset + 8),
+      initiator: "Core-" + String.fromCharCode(data.getUint8(offset + 9)),
+      target: modules[data.getUint8(offset + 10)],
+      address: data.getBigUint64(offset + 11, true),
+      data_length: data.getUint8(offset + 19),
+      data: data.getBigUint64(offset + 20, true),
+      trans_cnt: t_count + one,
     };
+    t_count += one;
     if (trans.target === undefined) {
       console.log("Transaction contains a unknown target");
       continue;
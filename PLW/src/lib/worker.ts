@@ -87,6 +87,11 @@ function handleJSON(wsCmd: any) {
 
   if (wsCmd.command === "Options") {
     self.postMessage({ type: "options", payload: wsCmd.value });
+    return;
+  }
+
+  if (wsCmd.command === "Error") {
+    console.log("Server error: " + wsCmd.value);
   }
 }
 